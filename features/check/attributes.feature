@@ -0,0 +1,23 @@
+Feature: Identify changes of ownership, mode and extended attributes
+
+    Background:
+        Given sample with minimum content
+        And file /tmp/image/usr/bin/ping
+        When run chmod 4755 ${root}/tmp/image/usr/bin/ping
+        Then success
+        When run ndbam-import --image ${root}/tmp/image net-misc/iputils
+        Then success
+
+    Scenario: Attributes are recorded on import
+        When run grep -rq mode=4755 ${location}/data/net-misc---iputils
+        Then success
+        When run ndbam-check net-misc/iputils
+        Then success
+        And no output
+
+    Scenario: Mode changed since installation
+        When run chmod 0755 ${root}/usr/bin/ping
+        Then success
+        When run ndbam-check net-misc/iputils
+        Then failure
+        And output contains: A /usr/bin/ping Attributes changed (mode)
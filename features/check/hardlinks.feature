@@ -0,0 +1,25 @@
+Feature: Identify hardlinks that are not shared anymore
+
+    Background:
+        Given sample with minimum content
+        And file /tmp/image/usr/bin/git
+        When run ln ${root}/tmp/image/usr/bin/git ${root}/tmp/image/usr/bin/git-add
+        Then success
+        When run ndbam-import --image ${root}/tmp/image dev-vcs/git
+        Then success
+
+    Scenario: Hardlinks are recorded on import
+        When run grep -rq hardlink=/usr/bin/git ${location}/data/dev-vcs---git
+        Then success
+        When run ndbam-check dev-vcs/git
+        Then success
+        And no output
+
+    Scenario: Hardlink replaced with a copy
+        When run cp -p ${root}/usr/bin/git ${root}/tmp/git-copy
+        Then success
+        When run mv ${root}/tmp/git-copy ${root}/usr/bin/git-add
+        Then success
+        When run ndbam-check dev-vcs/git
+        Then failure
+        And output contains: Not a hardlink of /usr/bin/git
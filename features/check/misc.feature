@@ -14,3 +14,39 @@ Feature: Miscelanous properties of ndbam-check
             not-installed - Not found
             """
         And failure
+
+    Scenario: Repository from the future
+        Given sample with minimum content
+        And file /var/db/ndbam/ndbam.conf
+            """
+            ndbam_format = 2
+            repository_format = exndbam-2
+            """
+        When run ndbam-check
+        Then failure
+        And no output
+
+    Scenario: Unparseable contents reported instead of crashing
+        Given sample with minimum content
+        And file /var/db/ndbam/data/dummy/0:0/contents
+            """
+            type=unknown path=/foo
+            """
+        When run ndbam-check
+        Then failure
+        And output contains: Unknown type "unknown"
+
+    Scenario: Summary of package shown in header
+        Given sample with basic content
+        And file /var/db/ndbam/data/empty/0:0/SUMMARY
+            """
+            Nothing to see here
+            """
+        When run ndbam-check --show-size empty
+        Then success
+        And output is:
+            """
+            empty-0:0
+              # Summary: Nothing to see here
+              # Size: 0 B
+            """
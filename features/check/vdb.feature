@@ -0,0 +1,48 @@
+Feature: Check packages recorded in Gentoo VDB with --vdb
+
+    Background:
+        Given file /hello.txt
+            """
+            Hello Rusty Exherbo
+            """
+        And file /var/db/ndbam/app-misc/hello-1.0/SLOT
+            """
+            0
+            """
+        And file /var/db/ndbam/app-misc/hello-1.0/DESCRIPTION
+            """
+            Greetings to everyone
+            """
+
+    Scenario: Intact package
+        Given file /var/db/ndbam/app-misc/hello-1.0/CONTENTS
+            """
+            dir /
+            obj /hello.txt 80c3a4292206a710d6a06d2f6b48c7a6 0
+            """
+        When run ndbam-check --vdb --allow-mtime --verbose app-misc/hello
+        Then success
+        And output contains: app-misc/hello-1.0:0
+        And output contains: Summary: Greetings to everyone
+
+    Scenario: Modified file
+        Given file /var/db/ndbam/app-misc/hello-1.0/CONTENTS
+            """
+            obj /hello.txt d15c3af0546fd1172b9b6a2d10fc018e 0
+            """
+        When run ndbam-check --vdb --allow-mtime
+        Then failure
+        And output contains: C /hello.txt Content changed
+
+
+    Scenario: FIFOs and device nodes are skipped
+        Given file /var/db/ndbam/app-misc/hello-1.0/CONTENTS
+            """
+            dir /
+            fif /run/hello
+            dev /dev/hello
+            obj /hello.txt 80c3a4292206a710d6a06d2f6b48c7a6 0
+            """
+        When run ndbam-check --vdb --allow-mtime
+        Then success
+        And no output
@@ -0,0 +1,92 @@
+Feature: Installed versions are reported in version order
+
+    Scenario: Multiple versions of the same package
+        Given sample with minimum content
+        And file /var/db/ndbam/data/dev-libs---foo/1.10:0/contents
+        And file /var/db/ndbam/data/dev-libs---foo/1.9-r1:0/contents
+        And file /var/db/ndbam/data/dev-libs---foo/1.10_rc1:1/contents
+        And file /var/db/ndbam/data/dev-libs---foo/scm:scm/contents
+        When run ndbam-check --show-size dev-libs/foo
+        Then success
+        And output is:
+            """
+            dev-libs/foo-1.9-r1:0
+              # Size: 0 B
+            dev-libs/foo-1.10_rc1:1
+              # Size: 0 B
+            dev-libs/foo-1.10:0
+              # Size: 0 B
+            dev-libs/foo-scm:scm
+              # Size: 0 B
+            """
+
+    Scenario: Broken version entry
+        Given sample with minimum content
+        And file /var/db/ndbam/data/dev-libs---foo/not-a-version:0/contents
+        When run ndbam-check dev-libs/foo
+        Then failure
+        And no output
+
+    Scenario: Select versions with spec
+        Given sample with minimum content
+        And file /var/db/ndbam/data/dev-libs---foo/1.10:0/contents
+        And file /var/db/ndbam/data/dev-libs---foo/1.9-r1:0/contents
+        And file /var/db/ndbam/data/dev-libs---foo/2.0:2/contents
+        And file /var/db/ndbam/data/sys-apps---bar/1.0:0/contents
+        When run ndbam-check --show-size >=dev-libs/foo-1.10 */*:0
+        Then success
+        And output is:
+            """
+            dev-libs/foo-1.10:0
+              # Size: 0 B
+            dev-libs/foo-2.0:2
+              # Size: 0 B
+            dev-libs/foo-1.9-r1:0
+              # Size: 0 B
+            dev-libs/foo-1.10:0
+              # Size: 0 B
+            sys-apps/bar-1.0:0
+              # Size: 0 B
+            """
+
+    Scenario: Nothing matches spec
+        Given sample with minimum content
+        And file /var/db/ndbam/data/dev-libs---foo/1.10:0/contents
+        When run ndbam-check <dev-libs/foo-1
+        Then failure
+        And output is:
+            """
+            <dev-libs/foo-1 - Not found
+            """
+
+    Scenario: Invalid spec
+        Given sample with minimum content
+        When run ndbam-check >=dev-libs/foo
+        Then failure
+        And output is:
+            """
+            >=dev-libs/foo - Missing version in ">=dev-libs/foo"
+            """
+
+    Scenario: Newest install of the same version and slot is authoritative
+        Given sample with minimum content
+        And file /hello.txt
+        And file /var/db/ndbam/data/hello/1.0:0:C.100.1558050000.0.C/contents
+            """
+            type=file path=/missing.txt md5=00000000000000000000000000000000 mtime=0
+            """
+        And file /var/db/ndbam/data/hello/1.0:0:C.200.1558050745.0.C/contents
+            """
+            type=file path=/hello.txt md5=d41d8cd98f00b204e9800998ecf8427e mtime=0
+            """
+        And file /var/db/ndbam/data/hello/1.0:0/contents
+            """
+            type=file path=/missing.txt md5=00000000000000000000000000000000 mtime=0
+            """
+        When run ndbam-check --allow-mtime --show-size hello
+        Then success
+        And output is:
+            """
+            hello-1.0:0
+              # Size: 0 B
+            """
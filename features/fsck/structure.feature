@@ -0,0 +1,105 @@
+Feature: Structural consistency of repository with ndbam-fsck
+
+    Scenario: Nothing wrong
+        Given sample with basic content
+        When run ndbam-fsck
+        Then success
+        And no output
+
+    Scenario: Malformed version entry
+        Given sample with minimum content
+        And file /var/db/ndbam/data/dev-libs---foo/not-a-version:0/contents
+        When run ndbam-fsck --repair
+        Then failure
+        And output contains: Malformed entry
+        And output contains: Suggested: rename or remove entry
+
+    Scenario: Package without contents
+        Given sample with minimum content
+        And dir /var/db/ndbam/data/dev-libs---foo/1.0:0
+        When run ndbam-fsck
+        Then failure
+        And output contains: Missing contents
+
+    Scenario: Unparseable contents
+        Given sample with minimum content
+        And file /var/db/ndbam/data/dummy/0:0/contents
+            """
+            type=dir path=/foo
+            type=unknown path=/foo
+            """
+        When run ndbam-fsck
+        Then failure
+        And output contains: contents":2: Unknown type "unknown"
+
+    Scenario: Leftover temporary file
+        Given sample with minimum content
+        And file /var/db/ndbam/data/dummy/0:0/contents
+        And file /var/db/ndbam/data/dummy/0:0/.tmpAbC123
+        When run ndbam-fsck
+        Then failure
+        And output contains: Leftover temporary file
+        And output contains: (use --repair)
+        And file /var/db/ndbam/data/dummy/0:0/.tmpAbC123 exists
+        When run ndbam-fsck --repair
+        Then success
+        And output contains: Repaired: remove
+        And no file /var/db/ndbam/data/dummy/0:0/.tmpAbC123 exists
+        When run ndbam-fsck
+        Then success
+        And no output
+
+    Scenario: Package left behind by interrupted unmerge
+        Given sample with minimum content
+        And file /var/db/ndbam/.tmpXyZ789/package/contents
+        When run ndbam-fsck --repair
+        Then success
+        And output contains: Leftover temporary file
+        And no dir /var/db/ndbam/.tmpXyZ789 exists
+
+    Scenario: Empty package name directory
+        Given sample with minimum content
+        And dir /var/db/ndbam/data/dev-libs---foo
+        When run ndbam-fsck --repair
+        Then success
+        And output contains: Package without versions
+        And no file /var/db/ndbam/data/dev-libs---foo exists
+
+    Scenario: Identical duplicates in contents
+        Given sample with minimum content
+        And file /var/db/ndbam/data/dummy/0:0/contents
+            """
+            type=dir path=/usr
+            type=dir path=/usr/
+            type=dir path=/etc
+            """
+        When run ndbam-fsck --repair
+        Then success
+        And output contains: Duplicate entries for "/usr"
+        When run cat ${location}/data/dummy/0:0/contents
+        Then output is:
+            """
+            type=dir path=/usr
+            type=dir path=/etc
+            """
+
+    Scenario: Conflicting duplicates in contents
+        Given sample with minimum content
+        And file /var/db/ndbam/data/dummy/0:0/contents
+            """
+            type=dir path=/usr
+            type=sym path=/usr target=/opt mtime=0
+            """
+        When run ndbam-fsck --repair
+        Then failure
+        And output contains: Conflicting entries for "/usr"
+
+    Scenario: File owned by several packages
+        Given sample with basic content
+        And file /var/db/ndbam/data/other/0:0/contents
+            """
+            type=file path=/hello.txt md5=d15c3af0546fd1172b9b6a2d10fc018e mtime=1558050745
+            """
+        When run ndbam-fsck
+        Then failure
+        And output contains: "/hello.txt" is owned by
@@ -0,0 +1,91 @@
+Feature: Collision policies of ndbam-import
+
+    Background:
+        Given sample with minimum content
+        And file /tmp/owner/etc/hosts
+            """
+            127.0.0.1 localhost
+            """
+        When run ndbam-import --image ${root}/tmp/owner sys-apps/baselayout
+        Then success
+        Given file /etc/motd
+            """
+            hello
+            """
+
+    Scenario: Conflicts are rejected by default
+        Given file /tmp/image/etc/hosts
+            """
+            ::1 localhost
+            """
+        When run ndbam-import --image ${root}/tmp/image net-misc/hosts
+        Then failure
+        And errors contains: already exists (owned by sys-apps/baselayout-0:0)
+        And file /tmp/image/etc/hosts exists
+        When run ndbam-check net-misc/hosts
+        Then failure
+
+    Scenario: Unowned files are reported too
+        Given file /tmp/image/etc/motd
+            """
+            hello
+            """
+        When run ndbam-import --image ${root}/tmp/image --collisions no-conflicts app-misc/motd
+        Then failure
+        And errors contains: already exists (not owned by any package)
+
+    Scenario: Identical files are shared
+        Given file /tmp/image/etc/hosts
+            """
+            127.0.0.1 localhost
+            """
+        When run ndbam-import --image ${root}/tmp/image --collisions allow-identical net-misc/hosts
+        Then success
+        And output contains: "/etc/hosts" (owned by sys-apps/baselayout-0:0) is identical and shared
+        When run ndbam-check net-misc/hosts sys-apps/baselayout
+        Then success
+
+    Scenario: Different files are not allowed as identical
+        Given file /tmp/image/etc/motd
+            """
+            bye
+            """
+        When run ndbam-import --image ${root}/tmp/image --collisions allow-identical app-misc/motd
+        Then failure
+        And file /etc/motd exists
+            """
+            hello
+            """
+
+    Scenario: Different files yield to existing ones
+        Given file /tmp/image/etc/hosts
+            """
+            ::1 localhost
+            """
+        When run ndbam-import --image ${root}/tmp/image --collisions yield net-misc/hosts
+        Then success
+        And output contains: "/etc/hosts" (owned by sys-apps/baselayout-0:0) yielded to
+        And file /etc/hosts exists
+            """
+            127.0.0.1 localhost
+            """
+        And file /etc/._cfg0000_hosts exists
+            """
+            ::1 localhost
+            """
+        When run ndbam-check net-misc/hosts
+        Then success
+
+    Scenario: Existing files are clobbered
+        Given file /tmp/image/etc/motd
+            """
+            bye
+            """
+        When run ndbam-import --image ${root}/tmp/image --collisions clobber app-misc/motd
+        Then success
+        And output contains: "/etc/motd" (not owned by any package) clobbered
+        And file /etc/motd exists
+            """
+            bye
+            """
+        And no file /etc/.motd.ndbam-replaced exists
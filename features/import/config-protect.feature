@@ -0,0 +1,92 @@
+Feature: Protection of configuration files
+
+    Background:
+        Given sample with minimum content
+        And file /tmp/image1/etc/foo.conf
+            """
+            default = 1
+            """
+        And file /tmp/image1/etc/env.d/50foo
+            """
+            FOO=1
+            """
+        When run ndbam-import --image ${root}/tmp/image1 app-misc/foo 1
+        Then success
+        Given file /tmp/image2/etc/foo.conf
+            """
+            default = 2
+            """
+        And file /tmp/image2/etc/env.d/50foo
+            """
+            FOO=2
+            """
+
+    Scenario: Unmodified protected file is upgraded
+        When run ndbam-import --image ${root}/tmp/image2 --config-protect /etc app-misc/foo 2
+        Then success
+        And file /etc/foo.conf exists
+            """
+            default = 2
+            """
+        And no file /etc/._cfg0000_foo.conf exists
+
+    Scenario: Modified protected file yields new one
+        Given file /etc/foo.conf
+            """
+            default = local
+            """
+        When run ndbam-import --image ${root}/tmp/image2 --config-protect /etc app-misc/foo 2
+        Then success
+        And output contains: "/etc/foo.conf" (owned by app-misc/foo-1:0) yielded to
+        And output contains: keeping "/etc/foo.conf": modified since install
+        And file /etc/foo.conf exists
+            """
+            default = local
+            """
+        And file /etc/._cfg0000_foo.conf exists
+            """
+            default = 2
+            """
+        When run ndbam-yields
+        Then success
+        And output contains: /etc/foo.conf <- /etc/._cfg0000_foo.conf (app-misc/foo-2:0)
+
+    Scenario: Protection configured in repository
+        Given file /var/db/ndbam/ndbam.conf
+            """
+            ndbam_format = 1
+            repository_format = exndbam-1
+            config_protect = /etc
+            config_protect_mask = /etc/env.d
+            """
+        And file /etc/foo.conf
+            """
+            default = local
+            """
+        And file /etc/env.d/50foo
+            """
+            FOO=local
+            """
+        When run ndbam-import --image ${root}/tmp/image2 app-misc/foo 2
+        Then success
+        And file /etc/foo.conf exists
+            """
+            default = local
+            """
+        And file /etc/._cfg0000_foo.conf exists
+        And file /etc/env.d/50foo exists
+            """
+            FOO=2
+            """
+
+    Scenario: Unprotected files are replaced as usual
+        Given file /etc/foo.conf
+            """
+            default = local
+            """
+        When run ndbam-import --image ${root}/tmp/image2 app-misc/foo 2
+        Then success
+        And file /etc/foo.conf exists
+            """
+            default = 2
+            """
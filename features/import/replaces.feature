@@ -0,0 +1,34 @@
+Feature: Many-to-many package replacement
+
+    Background:
+        Given sample with minimum content
+        And file /tmp/client/usr/bin/client
+        And file /tmp/client/usr/lib/libclient.so
+        When run ndbam-import --image ${root}/tmp/client net-misc/client
+        Then success
+        Given file /tmp/server/usr/bin/server
+        And file /tmp/server/usr/lib/libserver.so
+        When run ndbam-import --image ${root}/tmp/server net-misc/server
+        Then success
+
+    Scenario: Two packages merged into one
+        Given file /tmp/image/usr/bin/client
+        And file /tmp/image/usr/bin/server
+        When run ndbam-import --image ${root}/tmp/image --replaces net-misc/client --replaces net-misc/server net-misc/suite
+        Then success
+        And output contains: replacing net-misc/client-0:0
+        And output contains: replacing net-misc/server-0:0
+        And file /usr/bin/client exists
+        And file /usr/bin/server exists
+        And no file /usr/lib exists
+        When run ndbam-check net-misc/client
+        Then failure
+        When run ndbam-check net-misc/suite
+        Then success
+
+    Scenario: Replaced package must be installed
+        Given file /tmp/image/usr/bin/client
+        When run ndbam-import --image ${root}/tmp/image --replaces net-misc/absent net-misc/suite
+        Then failure
+        And output contains: net-misc/absent - Not found
+        And file /tmp/image/usr/bin/client exists
@@ -0,0 +1,48 @@
+Feature: Upgrades and slots
+
+    Background:
+        Given sample with minimum content
+        And file /tmp/image/usr/bin/hello
+            """
+            version 1
+            """
+        And file /tmp/image/usr/share/hello/obsolete
+        When run ndbam-import --image ${root}/tmp/image app-misc/hello 1 0
+        Then success
+
+    Scenario: Import into the same slot replaces old version
+        Given file /tmp/image/usr/bin/hello
+            """
+            version 2
+            """
+        When run ndbam-import --image ${root}/tmp/image app-misc/hello 2 0
+        Then success
+        And output contains: replacing app-misc/hello-1:0
+        And file /usr/bin/hello exists
+            """
+            version 2
+            """
+        And no file /usr/share/hello exists
+        When run ndbam-check app-misc/hello
+        Then success
+        When run ndbam-check =app-misc/hello-1
+        Then failure
+
+    Scenario: Import into other slot keeps both versions
+        Given file /tmp/image/opt/hello-2/hello
+        When run ndbam-import --image ${root}/tmp/image app-misc/hello 2 2
+        Then success
+        And file /usr/share/hello/obsolete exists
+        When run ndbam-check =app-misc/hello-1 =app-misc/hello-2
+        Then success
+
+    Scenario: Modified files of old version are kept
+        Given file /usr/share/hello/obsolete
+            """
+            local changes
+            """
+        And directory /tmp/image
+        When run ndbam-import --image ${root}/tmp/image app-misc/hello 2 0
+        Then success
+        And output contains: keeping "/usr/share/hello/obsolete": modified since install
+        And file /usr/share/hello/obsolete exists
@@ -0,0 +1,27 @@
+Feature: Initialize new repository with ndbam-init
+
+    Scenario: Fresh repository
+        When run ndbam-init
+        Then success
+        And no output
+        And directory /var/db/ndbam/data exists
+        When run cat ${location}/ndbam.conf
+        Then output is:
+            """
+            ndbam_format = 1
+            repository_format = exndbam-1
+            """
+        When run ndbam-check
+        Then success
+
+    Scenario: Custom repository format
+        When run ndbam-init --format exndbam-2
+        Then success
+        When run cat ${location}/ndbam.conf
+        Then output contains: repository_format = exndbam-2
+
+    Scenario: Repository already exists
+        Given sample with minimum content
+        When run ndbam-init
+        Then failure
+        And no output
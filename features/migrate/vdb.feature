@@ -0,0 +1,47 @@
+Feature: Migrate packages between VDB and NDBAM with ndbam-migrate
+
+    Scenario: From VDB into new repository
+        Given file /hello.txt
+            """
+            Hello Rusty Exherbo
+            """
+        And file /var/db/pkg/app-misc/hello-1.0/SLOT
+            """
+            0
+            """
+        And file /var/db/pkg/app-misc/hello-1.0/DESCRIPTION
+            """
+            Greetings to everyone
+            """
+        And file /var/db/pkg/app-misc/hello-1.0/CONTENTS
+            """
+            obj /hello.txt 80c3a4292206a710d6a06d2f6b48c7a6 0
+            """
+        When run ndbam-migrate --from-vdb ${root}/var/db/pkg
+        Then success
+        And no output
+        When run ndbam-check --allow-mtime --verbose app-misc/hello
+        Then success
+        And output contains: app-misc/hello-1.0:0
+        And output contains: Summary: Greetings to everyone
+
+    Scenario: Into existing repository
+        Given sample with minimum content
+        And dir /var/db/pkg
+        When run ndbam-migrate --from-vdb ${root}/var/db/pkg
+        Then failure
+        And no output
+
+    Scenario: From repository into VDB
+        Given sample with basic content
+        When run ndbam-migrate --to-vdb ${root}/var/db/pkg
+        Then failure
+        And output contains: X hello-0:0:
+        And output contains: has no category
+        And directory /var/db/pkg exists
+
+    Scenario: Direction is required
+        Given sample with basic content
+        When run ndbam-migrate
+        Then failure
+        And no output
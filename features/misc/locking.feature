@@ -0,0 +1,24 @@
+Feature: Repository locking between concurrent processes
+
+    Background:
+        Given sample with basic content
+
+    Scenario: Readers do not block each other
+        Given repository shared locked by another process
+        When run ndbam-check --no-wait --allow-mtime hello
+        Then success
+        And no output
+
+    Scenario: Reader does not wait for writer with --no-wait
+        Given repository exclusive locked by another process
+        When run ndbam-check --no-wait --allow-mtime hello
+        Then failure
+        And no output
+
+    Scenario: Writer does not wait for reader with --no-wait
+        Given repository shared locked by another process
+        And file /tmp/image/hello.md
+        When run ndbam-import --no-wait --image ${root}/tmp/image just-file
+        Then failure
+        And file /tmp/image/hello.md exists
+        And no file /hello.md exists
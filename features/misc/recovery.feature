@@ -0,0 +1,77 @@
+Feature: Recovery of operations interrupted by crash
+
+    Background:
+        Given sample with basic content
+
+    Scenario: Nothing to recover
+        When run ndbam-recover
+        Then success
+        And no output
+
+    Scenario: Uncommitted merge is rolled back
+        Given dir /var/db/ndbam/data/fresh/1:0
+        And file /var/db/ndbam/journal
+            """
+            package	fresh/1:0
+            mkdir	/nonexistent
+            move	/half-writ
+            """
+        When run ndbam-recover
+        Then success
+        And output is:
+            """
+            * Rolled back interrupted merge of fresh-1:0
+            """
+        And no dir /var/db/ndbam/data/fresh exists
+        And no file /var/db/ndbam/journal exists
+
+    Scenario: Committed merge is completed
+        Given file /var/db/ndbam/journal
+            """
+            package	hello/0:0
+            commit
+            mkdir	/half-writ
+            """
+        When run ndbam-recover
+        Then success
+        And output is:
+            """
+            * Completed interrupted merge of hello-0:0
+            """
+        And no file /var/db/ndbam/journal exists
+
+    Scenario: Readers report interrupted operation
+        Given dir /var/db/ndbam/data/fresh/1:0
+        And file /var/db/ndbam/journal
+            """
+            package	fresh/1:0
+            mkdir	/half-writ
+            """
+        When run ndbam-check --allow-mtime hello
+        Then failure
+        And errors contains: has interrupted operation pending
+        And file /var/db/ndbam/journal exists
+        And directory /var/db/ndbam/data/fresh/1:0 exists
+
+    Scenario: Writers recover on start
+        Given file /var/db/ndbam/journal
+            """
+            package	fresh/1:0
+            mkdir	/half-writ
+            """
+        And file /tmp/image/etc/fresh.conf
+        When run ndbam-import --image ${root}/tmp/image app-misc/fresh
+        Then success
+        And no file /var/db/ndbam/journal exists
+        When run ndbam-check --allow-mtime hello app-misc/fresh
+        Then success
+
+    Scenario: Corrupted journal
+        Given file /var/db/ndbam/journal
+            """
+            rename	/a	/b
+            commit
+            """
+        When run ndbam-recover
+        Then failure
+        And file /var/db/ndbam/journal exists
@@ -0,0 +1,73 @@
+Feature: Remove installed packages
+
+    Background:
+        Given sample with minimum content
+        And file /tmp/image/usr/share/doc/hello/README
+            """
+            Hello Exherbo!
+            """
+        And file /tmp/image/etc/hello.conf
+            """
+            greeting = hello
+            """
+        When run ndbam-import --image ${root}/tmp/image app-misc/hello
+        Then success
+
+    Scenario: Remove unmodified package
+        When run ndbam-remove app-misc/hello
+        Then success
+        And output contains: - /usr/share/doc/hello/README
+        And no file /usr/share/doc/hello/README exists
+        And no dir /usr exists
+        And no file /etc/hello.conf exists
+        When run ndbam-check app-misc/hello
+        Then output is:
+            """
+            app-misc/hello - Not found
+            """
+
+    Scenario: Dry run changes nothing
+        When run ndbam-remove --dry-run app-misc/hello
+        Then success
+        And output contains: - /etc/hello.conf
+        And file /etc/hello.conf exists
+        When run ndbam-check app-misc/hello
+        Then success
+
+    Scenario: Modified files are kept
+        Given file /etc/hello.conf
+            """
+            greeting = bye
+            """
+        When run ndbam-remove app-misc/hello
+        Then failure
+        And output contains: M /etc/hello.conf modified since install
+        And output contains: = /etc not empty
+        And file /etc/hello.conf exists
+        And no file /usr/share/doc/hello/README exists
+
+    Scenario: Modified files are removed with --force
+        Given file /etc/hello.conf
+            """
+            greeting = bye
+            """
+        When run ndbam-remove --force app-misc/hello
+        Then success
+        And no file /etc/hello.conf exists
+
+    Scenario: Files of other packages are kept
+        Given file /tmp/image/usr/share/doc/world/README
+        When run ndbam-import --image ${root}/tmp/image app-misc/world
+        Then success
+        When run ndbam-remove app-misc/hello
+        Then success
+        And output contains: = /usr/share/doc owned by another package (app-misc/world-0:0)
+        And file /usr/share/doc/world/README exists
+
+    Scenario: Request to remove package that is not installed
+        When run ndbam-remove not-installed
+        Then output is:
+            """
+            not-installed - Not found
+            """
+        And failure
@@ -0,0 +1,87 @@
+Feature: Resolving yielded files with ndbam-yields
+
+    Background:
+        Given sample with minimum content
+        And file /tmp/owner/etc/hosts
+            """
+            127.0.0.1 localhost
+            """
+        When run ndbam-import --image ${root}/tmp/owner sys-apps/baselayout
+        Then success
+        Given file /tmp/image/etc/hosts
+            """
+            ::1 localhost
+            """
+        When run ndbam-import --image ${root}/tmp/image --collisions yield net-misc/hosts
+        Then success
+
+    Scenario: List pending yields
+        When run ndbam-yields
+        Then success
+        And output is:
+            """
+            /etc/hosts <- /etc/._cfg0000_hosts (net-misc/hosts-0:0)
+            """
+        When run ndbam-yields list /etc/motd
+        Then failure
+        And output contains: /etc/motd - No pending yields
+
+    Scenario: Show difference
+        When run ndbam-yields diff /etc/hosts
+        Then success
+        And output contains: -127.0.0.1 localhost
+        And output contains: +::1 localhost
+
+    Scenario: Accept yielded file
+        When run ndbam-yields accept /etc/._cfg0000_hosts
+        Then success
+        And output contains: /etc/hosts accepted
+        And file /etc/hosts exists
+            """
+            ::1 localhost
+            """
+        And no file /etc/._cfg0000_hosts exists
+        When run ndbam-yields
+        Then success
+        And no output
+        When run ndbam-check net-misc/hosts
+        Then success
+
+    Scenario: Accept one of several yielded files
+        Given file /tmp/other/etc/hosts
+            """
+            ::2 localhost
+            """
+        When run ndbam-import --image ${root}/tmp/other --collisions yield net-misc/other-hosts
+        Then success
+        When run ndbam-yields accept /etc/hosts
+        Then failure
+        And output contains: /etc/hosts Several files yielded
+        And file /etc/hosts exists
+            """
+            127.0.0.1 localhost
+            """
+        When run ndbam-yields accept /etc/._cfg0001_hosts
+        Then success
+        And file /etc/hosts exists
+            """
+            ::2 localhost
+            """
+        And no file /etc/._cfg0000_hosts exists
+        When run ndbam-yields
+        Then success
+        And no output
+        When run ndbam-check net-misc/other-hosts
+        Then success
+
+    Scenario: Reject yielded file
+        When run ndbam-yields reject /etc/hosts
+        Then success
+        And output contains: /etc/hosts rejected
+        And file /etc/hosts exists
+            """
+            127.0.0.1 localhost
+            """
+        And no file /etc/._cfg0000_hosts exists
+        When run ndbam-check net-misc/hosts sys-apps/baselayout
+        Then success
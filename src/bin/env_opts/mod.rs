@@ -5,9 +5,12 @@ use structopt::clap::AppSettings;
 use structopt::StructOpt;
 
 use ndbam::*;
+use ndbam::config::{ConfigProtect, RepositoryConfig};
+use ndbam::vdb::VDB;
 
 use super::*;
 
+// Shared between binaries, not every one of them uses what is allowed to be dead code
 #[derive(StructOpt, Debug)]
 #[structopt(raw(global_settings = "&[AppSettings::ColoredHelp]"))]
 pub struct EnvOpts {
@@ -28,17 +31,66 @@ pub struct EnvOpts {
         default_value = "/",
         parse(from_os_str = "parse_root_arg")
     )]
+    #[allow(dead_code)]
     pub root: AnyRoot,
+
+    /// Wait for other processes to release repository (default)
+    #[structopt(long = "wait", raw(overrides_with = r#""no_wait""#))]
+    #[allow(dead_code)] // Only cancels --no-wait
+    pub wait: bool,
+
+    /// Fail instead of waiting for other processes to release repository
+    #[structopt(long = "no-wait", raw(overrides_with = r#""wait""#))]
+    #[allow(dead_code)]
+    pub no_wait: bool,
+
+    /// Never overwrite modified files under this path (in addition to config_protect of repository)
+    #[structopt(long = "config-protect", name = "PROTECT", raw(number_of_values = "1"))]
+    #[allow(dead_code)]
+    pub config_protect: Vec<PathBuf>,
+
+    /// Exclude path from protection (in addition to config_protect_mask of repository)
+    #[structopt(long = "config-protect-mask", name = "MASK", raw(number_of_values = "1"))]
+    #[allow(dead_code)]
+    pub config_protect_mask: Vec<PathBuf>,
 }
 
 impl EnvOpts {
+    #[allow(dead_code)]
     pub fn ndbam(&self) -> NDBAM {
         NDBAM::new(&self.location)
+            .map(|ndbam| ndbam.wait_for_lock(!self.no_wait))
+            .unwrap_or_else(|err| {
+                eprintln!("Failed to open repository at {:?}: {}", self.location, err);
+                std::process::exit(1);
+            })
+    }
+
+    /// Protected paths of repository extended with ones from command line.
+    #[allow(dead_code)]
+    pub fn config_protect(&self, config: &RepositoryConfig) -> ConfigProtect {
+        let mut protect = config.config_protect();
+        protect.extend(&self.config_protect, &self.config_protect_mask);
+        protect
+    }
+
+    #[allow(dead_code)]
+    pub fn vdb(&self) -> VDB {
+        VDB::new(&self.location).unwrap_or_else(|err| {
+            eprintln!("Failed to open VDB at {:?}: {}", self.location, err);
+            std::process::exit(1);
+        })
     }
 }
 
 fn parse_path_arg(arg: &OsStr) -> PathBuf {
-    Path::new(arg).canonicalize().expect("valid path")
+    let path = Path::new(arg);
+    if path.exists() {
+        path.canonicalize().expect("valid path")
+    } else {
+        // Might be created later (e.g. by ndbam-init)
+        std::env::current_dir().expect("valid working directory").join(path)
+    }
 }
 
 fn parse_root_arg(arg: &OsStr) -> AnyRoot {
@@ -2,9 +2,12 @@ extern crate structopt;
 
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::path::{Path, PathBuf};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::os::unix::fs::MetadataExt;
 use ndbam::*;
 use ndbam::contents::*;
+use ndbam::dep_spec::PackageDepSpec;
+use ndbam::repository::*;
 use structopt::clap::AppSettings;
 use structopt::StructOpt;
 use bytesize::ByteSize;
@@ -53,9 +56,13 @@ struct Opts {
     #[structopt(short, long)]
     verbose: bool,
 
-    /// Package names to inspect (by default whole database)
-    #[structopt(name = "PACKAGE NAMES")]
-    names: Vec<String>,
+    /// Treat location as Gentoo VDB (e.g. /var/db/pkg)
+    #[structopt(long)]
+    vdb: bool,
+
+    /// Package specs to inspect, e.g. ">=dev-libs/openssl-1.1:0" (by default whole database)
+    #[structopt(name = "PACKAGE SPECS")]
+    specs: Vec<String>,
 }
 
 trait ContentFilter {
@@ -95,6 +102,14 @@ fn main() {
     let opts =  Opts::from_args();
     opts.color.force();
 
+    if opts.vdb {
+        check_repository(&opts, &opts.env.vdb())
+    } else {
+        check_repository(&opts, &opts.env.ndbam())
+    }
+}
+
+fn check_repository<R: Repository>(opts: &Opts, reg: &R) {
 
     let content_filter = if !opts.files.is_empty() {
         let mut files : HashSet<&Path> = HashSet::new();
@@ -112,17 +127,24 @@ fn main() {
         FileFilter::Everything
     };
 
-    let reg = opts.env.ndbam();
     let mut total_size = 0u64;
     let mut missing_packages = false;
     let mut any_problems = false;
-    let mut handle_package = |pkg| {
+    let mut handle_package = |pkg: ndbam::Result<R::Package>| {
+        let pkg = match pkg {
+            Ok(pkg) => pkg,
+            Err(err) => {
+                eprintln!("{}: {}", "Error".red().bold(), err);
+                any_problems = true;
+                return;
+            }
+        };
         let mut reporter = ConsolePackageReporter::new(&pkg);
         if opts.verbose {
             reporter.header()
         }
         if !opts.no_contents {
-            let size = check_contents(&opts, &content_filter, &pkg, &mut reporter);
+            let size = check_contents(opts, &content_filter, &pkg, &mut reporter);
             any_problems = any_problems || reporter.any_problems;
             total_size += size;
             if opts.show_size { reporter.header() }  // force report
@@ -132,15 +154,34 @@ fn main() {
         }
     };
 
-    if opts.names.is_empty() {
-        reg.all_packages().map(|iter| for pkg in iter { handle_package(pkg) });
+    if opts.specs.is_empty() {
+        match reg.all_packages() {
+            Ok(iter) => for pkg in iter { handle_package(pkg) },
+            Err(err) => handle_package(Err(err)),
+        }
     } else {
-        for ref name in &opts.names {
-            if let Some(iter) = reg.versions_of(name) {
-                for pkg in iter { handle_package(pkg) }
-            } else {
-                println!("{} - {}", name, "Not found".red().bold());
-                missing_packages = true;
+        for ref text in &opts.specs {
+            let spec = match PackageDepSpec::parse(text) {
+                Ok(spec) => spec,
+                Err(err) => {
+                    println!("{} - {}", text, err.red().bold());
+                    missing_packages = true;
+                    continue;
+                }
+            };
+            match reg.matching(&spec) {
+                Ok(iter) => {
+                    let mut found = false;
+                    for pkg in iter {
+                        found = true;
+                        handle_package(pkg)
+                    }
+                    if !found {
+                        println!("{} - {}", text, "Not found".red().bold());
+                        missing_packages = true;
+                    }
+                }
+                Err(err) => handle_package(Err(err)),
             }
         }
     }
@@ -163,16 +204,17 @@ trait ContentReporter {
         self.note(content_entry, 'X', &err.to_string())
     }
     fn dump_entry(&mut self, content_entry: &Entry);
+    fn broken(&mut self, err: &ndbam::Error);
 }
 
-struct ConsolePackageReporter<'p> {
-    pkg: &'p PackageView,
+struct ConsolePackageReporter<'p, P: Package> {
+    pkg: &'p P,
     any_reports: bool,
     any_problems: bool,
 }
 
-impl<'p> ConsolePackageReporter<'p> {
-    fn new(pkg: &PackageView) -> ConsolePackageReporter {
+impl<'p, P: Package> ConsolePackageReporter<'p, P> {
+    fn new(pkg: &P) -> ConsolePackageReporter<'_, P> {
         ConsolePackageReporter { pkg, any_reports: false, any_problems: false }
     }
 
@@ -182,15 +224,13 @@ impl<'p> ConsolePackageReporter<'p> {
         if self.any_reports { return }
         self.any_reports = true;
         println!("{}:{}", self.pkg.full_name(), self.pkg.slot().unwrap_or("0"));
-        if let Ok(summary) = self.pkg.read_key("SUMMARY") {
-            if !summary.trim_end().is_empty() {
-                println!("  # {}: {}", "Summary".bold(), summary.trim_end());
-            }
+        if let Ok(Some(summary)) = self.pkg.summary() {
+            println!("  # {}: {}", "Summary".bold(), summary);
         }
     }
 }
 
-impl<'p> ContentReporter for ConsolePackageReporter<'p> {
+impl<'p, P: Package> ContentReporter for ConsolePackageReporter<'p, P> {
     fn note(&mut self, content_entry: &Entry, class: char, note: &str) {
         self.header();
         println!("  {} {} {}", class, content_entry.path().to_string_lossy().red(), note);
@@ -204,15 +244,36 @@ impl<'p> ContentReporter for ConsolePackageReporter<'p> {
         self.header();
         println!("  # {:?}", content_entry);
     }
+    fn broken(&mut self, err: &ndbam::Error) {
+        self.header();
+        println!("  X {}", err.to_string().red());
+        self.any_problems = true;
+    }
 }
 
-fn check_contents(opts: &Opts, filter: &ContentFilter, pkg: &PackageView, reporter: &mut impl ContentReporter) -> u64 {
+fn check_contents(opts: &Opts, filter: &dyn ContentFilter, pkg: &impl Package, reporter: &mut impl ContentReporter) -> u64 {
     let root = &opts.env.root;
     let mut size = 0;
-    for ref entry in pkg.contents() {
+    // First member seen of every hardlink group and its device and inode
+    let mut links: HashMap<String, (PathBuf, (u64, u64))> = HashMap::new();
+    let contents = match pkg.contents() {
+        Ok(contents) => contents,
+        Err(err) => {
+            reporter.broken(&err);
+            return size;
+        }
+    };
+    for entry in contents {
+        let entry = &match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                reporter.broken(&err);
+                continue;
+            }
+        };
         let path = entry.path();
         let real_path = root.real_path(entry.path()).unwrap();
-        if filter.skip_entry(&entry) {
+        if filter.skip_entry(entry) {
             continue;
         }
 
@@ -261,7 +322,7 @@ fn check_contents(opts: &Opts, filter: &ContentFilter, pkg: &PackageView, report
                 }
 
                 if !opts.no_integrity {
-                    match file_hash(Algorithm::MD5, real_path) {
+                    match file_hash(Algorithm::MD5, &real_path) {
                         Ok(real_md5) => {
                             if &real_md5 != md5 {
                                 reporter.note(entry, 'C', "Content changed");
@@ -274,6 +335,25 @@ fn check_contents(opts: &Opts, filter: &ContentFilter, pkg: &PackageView, report
                     }
                 }
 
+                if !check_attributes(entry, &real_path, reporter) {
+                    continue;
+                }
+
+                if let Some(group) = entry.hardlink_group() {
+                    let inode = (metadata.dev(), metadata.ino());
+                    match links.get(group) {
+                        Some((first, first_inode)) if *first_inode != inode => {
+                            reporter.note(entry, 'L', &format!("Not a hardlink of {}", first.to_string_lossy()));
+                            continue;
+                        }
+                        // Shared content is counted once
+                        Some(_) => continue,
+                        None => {
+                            links.insert(group.to_string(), (path.to_owned(), inode));
+                        }
+                    }
+                }
+
                 // Count only file content confirmed to be owned by package
                 size += metadata.len();
             },
@@ -297,6 +377,10 @@ fn check_contents(opts: &Opts, filter: &ContentFilter, pkg: &PackageView, report
                     },
                 }
 
+                if !check_attributes(entry, &real_path, reporter) {
+                    continue;
+                }
+
                 if let Err(err) = root.canonicalize_to_real(path) {
                     if err.kind() == std::io::ErrorKind::NotFound {
                         reporter.note(entry, 'X', "Dangling symbolic link");
@@ -311,6 +395,21 @@ fn check_contents(opts: &Opts, filter: &ContentFilter, pkg: &PackageView, report
     size
 }
 
+/// Reports ownership, mode or extended attributes that differ from recorded ones (if any).
+fn check_attributes(entry: &Entry, real_path: &Path, reporter: &mut impl ContentReporter) -> bool {
+    match entry.changed_attributes(real_path) {
+        Ok(ref changed) if changed.is_empty() => true,
+        Ok(changed) => {
+            reporter.note(entry, 'A', &format!("Attributes changed ({})", changed.join(", ")));
+            false
+        }
+        Err(err) => {
+            reporter.err(entry, err);
+            false
+        }
+    }
+}
+
 fn epoch_secs(moment: &SystemTime) -> u64 {
     moment.duration_since(UNIX_EPOCH).unwrap().as_secs()
 }
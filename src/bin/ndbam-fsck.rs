@@ -0,0 +1,66 @@
+mod colorful;
+mod env_opts;
+
+use structopt::clap::AppSettings;
+use structopt::StructOpt;
+
+use colorful::*;
+use env_opts::*;
+
+const DEFAULT_REPO_PATH : &str = "/var/db/paludis/repositories/installed";
+
+#[derive(StructOpt, Debug)]
+#[structopt(raw(global_settings = "&[AppSettings::ColoredHelp]"))]
+struct Opts {
+    #[structopt(flatten)]
+    env: EnvOpts,
+
+    /// Apply repairs that do not loose any information
+    #[structopt(long)]
+    repair: bool,
+
+    /// Colorize output?
+    #[structopt(long, name = "WHEN", default_value = "auto", raw(possible_values = "&ColorWhen::variants()", case_insensitive = "true"))]
+    color: ColorWhen,
+}
+
+fn main() {
+    let opts = Opts::from_args();
+    opts.color.force();
+
+    let reg = opts.env.ndbam();
+    if opts.repair {
+        if let Err(err) = reg.lock_exclusive() {
+            fail(err);
+        }
+    }
+    let problems = reg.fsck().unwrap_or_else(|err| fail(err));
+
+    let mut unresolved = false;
+    for problem in problems {
+        println!("{} {}", "X".red().bold(), problem);
+        let repair = problem.repair();
+        if !opts.repair || !repair.is_safe() {
+            let hint = if repair.is_safe() { " (use --repair)" } else { "" };
+            println!("  # {}: {}{}", "Suggested".bold(), repair, hint);
+            unresolved = true;
+            continue;
+        }
+        match reg.repair(&repair) {
+            Ok(_) => println!("  # {}: {}", "Repaired".bold(), repair),
+            Err(err) => {
+                println!("  # {}: {}", "Failed".bold(), err.to_string().red());
+                unresolved = true;
+            }
+        }
+    }
+
+    if unresolved {
+        std::process::exit(1);
+    }
+}
+
+fn fail<E: std::fmt::Display>(err: E) -> ! {
+    eprintln!("Error: {}", err);
+    std::process::exit(1);
+}
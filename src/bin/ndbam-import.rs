@@ -6,6 +6,11 @@ use structopt::StructOpt;
 
 use env_opts::*;
 use ndbam::*;
+use ndbam::config::ConfigProtect;
+use ndbam::dep_spec::PackageDepSpec;
+use ndbam::merger::CollisionPolicy;
+use ndbam::repository::*;
+use ndbam::unmerger::{KeepReason, Removal};
 
 const DEFAULT_REPO_PATH : &'static str = "/var/db/paludis/repositories/unpackaged";
 
@@ -19,6 +24,14 @@ struct Opts {
     #[structopt(long, short)]
     image: Option<PathBuf>,
 
+    /// Retire packages matching spec once import is done (can be specified multiple times)
+    #[structopt(long = "replaces", name = "SPEC", raw(number_of_values = "1"))]
+    replaces: Vec<String>,
+
+    /// What to do with paths that exist already
+    #[structopt(long, name = "POLICY", default_value = "no-conflicts", raw(possible_values = "&CollisionPolicy::variants()"))]
+    collisions: CollisionPolicy,
+
     /// Do not perform actual modifications
     #[structopt(long = "dry-run", short = "n")]
     dry_run: bool,
@@ -47,16 +60,79 @@ fn main() {
     let opts =  Opts::from_args();
 
     let reg = opts.env.ndbam();
-    assert!(
-        !reg.versions_of(&opts.package_name).is_some(),
-        "Upgrades and slots are not supported yet"
-    );
+    let protect = opts.env.config_protect(reg.config());
+    import(&opts, &reg, &protect);
+}
+
+fn import<R: Repository>(opts: &Opts, reg: &R, protect: &ConfigProtect) {
+    if !opts.dry_run {
+        if let Err(err) = reg.lock_exclusive() {
+            fail(err);
+        }
+    }
+    let spec = PackageDepSpec::parse(&opts.package_name).unwrap_or_else(|err| fail(err));
+    let mut replaced: Vec<R::Package> = Vec::new();
+    for pkg in reg.matching(&spec).unwrap_or_else(|err| fail(err)) {
+        match pkg {
+            // Other slots are kept side by side
+            Ok(pkg) if pkg.name() == opts.package_name && pkg.slot().unwrap_or("0") == opts.slot => replaced.push(pkg),
+            Ok(_) => {}
+            Err(err) => fail(err),
+        }
+    }
+    let mut missing_packages = false;
+    for text in &opts.replaces {
+        let spec = PackageDepSpec::parse(text).unwrap_or_else(|err| fail(err));
+        let mut found = false;
+        for pkg in reg.matching(&spec).unwrap_or_else(|err| fail(err)) {
+            let pkg = pkg.unwrap_or_else(|err| fail(err));
+            found = true;
+            if !replaced.iter().any(|other| other.id() == pkg.id()) {
+                replaced.push(pkg);
+            }
+        }
+        if !found {
+            println!("{} - Not found", text);
+            missing_packages = true;
+        }
+    }
+    if missing_packages {
+        std::process::exit(2);
+    }
+    for old in &replaced {
+        println!("replacing {}", old.id());
+    }
 
     if opts.dry_run {
         println!("Dry-run. No actions.");
         return;
     }
 
-    reg.new_package_version(&opts.package_name, &opts.version, &opts.slot)
-        .merge(&opts.image(), &opts.env.root).unwrap();
+    let merged = reg.new_package_version(&opts.package_name, &opts.version, &opts.slot)
+        .and_then(|pkg| {
+            let merged = pkg.merge_replacing(&opts.image(), &opts.env.root, &replaced, opts.collisions, protect)?;
+            pkg.set_installed_time(std::time::SystemTime::now())?;
+            Ok(merged)
+        });
+    match merged {
+        Ok(merged) => {
+            for moved in merged.moves {
+                println!("{}", moved);
+            }
+            for collision in merged.collisions {
+                println!("collision {}", collision);
+            }
+            for removal in merged.removals {
+                if let Removal::Keep(entry, reason @ KeepReason::Modified(_)) = removal {
+                    println!("keeping {:?}: {}", entry.path(), reason);
+                }
+            }
+        }
+        Err(err) => fail(err),
+    }
+}
+
+fn fail<E: std::fmt::Display>(err: E) -> ! {
+    eprintln!("Error: {}", err);
+    std::process::exit(1);
 }
@@ -0,0 +1,29 @@
+mod env_opts;
+
+use structopt::clap::AppSettings;
+use structopt::StructOpt;
+
+use env_opts::*;
+use ndbam::*;
+
+const DEFAULT_REPO_PATH : &str = "/var/db/paludis/repositories/installed";
+
+#[derive(StructOpt, Debug)]
+#[structopt(raw(global_settings = "&[AppSettings::ColoredHelp]"))]
+struct Opts {
+    #[structopt(flatten)]
+    env: EnvOpts,
+
+    /// Value for repository_format in ndbam.conf
+    #[structopt(long, default_value = "exndbam-1")]
+    format: String,
+}
+
+fn main() {
+    let opts = Opts::from_args();
+
+    if let Err(err) = NDBAM::create(&opts.env.location, &opts.format) {
+        eprintln!("Failed to create repository at {:?}: {}", opts.env.location, err);
+        std::process::exit(1);
+    }
+}
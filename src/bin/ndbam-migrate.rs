@@ -0,0 +1,63 @@
+mod colorful;
+mod env_opts;
+
+use std::path::PathBuf;
+use structopt::clap::AppSettings;
+use structopt::StructOpt;
+
+use colorful::*;
+use env_opts::*;
+use ndbam::*;
+use ndbam::migrate::*;
+use ndbam::vdb::VDB;
+
+const DEFAULT_REPO_PATH : &str = "/var/db/paludis/repositories/installed";
+
+#[derive(StructOpt, Debug)]
+#[structopt(raw(global_settings = "&[AppSettings::ColoredHelp]"))]
+struct Opts {
+    #[structopt(flatten)]
+    env: EnvOpts,
+
+    /// Copy packages from VDB at PATH into new repository at location
+    #[structopt(long = "from-vdb", raw(value_name = r#""PATH""#, required_unless = r#""to_vdb""#), parse(from_os_str))]
+    from_vdb: Option<PathBuf>,
+
+    /// Copy packages from repository at location into VDB at PATH (should be empty)
+    #[structopt(long = "to-vdb", raw(value_name = r#""PATH""#, conflicts_with = r#""from_vdb""#), parse(from_os_str))]
+    to_vdb: Option<PathBuf>,
+
+    /// Colorize output?
+    #[structopt(long, name = "WHEN", default_value = "auto", raw(possible_values = "&ColorWhen::variants()", case_insensitive = "true"))]
+    color: ColorWhen,
+}
+
+fn main() {
+    let opts = Opts::from_args();
+    opts.color.force();
+
+    let losses = if let Some(ref source) = opts.from_vdb {
+        VDB::new(source).and_then(|source| {
+            let target = NDBAM::create(&opts.env.location, "exndbam-1")?.wait_for_lock(!opts.env.no_wait);
+            vdb_to_ndbam(&source, &target)
+        })
+    } else if let Some(ref target) = opts.to_vdb {
+        VDB::create(target).and_then(|target| ndbam_to_vdb(&opts.env.ndbam(), &target))
+    } else {
+        unreachable!("one of directions is required")
+    };
+
+    match losses {
+        Ok(ref losses) if losses.is_empty() => {}
+        Ok(losses) => {
+            for loss in losses {
+                println!("{} {}", "X".red().bold(), loss);
+            }
+            std::process::exit(2);
+        }
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
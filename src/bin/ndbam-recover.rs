@@ -0,0 +1,38 @@
+mod colorful;
+mod env_opts;
+
+use structopt::clap::AppSettings;
+use structopt::StructOpt;
+
+use colorful::*;
+use env_opts::*;
+
+const DEFAULT_REPO_PATH : &str = "/var/db/paludis/repositories/installed";
+
+#[derive(StructOpt, Debug)]
+#[structopt(raw(global_settings = "&[AppSettings::ColoredHelp]"))]
+struct Opts {
+    #[structopt(flatten)]
+    env: EnvOpts,
+
+    /// Colorize output?
+    #[structopt(long, name = "WHEN", default_value = "auto", raw(possible_values = "&ColorWhen::variants()", case_insensitive = "true"))]
+    color: ColorWhen,
+}
+
+fn main() {
+    let opts = Opts::from_args();
+    opts.color.force();
+
+    let reg = opts.env.ndbam();
+    let recovered = match reg.recover() {
+        Ok(recovered) => recovered,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+    };
+    if let Some(recovery) = recovered {
+        println!("{} {}", "*".green().bold(), recovery);
+    }
+}
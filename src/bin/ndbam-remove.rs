@@ -0,0 +1,120 @@
+mod colorful;
+mod env_opts;
+
+use structopt::clap::AppSettings;
+use structopt::StructOpt;
+
+use colorful::*;
+use env_opts::*;
+use ndbam::*;
+use ndbam::dep_spec::PackageDepSpec;
+use ndbam::unmerger::*;
+
+const DEFAULT_REPO_PATH : &str = "/var/db/paludis/repositories/installed";
+
+#[derive(StructOpt, Debug)]
+#[structopt(raw(global_settings = "&[AppSettings::ColoredHelp]"))]
+struct Opts {
+    #[structopt(flatten)]
+    env: EnvOpts,
+
+    /// Only show what would be removed and what would be kept
+    #[structopt(long = "dry-run")]
+    dry_run: bool,
+
+    /// Remove files and symlinks even if they were modified since install
+    #[structopt(long)]
+    force: bool,
+
+    /// Colorize output?
+    #[structopt(long, name = "WHEN", default_value = "auto", raw(possible_values = "&ColorWhen::variants()", case_insensitive = "true"))]
+    color: ColorWhen,
+
+    /// Package specs to remove, e.g. "=dev-libs/openssl-1.1.1k:0"
+    #[structopt(name = "PACKAGE SPECS", raw(required = "true"))]
+    specs: Vec<String>,
+}
+
+fn main() {
+    let opts = Opts::from_args();
+    opts.color.force();
+
+    let reg = opts.env.ndbam();
+    let mut missing_packages = false;
+    let mut any_problems = false;
+
+    let mut packages: Vec<PackageView> = Vec::new();
+    for text in &opts.specs {
+        let spec = match PackageDepSpec::parse(text) {
+            Ok(spec) => spec,
+            Err(err) => {
+                println!("{} - {}", text, err.red().bold());
+                missing_packages = true;
+                continue;
+            }
+        };
+        let mut found = false;
+        for pkg in reg.matching(&spec).unwrap_or_else(|err| fail(err)) {
+            found = true;
+            match pkg {
+                Ok(pkg) => {
+                    if !packages.iter().any(|other| other.id() == pkg.id()) {
+                        packages.push(pkg);
+                    }
+                }
+                Err(err) => {
+                    eprintln!("{}: {}", "Error".red().bold(), err);
+                    any_problems = true;
+                }
+            }
+        }
+        if !found {
+            println!("{} - {}", text, "Not found".red().bold());
+            missing_packages = true;
+        }
+    }
+
+    let policy = if opts.force { UnmergePolicy::Force } else { UnmergePolicy::Safe };
+    for pkg in packages {
+        println!("{}:{}", pkg.full_name(), pkg.slot().unwrap_or("0"));
+        let plan = if opts.dry_run {
+            pkg.unmerge_plan(&opts.env.root, policy)
+        } else {
+            pkg.unmerge(&opts.env.root, policy)
+        };
+        let plan = match plan {
+            Ok(plan) => plan,
+            Err(err) => {
+                println!("  X {}", err.to_string().red());
+                any_problems = true;
+                continue;
+            }
+        };
+        for removal in plan {
+            match removal {
+                Removal::Remove(entry) => println!("  - {}", entry.path().to_string_lossy()),
+                Removal::Keep(entry, reason) => {
+                    let class = match reason {
+                        KeepReason::Modified(_) => {
+                            any_problems = true;
+                            'M'
+                        }
+                        _ => '=',
+                    };
+                    println!("  {} {} {}", class, entry.path().to_string_lossy().yellow(), reason);
+                }
+            }
+        }
+    }
+
+    if any_problems {
+        std::process::exit(1);
+    } else if missing_packages {
+        std::process::exit(2);
+    }
+}
+
+fn fail<E: std::fmt::Display>(err: E) -> ! {
+    eprintln!("Error: {}", err);
+    std::process::exit(1);
+}
@@ -0,0 +1,158 @@
+mod colorful;
+mod env_opts;
+
+use std::path::PathBuf;
+use std::process::Command;
+use structopt::clap::AppSettings;
+use structopt::StructOpt;
+
+use colorful::*;
+use env_opts::*;
+use ndbam::*;
+use ndbam::yields::Yield;
+
+const DEFAULT_REPO_PATH : &str = "/var/db/paludis/repositories/installed";
+
+#[derive(StructOpt, Debug)]
+#[structopt(raw(global_settings = "&[AppSettings::ColoredHelp]"))]
+struct Opts {
+    #[structopt(flatten)]
+    env: EnvOpts,
+
+    /// Colorize output?
+    #[structopt(long, name = "WHEN", default_value = "auto", raw(possible_values = "&ColorWhen::variants()", case_insensitive = "true"))]
+    color: ColorWhen,
+
+    #[structopt(subcommand)]
+    action: Option<Action>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Action {
+    /// Show files waiting for decision (default)
+    #[structopt(name = "list")]
+    List {
+        /// Only those yielded to or at these paths
+        #[structopt(name = "PATHS")]
+        paths: Vec<PathBuf>,
+    },
+
+    /// Replace intended files with yielded ones
+    #[structopt(name = "accept")]
+    Accept {
+        /// Intended or yielded paths
+        #[structopt(name = "PATHS", raw(required = "true"))]
+        paths: Vec<PathBuf>,
+    },
+
+    /// Remove yielded files keeping intended ones as is
+    #[structopt(name = "reject")]
+    Reject {
+        /// Intended or yielded paths
+        #[structopt(name = "PATHS", raw(required = "true"))]
+        paths: Vec<PathBuf>,
+    },
+
+    /// Show difference between intended and yielded files
+    #[structopt(name = "diff")]
+    Diff {
+        /// Only those yielded to or at these paths
+        #[structopt(name = "PATHS")]
+        paths: Vec<PathBuf>,
+    },
+}
+
+fn main() {
+    let opts = Opts::from_args();
+    opts.color.force();
+
+    let reg = opts.env.ndbam();
+    let pending = reg.pending_yields().unwrap_or_else(|err| fail(err));
+    let action = opts.action.unwrap_or(Action::List { paths: Vec::new() });
+    let paths = match &action {
+        Action::List { paths } | Action::Accept { paths } | Action::Reject { paths } | Action::Diff { paths } => paths,
+    };
+
+    let mut selected: Vec<&Yield> = Vec::new();
+    let mut missing_yields = false;
+    for path in paths {
+        let before = selected.len();
+        for pending in pending.iter().filter(|pending| pending.matches(path)) {
+            if !selected.iter().any(|other| other.path == pending.path) {
+                selected.push(pending);
+            }
+        }
+        if selected.len() == before {
+            println!("{} - {}", path.to_string_lossy(), "No pending yields".red().bold());
+            missing_yields = true;
+        }
+    }
+    if paths.is_empty() {
+        selected = pending.iter().collect();
+    }
+
+    let mut any_problems = false;
+    if let Action::Accept { .. } = action {
+        // Only one of files yielded to the same path can be accepted and it takes its side path
+        let (ambiguous, unique): (Vec<&Yield>, Vec<&Yield>) = selected.iter()
+            .partition(|pending| selected.iter().filter(|other| other.matches(&pending.target)).count() > 1);
+        selected = unique;
+        let mut reported: Vec<&PathBuf> = Vec::new();
+        for pending in &ambiguous {
+            if reported.iter().any(|target| pending.matches(target)) {
+                continue;
+            }
+            reported.push(&pending.target);
+            let sides: Vec<_> = ambiguous.iter()
+                .filter(|other| other.matches(&pending.target))
+                .map(|other| other.path.to_string_lossy().into_owned())
+                .collect();
+            let err = format!("Several files yielded ({}), accept one of them", sides.join(", "));
+            println!("{} {} {}", "X".red().bold(), pending.target.to_string_lossy(), err.red());
+            any_problems = true;
+        }
+    }
+
+    for pending in selected {
+        let side = pending.path.to_string_lossy();
+        let target = pending.target.to_string_lossy();
+        let outcome = match action {
+            Action::List { .. } => {
+                println!("{} {} {} ({})", target, "<-".yellow(), side, pending.package.id());
+                Ok(())
+            }
+            Action::Accept { .. } => pending.accept(&opts.env.root).map(|()| {
+                println!("{} {} {}", "*".green().bold(), target, "accepted".green());
+            }),
+            Action::Reject { .. } => pending.reject(&opts.env.root).map(|()| {
+                println!("{} {} {}", "*".green().bold(), target, "rejected".yellow());
+            }),
+            Action::Diff { .. } => {
+                let root = &opts.env.root;
+                root.real_path(&pending.target).and_then(|target| Ok((target, root.real_path(&pending.path)?)))
+                    .map(|(target, side)| {
+                        // Exit status only tells whether files differ
+                        if let Err(err) = Command::new("diff").arg("-u").arg(&*target).arg(&*side).status() {
+                            fail(err);
+                        }
+                    })
+                    .map_err(Error::from)
+            }
+        };
+        if let Err(err) = outcome {
+            println!("{} {} {}", "X".red().bold(), target, err.to_string().red());
+            any_problems = true;
+        }
+    }
+
+    if any_problems {
+        std::process::exit(1);
+    } else if missing_yields {
+        std::process::exit(2);
+    }
+}
+
+fn fail<E: std::fmt::Display>(err: E) -> ! {
+    eprintln!("Error: {}", err);
+    std::process::exit(1);
+}
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+use crate::error::*;
+
+/// The only `ndbam_format` we know how to handle.
+pub const NDBAM_FORMAT: u32 = 1;
+
+/// Layout of package entries inside of repository (`repository_format` key).
+#[derive(Debug, Clone, PartialEq)]
+pub enum RepositoryFormat {
+    ExNDBAM1,
+    Other(String),
+}
+
+impl RepositoryFormat {
+    pub fn as_str(&self) -> &str {
+        match self {
+            RepositoryFormat::ExNDBAM1 => "exndbam-1",
+            RepositoryFormat::Other(name) => name,
+        }
+    }
+}
+
+impl From<&str> for RepositoryFormat {
+    fn from(name: &str) -> Self {
+        match name {
+            "exndbam-1" => RepositoryFormat::ExNDBAM1,
+            _ => RepositoryFormat::Other(name.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for RepositoryFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Content of `ndbam.conf`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RepositoryConfig {
+    pub ndbam_format: u32,
+    pub repository_format: RepositoryFormat,
+    keys: HashMap<String, String>,
+}
+
+impl RepositoryConfig {
+    pub fn new(repository_format: RepositoryFormat) -> Self {
+        let mut keys = HashMap::new();
+        keys.insert("ndbam_format".to_string(), NDBAM_FORMAT.to_string());
+        keys.insert("repository_format".to_string(), repository_format.to_string());
+        RepositoryConfig { ndbam_format: NDBAM_FORMAT, repository_format, keys }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::parse(&fs::read_to_string(path)?).map_err(|reason| Error::Parse {
+            path: path.to_path_buf(),
+            line: None,
+            reason,
+        })
+    }
+
+    /// # Examples
+    ///
+    /// ```
+    /// # use ndbam::config::*;
+    /// let config = RepositoryConfig::parse("ndbam_format = 1\nrepository_format = exndbam-1\n").unwrap();
+    /// assert_eq!(config.repository_format, RepositoryFormat::ExNDBAM1);
+    ///
+    /// assert!(RepositoryConfig::parse("ndbam_format = 2\nrepository_format = exndbam-1\n").is_err());
+    /// ```
+    pub fn parse(text: &str) -> std::result::Result<Self, String> {
+        let mut keys = HashMap::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut kv = line.splitn(2, '=');
+            let key = kv.next().unwrap().trim();
+            let value = kv.next()
+                .ok_or_else(|| format!("Expected \"key = value\" at line {}: {:?}", n + 1, line))?
+                .trim();
+            if key.is_empty() {
+                return Err(format!("Missing key at line {}: {:?}", n + 1, line));
+            }
+            if keys.insert(key.to_string(), value.to_string()).is_some() {
+                return Err(format!("Duplicate key {:?} at line {}", key, n + 1));
+            }
+        }
+
+        let ndbam_format = keys.get("ndbam_format")
+            .ok_or("Missing ndbam_format")?;
+        let ndbam_format = ndbam_format.parse::<u32>()
+            .map_err(|err| format!("Invalid ndbam_format {:?}: {}", ndbam_format, err))?;
+        if ndbam_format != NDBAM_FORMAT {
+            return Err(format!(
+                "Unsupported ndbam_format {} (only {} is supported)", ndbam_format, NDBAM_FORMAT));
+        }
+
+        let repository_format = keys.get("repository_format")
+            .ok_or("Missing repository_format")?
+            .as_str()
+            .into();
+
+        Ok(RepositoryConfig { ndbam_format, repository_format, keys })
+    }
+
+    /// Raw value of any key (including unknown ones).
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.keys.get(key).map(String::as_str)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item=&str> {
+        self.keys.keys().map(String::as_str)
+    }
+
+    /// Paths listed in `config_protect` and `config_protect_mask` keys (separated by whitespace).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use std::path::Path;
+    /// # use ndbam::config::*;
+    /// let config = RepositoryConfig::parse("ndbam_format = 1\nrepository_format = exndbam-1\n\
+    ///                                       config_protect = /etc /usr/share/config\n\
+    ///                                       config_protect_mask = /etc/env.d\n").unwrap();
+    /// let protect = config.config_protect();
+    /// assert!(protect.is_protected(Path::new("/etc/hosts")));
+    /// assert!(!protect.is_protected(Path::new("/etc/env.d/00basic")));
+    /// assert!(!protect.is_protected(Path::new("/usr/bin/ls")));
+    /// ```
+    pub fn config_protect(&self) -> ConfigProtect {
+        let paths = |key| self.get(key).unwrap_or_default().split_whitespace().map(PathBuf::from).collect();
+        ConfigProtect { protect: paths("config_protect"), mask: paths("config_protect_mask") }
+    }
+
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "ndbam_format = {}", self.ndbam_format)?;
+        writeln!(out, "repository_format = {}", self.repository_format)?;
+        let mut rest: Vec<_> = self.keys.iter()
+            .filter(|(key, _)| *key != "ndbam_format" && *key != "repository_format")
+            .collect();
+        rest.sort();
+        for (key, value) in rest {
+            writeln!(out, "{} = {}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Paths where modified files are never overwritten by merge (e.g. `/etc`) unless they are
+/// under one of excluded paths (e.g. `/etc/env.d`).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConfigProtect {
+    pub protect: Vec<PathBuf>,
+    pub mask: Vec<PathBuf>,
+}
+
+impl ConfigProtect {
+    /// Adds more protected and excluded paths.
+    pub fn extend(&mut self, protect: &[PathBuf], mask: &[PathBuf]) {
+        self.protect.extend_from_slice(protect);
+        self.mask.extend_from_slice(mask);
+    }
+
+    pub fn is_protected(&self, path: &Path) -> bool {
+        self.protect.iter().any(|protect| path.starts_with(protect))
+            && !self.mask.iter().any(|mask| path.starts_with(mask))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use totems::*;
+
+    #[test]
+    fn paludis_conf() {
+        assert_ok!(
+            RepositoryConfig::parse("ndbam_format = 1\nrepository_format = exndbam-1\n"),
+            value == RepositoryConfig::new(RepositoryFormat::ExNDBAM1)
+        );
+    }
+
+    #[test]
+    fn other_format() {
+        assert_ok!(
+            RepositoryConfig::parse("ndbam_format=1\nrepository_format=whatever-2").map(|c| c.repository_format),
+            value == RepositoryFormat::Other("whatever-2".to_string())
+        );
+    }
+
+    #[test]
+    fn extra_keys() {
+        let config = RepositoryConfig::parse("# comment\n\nndbam_format = 1\nrepository_format = exndbam-1\nfoo = bar baz\n").unwrap();
+        assert_eq!(config.get("foo"), Some("bar baz"));
+        assert_eq!(config.get("absent"), None);
+    }
+
+    #[test]
+    fn bad_format() {
+        assert_err!(RepositoryConfig::parse(""));
+        assert_err!(RepositoryConfig::parse("repository_format = exndbam-1"));
+        assert_err!(RepositoryConfig::parse("ndbam_format = 1"));
+        assert_err!(RepositoryConfig::parse("ndbam_format = 2\nrepository_format = exndbam-1"));
+        assert_err!(RepositoryConfig::parse("ndbam_format = one\nrepository_format = exndbam-1"));
+        assert_err!(RepositoryConfig::parse("ndbam_format = 1\nrepository_format = exndbam-1\ngarbage"));
+        assert_err!(RepositoryConfig::parse("ndbam_format = 1\nndbam_format = 1\nrepository_format = exndbam-1"));
+    }
+
+    #[test]
+    fn round_trip() {
+        let config = RepositoryConfig::parse("ndbam_format = 1\nrepository_format = exndbam-1\nfoo = bar\n").unwrap();
+        let mut buf = Vec::new();
+        config.write_to(&mut buf).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), "ndbam_format = 1\nrepository_format = exndbam-1\nfoo = bar\n");
+    }
+}
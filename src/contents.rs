@@ -1,4 +1,6 @@
+mod attributes;
 mod parser;
+mod vdb;
 mod writer;
 
 use std::collections::HashMap;
@@ -6,12 +8,13 @@ use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
 pub use crate::utils::hashing::*;
+pub use attributes::*;
 pub use parser::*;
 pub use writer::*;
 
 /// Represents NDBAM/VDB contents entry
 ///
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Entry {
     Dir { path: PathBuf },
     File { path: PathBuf, md5: String, mtime: SystemTime, extra: HashMap<String, String> },
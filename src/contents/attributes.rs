@@ -0,0 +1,149 @@
+//! Ownership, permissions and extended attributes recorded as extra tokens of `contents` entries:
+//!
+//! ```text
+//! type=file path=/bin/ping md5=... mtime=... uid=0 gid=0 mode=0755 xattr:security.capability=0100...
+//! ```
+//!
+//! Values of extended attributes are hex-encoded. Only file capabilities (`security.capability`)
+//! and `user.*` attributes are recorded since others are either volatile (e.g. `security.selinux`
+//! labels change on relabel) or private to the system. Names that do not fit into token key (not
+//! printable ASCII, `=` or `\`) are skipped as well. Symlinks get no `mode` since it is
+//! meaningless for them. Entries without these tokens (e.g. recorded by Paludis) are not checked.
+//!
+//! Files merged as hardlinks of each other also share `hardlink=<path>` token naming their group
+//! after the first member merged (e.g. `hardlink=/usr/bin/git` for `/usr/libexec/git-core/git`).
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fs::Metadata;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use super::Entry;
+use crate::utils::copy::{xattr_names, xattr_value};
+
+pub const UID_KEY: &str = "uid";
+pub const GID_KEY: &str = "gid";
+pub const MODE_KEY: &str = "mode";
+/// Prefix of keys for extended attributes followed by attribute name (e.g. `xattr:user.foo`).
+pub const XATTR_PREFIX: &str = "xattr:";
+pub const HARDLINK_KEY: &str = "hardlink";
+
+/// Extra tokens describing attributes of file or symlink at `real_path`.
+pub fn attributes_of(real_path: &Path, metadata: &Metadata) -> io::Result<HashMap<String, String>> {
+    let mut extra = HashMap::new();
+    extra.insert(UID_KEY.to_string(), metadata.uid().to_string());
+    extra.insert(GID_KEY.to_string(), metadata.gid().to_string());
+    if !metadata.file_type().is_symlink() {
+        extra.insert(MODE_KEY.to_string(), format!("{:04o}", metadata.mode() & 0o7777));
+    }
+    for name in xattrs_of(real_path)? {
+        if let Some(key) = recorded_xattr(&name) {
+            let value = xattr_value(real_path, &name)?;
+            extra.insert(format!("{}{}", XATTR_PREFIX, key), hex::encode(value));
+        }
+    }
+    Ok(extra)
+}
+
+/// Name of extended attribute as it is recorded (if it is).
+fn recorded_xattr(name: &CStr) -> Option<&str> {
+    let name = name.to_str().ok()?;
+    let representable = name.bytes().all(|ch| ch.is_ascii_graphic() && ch != b'=' && ch != b'\\');
+    if representable && (name == "security.capability" || name.starts_with("user.")) {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+impl Entry {
+    /// Keys of recorded attributes that do not match file or symlink at `real_path` anymore.
+    ///
+    /// Only extended attributes that were recorded (and would be recorded now) are checked.
+    pub fn changed_attributes(&self, real_path: &Path) -> io::Result<Vec<String>> {
+        let extra = match self {
+            Entry::File { extra, .. } | Entry::Sym { extra, .. } => extra,
+            Entry::Dir { .. } => return Ok(Vec::new()),
+        };
+        let metadata = real_path.symlink_metadata()?;
+        let actual = attributes_of(real_path, &metadata)?;
+        let mut changed: Vec<String> = extra.iter()
+            .filter(|(key, _)| {
+                key.as_str() == UID_KEY || key.as_str() == GID_KEY || key.as_str() == MODE_KEY
+                    || key.strip_prefix(XATTR_PREFIX).and_then(|name| CString::new(name).ok())
+                        .is_some_and(|name| recorded_xattr(&name).is_some())
+            })
+            .filter(|(key, value)| actual.get(key.as_str()) != Some(value))
+            .map(|(key, _)| key.clone())
+            .collect();
+        changed.sort();
+        Ok(changed)
+    }
+
+    /// Group of files that should stay hardlinks of each other (if recorded).
+    pub fn hardlink_group(&self) -> Option<&str> {
+        match self {
+            Entry::File { extra, .. } => extra.get(HARDLINK_KEY).map(String::as_str),
+            _ => None,
+        }
+    }
+}
+
+fn xattrs_of(real_path: &Path) -> io::Result<Vec<CString>> {
+    match xattr_names(real_path) {
+        Err(ref err) if err.raw_os_error() == Some(libc::ENOTSUP) => Ok(Vec::new()),
+        res => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::copy::set_xattr;
+    use crate::utils::virtual_root::*;
+    use std::fs;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    #[test]
+    fn record_and_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ping");
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o4711)).unwrap();
+        let xattr = CString::new("user.ndbam").unwrap();
+        let has_xattrs = set_xattr(&path, &xattr, b"\x01\x02").is_ok();
+        for skipped in &["user.a=b", "user.a b", "trusted.ndbam"] {
+            let _ = set_xattr(&path, &CString::new(*skipped).unwrap(), b"");
+        }
+        symlink("ping", dir.path().join("link")).unwrap();
+
+        let root = root_at_buf(dir.path().to_owned());
+        let entry = Entry::from_path(&path, &root).unwrap();
+        match &entry {
+            Entry::File { extra, .. } => {
+                assert_eq!(extra.get(MODE_KEY).map(String::as_str), Some("4711"));
+                assert_eq!(extra.get(UID_KEY), Some(&path.metadata().unwrap().uid().to_string()));
+                if has_xattrs {
+                    assert_eq!(extra.get("xattr:user.ndbam").map(String::as_str), Some("0102"));
+                }
+                assert_eq!(extra.keys().filter(|key| key.starts_with(XATTR_PREFIX)).count(), has_xattrs as usize);
+            }
+            other => panic!("unexpected entry {:?}", other),
+        }
+        assert_eq!(entry.changed_attributes(&path).unwrap(), Vec::<String>::new());
+        match Entry::from_path(&dir.path().join("link"), &root).unwrap() {
+            Entry::Sym { extra, .. } => assert!(!extra.contains_key(MODE_KEY) && extra.contains_key(GID_KEY)),
+            other => panic!("unexpected entry {:?}", other),
+        }
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        if has_xattrs {
+            set_xattr(&path, &xattr, b"\x03").unwrap();
+            assert_eq!(entry.changed_attributes(&path).unwrap(), vec![MODE_KEY, "xattr:user.ndbam"]);
+        } else {
+            assert_eq!(entry.changed_attributes(&path).unwrap(), vec![MODE_KEY]);
+        }
+    }
+}
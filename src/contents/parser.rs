@@ -99,7 +99,7 @@ type Tokens<'s> = HashMap<&'s str, String>;
 
 named!(hspace<&[u8], ()>, do_parse!(verify!(call!(is_a(b" \t")), |sp: &[u8]| sp.len() > 0) >> ()));
 named!(unescaped_value_chunk<&[u8], &[u8]>, verify!(call!(is_not(b" \t\\")), |chunk: &[u8]| chunk.len() > 0));
-named!(key<&[u8], &str>, do_parse!(name: is_not!(b"=") >> (std::str::from_utf8(name).unwrap())));
+named!(key<&[u8], &str>, map_res!(is_not!(b"="), std::str::from_utf8));
 named!(value<&[u8], String>, map_res!(
     escaped_transform!(unescaped_value_chunk, b'\\', alt!(tag!("n") => { |_| &b"\n"[..] } | take!(1))),
     map_utf8));
@@ -134,6 +134,7 @@ mod token_tests {
     #[test] fn key_bad() {
         assert!(key(b"=def").is_err());
         assert!(key(b"").is_err());
+        assert_err!(key(b"\xff=def"));
     }
 
     #[test] fn value_basic() {
@@ -0,0 +1,171 @@
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::Entry;
+
+impl Entry {
+    /// Parses line of Gentoo VDB `CONTENTS`.
+    ///
+    /// FIFOs (`fif`) and device nodes (`dev`) give `None` since contents have no entry for them.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use totems::*;
+    /// # use std::default::Default;
+    /// # use std::path::PathBuf;
+    /// # use std::time::{UNIX_EPOCH, Duration};
+    /// # use ndbam::contents::Entry;
+    ///
+    /// assert_ok!(Entry::parse_vdb(b"dir /usr/share/doc"), value == Some(Entry::Dir { path: PathBuf::from("/usr/share/doc") }));
+    /// assert_ok!(Entry::parse_vdb(b"obj /usr/bin/some tool d692bb800 1549752022"), value == Some(Entry::File {
+    ///            path: PathBuf::from("/usr/bin/some tool"),
+    ///            md5: "d692bb800".to_string(),
+    ///            mtime: UNIX_EPOCH + Duration::from_secs(1549752022),
+    ///            extra: Default::default() }));
+    /// assert_ok!(Entry::parse_vdb(b"sym /usr/lib/libz.so -> libz.so.1 1549752022"), value == Some(Entry::Sym {
+    ///            path: PathBuf::from("/usr/lib/libz.so"),
+    ///            target: PathBuf::from("libz.so.1"),
+    ///            mtime: UNIX_EPOCH + Duration::from_secs(1549752022),
+    ///            extra: Default::default() }));
+    /// assert_eq!(Entry::parse_vdb(b"fif /run/fifo"), Ok(None));
+    /// assert_err!(Entry::parse_vdb(b"sok /run/socket"));
+    /// ```
+    pub fn parse_vdb(i: &[u8]) -> Result<Option<Entry>, String> {
+        if i.len() < 4 || i[3] != b' ' {
+            return Err(format!("Malformed entry {:?}", String::from_utf8_lossy(i)));
+        }
+        let (kind, rest) = (&i[..3], &i[4..]);
+        match kind {
+            b"dir" => Ok(Some(Entry::Dir { path: to_path(rest)? })),
+            b"fif" | b"dev" => to_path(rest).map(|_| None),
+            b"obj" => {
+                let (rest, mtime) = split_last(rest)?;
+                let (path, md5) = split_last(rest)?;
+                Ok(Some(Entry::File {
+                    path: to_path(path)?,
+                    md5: String::from_utf8_lossy(md5).into_owned(),
+                    mtime: parse_mtime(mtime)?,
+                    extra: Default::default(),
+                }))
+            }
+            b"sym" => {
+                let (rest, mtime) = split_last(rest)?;
+                let arrow = rest.windows(4).position(|w| w == b" -> ")
+                    .ok_or_else(|| "Missing symlink target".to_string())?;
+                Ok(Some(Entry::Sym {
+                    path: to_path(&rest[..arrow])?,
+                    target: PathBuf::from(OsStr::from_bytes(&rest[arrow + 4..])),
+                    mtime: parse_mtime(mtime)?,
+                    extra: Default::default(),
+                }))
+            }
+            _ => Err(format!("Unsupported type {:?}", String::from_utf8_lossy(kind))),
+        }
+    }
+
+    /// Formats entry as line of Gentoo VDB `CONTENTS` (without trailing newline).
+    ///
+    /// Extra tokens are dropped since VDB have no place for them.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use std::path::PathBuf;
+    /// # use std::time::{UNIX_EPOCH, Duration};
+    /// # use ndbam::contents::Entry;
+    /// let entry = Entry::Sym {
+    ///     path: PathBuf::from("/usr/lib/libz.so"),
+    ///     target: PathBuf::from("libz.so.1"),
+    ///     mtime: UNIX_EPOCH + Duration::from_secs(1549752022),
+    ///     extra: Default::default(),
+    /// };
+    /// assert_eq!(entry.to_vdb().unwrap(), b"sym /usr/lib/libz.so -> libz.so.1 1549752022");
+    /// assert!(Entry::Dir { path: PathBuf::from("/multiple\nlines") }.to_vdb().is_err());
+    /// ```
+    pub fn to_vdb(&self) -> Result<Vec<u8>, String> {
+        let path = self.path().as_os_str().as_bytes();
+        if path.contains(&b'\n') {
+            return Err(format!("VDB cannot represent newline in {:?}", self.path()));
+        }
+        let mut line = Vec::with_capacity(path.len() + 64);
+        match self {
+            Entry::Dir { .. } => {
+                line.extend_from_slice(b"dir ");
+                line.extend_from_slice(path);
+            }
+            Entry::File { md5, mtime, .. } => {
+                line.extend_from_slice(b"obj ");
+                line.extend_from_slice(path);
+                line.extend_from_slice(format!(" {} {}", md5, format_mtime(mtime)?).as_bytes());
+            }
+            Entry::Sym { target, mtime, .. } => {
+                let target = target.as_os_str().as_bytes();
+                if path.windows(4).any(|w| w == b" -> ") || target.contains(&b'\n') {
+                    return Err(format!("VDB cannot represent symlink {:?}", self.path()));
+                }
+                line.extend_from_slice(b"sym ");
+                line.extend_from_slice(path);
+                line.extend_from_slice(b" -> ");
+                line.extend_from_slice(target);
+                line.extend_from_slice(format!(" {}", format_mtime(mtime)?).as_bytes());
+            }
+        }
+        Ok(line)
+    }
+}
+
+fn format_mtime(mtime: &SystemTime) -> Result<u64, String> {
+    mtime.duration_since(UNIX_EPOCH).map(|epoch| epoch.as_secs()).map_err(|err| err.to_string())
+}
+
+/// Splits off last space-separated field.
+fn split_last(i: &[u8]) -> Result<(&[u8], &[u8]), String> {
+    match i.iter().rposition(|ch| *ch == b' ') {
+        Some(n) => Ok((&i[..n], &i[n + 1..])),
+        None => Err(format!("Missing fields in {:?}", String::from_utf8_lossy(i))),
+    }
+}
+
+fn to_path(i: &[u8]) -> Result<PathBuf, String> {
+    if i.first() != Some(&b'/') {
+        return Err(format!("Path is not absolute {:?}", String::from_utf8_lossy(i)));
+    }
+    Ok(PathBuf::from(OsStr::from_bytes(i)))
+}
+
+fn parse_mtime(i: &[u8]) -> Result<SystemTime, String> {
+    let secs = std::str::from_utf8(i).ok().and_then(|text| text.parse::<u64>().ok())
+        .ok_or_else(|| format!("Invalid mtime {:?}", String::from_utf8_lossy(i)))?;
+    Ok(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use totems::*;
+
+    #[test]
+    fn bad_lines() {
+        for line in &[&b""[..], b"dir", b"dir relative", b"obj /usr/bin/foo", b"obj /usr/bin/foo abc def",
+                      b"sym /usr/lib/libz.so libz.so.1 1549752022", b"dev dev/null", b"fif"] {
+            assert!(Entry::parse_vdb(line).is_err(), "{:?}", String::from_utf8_lossy(line));
+        }
+    }
+
+    #[test]
+    fn special_paths() {
+        assert_ok!(Entry::parse_vdb(b"sym /a -> b -> c 0"), value == Some(Entry::Sym {
+            path: PathBuf::from("/a"),
+            target: PathBuf::from("b -> c"),
+            mtime: UNIX_EPOCH,
+            extra: Default::default(),
+        }));
+        assert_ok!(Entry::parse_vdb(b"dir /bad\x9cbyte"), value == Some(Entry::Dir {
+            path: PathBuf::from(OsStr::from_bytes(b"/bad\x9cbyte")),
+        }));
+        assert_eq!(Entry::parse_vdb(b"dev /dev/null"), Ok(None));
+    }
+}
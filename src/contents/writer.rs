@@ -61,13 +61,10 @@ impl<T: io::Write> ContentsWriter<T> {
     }
 
     fn write_mtime(&mut self, mtime: &SystemTime) -> io::Result<()> {
-        self.write_raw(
-            &mtime
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs()
-                .to_string(),
-        )
+        let epoch = mtime
+            .duration_since(UNIX_EPOCH)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        self.write_raw(&epoch.as_secs().to_string())
     }
 
     fn write_extra_tokens(&mut self, extra: &HashMap<String, String>) -> io::Result<()> {
@@ -0,0 +1,311 @@
+use std::fmt;
+
+use crate::package_id::PackageId;
+use crate::version::Version;
+
+/// Version restriction operator of [`PackageDepSpec`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Less,
+    LessOrEqual,
+    Equal,
+    /// `=cat/pkg-1.2*`: any version that starts with given components
+    EqualStar,
+    /// `~cat/pkg-1.2`: same version with any revision
+    Tilde,
+    GreaterOrEqual,
+    Greater,
+}
+
+impl Operator {
+    fn parse(i: &str) -> (Option<Operator>, &str) {
+        for (prefix, op) in &[
+            ("<=", Operator::LessOrEqual),
+            (">=", Operator::GreaterOrEqual),
+            ("<", Operator::Less),
+            (">", Operator::Greater),
+            ("=", Operator::Equal),
+            ("~", Operator::Tilde),
+        ] {
+            if let Some(tail) = i.strip_prefix(prefix) {
+                return (Some(*op), tail);
+            }
+        }
+        (None, i)
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Operator::Less => "<",
+            Operator::LessOrEqual => "<=",
+            Operator::Equal | Operator::EqualStar => "=",
+            Operator::Tilde => "~",
+            Operator::GreaterOrEqual => ">=",
+            Operator::Greater => ">",
+        }
+    }
+}
+
+/// Restriction for category or package name part of spec.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NamePattern {
+    Any,
+    Exact(String),
+}
+
+impl NamePattern {
+    fn parse(i: &str) -> Result<NamePattern, String> {
+        if i == "*" {
+            Ok(NamePattern::Any)
+        } else if i.is_empty() || i.contains(['*', '/', ':']) {
+            Err(format!("Invalid name {:?}", i))
+        } else if split_name_version(i).is_some() {
+            Err(format!("Name {:?} ends with version (missing operator?)", i))
+        } else {
+            Ok(NamePattern::Exact(i.to_string()))
+        }
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            NamePattern::Any => true,
+            NamePattern::Exact(expected) => expected == value,
+        }
+    }
+}
+
+impl fmt::Display for NamePattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NamePattern::Any => f.write_str("*"),
+            NamePattern::Exact(name) => f.write_str(name),
+        }
+    }
+}
+
+/// Specification of packages to select (e.g. `>=dev-libs/openssl-1.1:0`).
+///
+/// Spec without category (e.g. `openssl`) matches package with such name in any category (or
+/// without one).
+///
+/// # Examples
+///
+/// ```
+/// # use ndbam::dep_spec::PackageDepSpec;
+/// # use ndbam::package_id::PackageId;
+/// let id = PackageId::from_entry("dev-libs---openssl", "1.1.1b:0").unwrap();
+/// let matches = |spec| PackageDepSpec::parse(spec).unwrap().matches(&id);
+///
+/// assert!(matches("dev-libs/openssl"));
+/// assert!(matches("openssl"));
+/// assert!(matches(">=dev-libs/openssl-1.1:0"));
+/// assert!(matches("=dev-libs/openssl-1.1*"));
+/// assert!(matches("dev-libs/*"));
+/// assert!(matches("*/*:0"));
+/// assert!(!matches("<dev-libs/openssl-1.1"));
+/// assert!(!matches("*/*:1.0"));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackageDepSpec {
+    /// `None` if spec were given without category at all
+    pub category: Option<NamePattern>,
+    pub name: NamePattern,
+    pub version: Option<(Operator, Version)>,
+    pub slot: Option<String>,
+}
+
+impl PackageDepSpec {
+    pub fn parse(text: &str) -> Result<PackageDepSpec, String> {
+        let (op, rest) = Operator::parse(text);
+
+        let mut parts = rest.splitn(2, ':');
+        let rest = parts.next().unwrap_or_default();
+        let slot = match parts.next() {
+            Some(slot) if slot.is_empty() || slot.contains(':') => {
+                return Err(format!("Invalid slot in {:?}", text))
+            }
+            slot => slot.map(String::from),
+        };
+
+        let (qualified_name, version) = match op {
+            None => (rest, None),
+            Some(op) => {
+                let (name, version) = split_name_version(rest)
+                    .ok_or_else(|| format!("Missing version in {:?}", text))?;
+                match (op, version.strip_suffix('*')) {
+                    (Operator::Equal, Some(prefix)) => {
+                        (name, Some((Operator::EqualStar, Version::parse(prefix)?)))
+                    }
+                    (_, Some(_)) => return Err(format!("Wildcard is allowed only with \"=\" in {:?}", text)),
+                    (op, None) => (name, Some((op, Version::parse(version)?))),
+                }
+            }
+        };
+
+        let mut parts = qualified_name.splitn(2, '/');
+        let first = NamePattern::parse(parts.next().unwrap_or_default())?;
+        let (category, name) = match parts.next() {
+            Some(name) => (Some(first), NamePattern::parse(name)?),
+            None => (None, first),
+        };
+
+        Ok(PackageDepSpec { category, name, version, slot })
+    }
+
+    pub fn matches(&self, id: &PackageId) -> bool {
+        let category_matches = match (&self.category, &id.category) {
+            (None, _) => true,
+            (Some(pattern), Some(category)) => pattern.matches(category),
+            (Some(pattern), None) => *pattern == NamePattern::Any,
+        };
+        if !category_matches || !self.name.matches(&id.name) {
+            return false;
+        }
+
+        if let Some(ref slot) = self.slot {
+            if id.slot.as_ref() != Some(slot) {
+                return false;
+            }
+        }
+
+        match self.version {
+            None => true,
+            Some((op, ref version)) => match op {
+                Operator::Less => id.version < *version,
+                Operator::LessOrEqual => id.version <= *version,
+                Operator::Equal => id.version == *version,
+                Operator::EqualStar => id.version.starts_with(version),
+                Operator::Tilde => id.version.cmp_without_revision(version) == std::cmp::Ordering::Equal,
+                Operator::GreaterOrEqual => id.version >= *version,
+                Operator::Greater => id.version > *version,
+            },
+        }
+    }
+
+    /// Fully qualified name if spec refers to exactly one package name.
+    pub fn exact_name(&self) -> Option<String> {
+        match (&self.category, &self.name) {
+            (Some(NamePattern::Exact(category)), NamePattern::Exact(name)) => {
+                Some(format!("{}/{}", category, name))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PackageDepSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some((op, _)) = self.version {
+            f.write_str(op.as_str())?;
+        }
+        if let Some(ref category) = self.category {
+            write!(f, "{}/", category)?;
+        }
+        write!(f, "{}", self.name)?;
+        if let Some((op, ref version)) = self.version {
+            write!(f, "-{}", version)?;
+            if op == Operator::EqualStar {
+                f.write_str("*")?;
+            }
+        }
+        if let Some(ref slot) = self.slot {
+            write!(f, ":{}", slot)?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits `cat/pkg-1.0-r1` into `cat/pkg` and `1.0-r1` (wildcard suffix allowed).
+pub(crate) fn split_name_version(i: &str) -> Option<(&str, &str)> {
+    i.match_indices('-')
+        .map(|(n, _)| (&i[..n], &i[n + 1..]))
+        .find(|(name, version)| {
+            !name.is_empty() && Version::parse(version.strip_suffix('*').unwrap_or(version)).is_ok()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use totems::*;
+
+    fn id(name: &str, version: &str) -> PackageId {
+        PackageId::from_entry(name, version).unwrap()
+    }
+
+    fn spec(text: &str) -> PackageDepSpec {
+        PackageDepSpec::parse(text).unwrap()
+    }
+
+    #[test]
+    fn parse_good() {
+        for text in &["foo", "cat/foo", "cat/*", "*/*", "*/foo", "*/*:0", ">=cat/foo-1.1:0", "=cat/foo-1*",
+                      "~cat/foo-1.0", "<cat/foo-bar-2-r1", "=cat/foo-2-scm", "cat/foo:slot/sub"] {
+            assert_ok!(PackageDepSpec::parse(text));
+        }
+    }
+
+    #[test]
+    fn parse_bad() {
+        for text in &["", "/foo", "cat/", "cat/foo/bar", ">=cat/foo", "=cat/foo-bar", "cat/foo-1.0",
+                      ">=cat/foo-1*", "cat/foo:", "cat/f*o", "cat/foo:0:1"] {
+            assert!(PackageDepSpec::parse(text).is_err(), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for text in &["foo", "*/*:0", ">=cat/foo-1.1:0", "=cat/foo-1*", "~cat/foo-1.0", "<cat/foo-bar-2-r1"] {
+            assert_eq!(spec(text).to_string(), *text);
+        }
+    }
+
+    #[test]
+    fn version_with_dashes_in_name() {
+        assert_ok!(PackageDepSpec::parse("=cat/foo-bar-2-r1").map(|s| s.name), value == NamePattern::Exact("foo-bar".to_string()));
+        assert_ok!(PackageDepSpec::parse("=cat/foo-2bar-2").map(|s| s.name), value == NamePattern::Exact("foo-2bar".to_string()));
+    }
+
+    #[test]
+    fn operators() {
+        let pkg = id("cat---foo", "1.2-r1:0");
+        assert!(spec("<cat/foo-1.3").matches(&pkg));
+        assert!(!spec("<cat/foo-1.2-r1").matches(&pkg));
+        assert!(spec("<=cat/foo-1.2-r1").matches(&pkg));
+        assert!(spec("=cat/foo-1.2-r1").matches(&pkg));
+        assert!(!spec("=cat/foo-1.2").matches(&pkg));
+        assert!(spec("~cat/foo-1.2").matches(&pkg));
+        assert!(spec(">=cat/foo-1.2").matches(&pkg));
+        assert!(spec(">cat/foo-1.2").matches(&pkg));
+        assert!(!spec(">cat/foo-1.2-r1").matches(&pkg));
+    }
+
+    #[test]
+    fn equal_star() {
+        assert!(spec("=cat/foo-1*").matches(&id("cat---foo", "1")));
+        assert!(spec("=cat/foo-1*").matches(&id("cat---foo", "1.0.3")));
+        assert!(spec("=cat/foo-1.0*").matches(&id("cat---foo", "1.0_rc1")));
+        assert!(!spec("=cat/foo-1*").matches(&id("cat---foo", "10")));
+        assert!(!spec("=cat/foo-1.1*").matches(&id("cat---foo", "1.0")));
+    }
+
+    #[test]
+    fn names() {
+        let pkg = id("cat---foo", "1:0");
+        let bare = id("foo", "1:0");
+        assert!(spec("foo").matches(&pkg));
+        assert!(spec("foo").matches(&bare));
+        assert!(spec("*/foo").matches(&pkg));
+        assert!(spec("*/foo").matches(&bare));
+        assert!(!spec("other/foo").matches(&pkg));
+        assert!(!spec("cat/foo").matches(&bare));
+        assert!(!spec("cat/bar").matches(&pkg));
+    }
+
+    #[test]
+    fn slots() {
+        assert!(spec("cat/foo:0").matches(&id("cat---foo", "1:0")));
+        assert!(!spec("cat/foo:1").matches(&id("cat---foo", "1:0")));
+        assert!(!spec("cat/foo:0").matches(&id("cat---foo", "1")));
+    }
+}
@@ -0,0 +1,72 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Everything that may go wrong while working with repository.
+#[derive(Debug)]
+pub enum Error {
+    /// Repository or package entry does not follow expected layout.
+    Layout { path: PathBuf, reason: String },
+    /// Malformed content of some file (e.g. `contents` or `ndbam.conf`).
+    Parse { path: PathBuf, line: Option<usize>, reason: String },
+    Io(io::Error),
+    /// Repository is locked by someone else and we were asked not to wait.
+    Locked { path: PathBuf },
+    /// Operation interrupted by other process should be recovered before repository can be read.
+    Interrupted { path: PathBuf },
+    /// Object we are about to install conflicts with something that already exists.
+    Collision { path: PathBuf, reason: String },
+    /// Symbolic link that is not safe to install.
+    Symlink { path: PathBuf, target: PathBuf, reason: String },
+    /// Repository layout does not support requested operation (e.g. modifying VDB).
+    Unsupported { path: PathBuf, operation: &'static str },
+}
+
+impl Error {
+    pub(crate) fn layout<P: Into<PathBuf>, S: ToString>(path: P, reason: S) -> Error {
+        Error::Layout { path: path.into(), reason: reason.to_string() }
+    }
+
+    pub(crate) fn collision<P: Into<PathBuf>, S: ToString>(path: P, reason: S) -> Error {
+        Error::Collision { path: path.into(), reason: reason.to_string() }
+    }
+
+    pub(crate) fn symlink<P: Into<PathBuf>, T: Into<PathBuf>, S: ToString>(path: P, target: T, reason: S) -> Error {
+        Error::Symlink { path: path.into(), target: target.into(), reason: reason.to_string() }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Layout { path, reason } => write!(f, "Broken layout at {:?}: {}", path, reason),
+            Error::Parse { path, line: Some(line), reason } => write!(f, "{:?}:{}: {}", path, line, reason),
+            Error::Parse { path, line: None, reason } => write!(f, "{:?}: {}", path, reason),
+            Error::Io(err) => err.fmt(f),
+            Error::Locked { path } => write!(f, "Repository at {:?} is locked by another process", path),
+            Error::Interrupted { path } => write!(f, "Repository at {:?} has interrupted operation pending", path),
+            Error::Collision { path, reason } => write!(f, "Collision at {:?}: {}", path, reason),
+            Error::Symlink { path, target, reason } => {
+                write!(f, "Bad symlink {:?} -> {:?}: {}", path, target, reason)
+            }
+            Error::Unsupported { path, operation } => write!(f, "Repository at {:?} does not support {}", path, operation),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
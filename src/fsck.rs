@@ -0,0 +1,294 @@
+//! Structural consistency checking of repository itself (as opposed to checking installed
+//! files against recorded contents).
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::{PackageView, NDBAM};
+use crate::contents::*;
+use crate::error::*;
+use crate::journal;
+use crate::path_index::canonical;
+use crate::shared_paths::SharedPaths;
+
+/// Something wrong with repository layout or metadata.
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    /// Entry in `data/` that does not describe any package (e.g. malformed `version:slot:cookie`).
+    MalformedEntry { path: PathBuf, reason: String },
+    /// Package without `contents`.
+    MissingContents { package: PathBuf },
+    /// Line of `contents` that cannot be parsed.
+    BrokenContents { path: PathBuf, line: Option<usize>, reason: String },
+    /// The same path recorded more than once in a single `contents`.
+    DuplicatePath { contents: PathBuf, path: PathBuf, identical: bool },
+    /// File or symlink owned by several packages without registered shared ownership.
+    ConflictingOwners { path: PathBuf, owners: Vec<PathBuf> },
+    /// Temporary file left by interrupted atomic update.
+    LeftoverTemp { path: PathBuf },
+    /// Package name directory without any versions.
+    EmptyName { path: PathBuf },
+}
+
+/// Suggested way to fix a [`Problem`].
+#[derive(Debug, PartialEq)]
+pub enum Repair {
+    /// Remove file or empty directory.
+    Remove(PathBuf),
+    /// Rewrite `contents` leaving only the first of identical entries.
+    DropDuplicates(PathBuf),
+    /// Requires human decision.
+    Manual(&'static str),
+}
+
+impl Problem {
+    pub fn repair(&self) -> Repair {
+        match self {
+            Problem::MalformedEntry { .. } => Repair::Manual("rename or remove entry"),
+            Problem::MissingContents { .. } => Repair::Manual("re-install package or remove its entry"),
+            Problem::BrokenContents { .. } => Repair::Manual("fix the line or re-install package"),
+            Problem::DuplicatePath { contents, identical: true, .. } => Repair::DropDuplicates(contents.clone()),
+            Problem::DuplicatePath { identical: false, .. } => Repair::Manual("keep only one of conflicting entries"),
+            Problem::ConflictingOwners { .. } => {
+                Repair::Manual("register shared ownership or remove path from all owners but one")
+            }
+            Problem::LeftoverTemp { path } => Repair::Remove(path.clone()),
+            Problem::EmptyName { path } => Repair::Remove(path.clone()),
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::MalformedEntry { path, reason } => write!(f, "Malformed entry {:?}: {}", path, reason),
+            Problem::MissingContents { package } => write!(f, "Missing contents of {:?}", package),
+            Problem::BrokenContents { path, line: Some(line), reason } => write!(f, "{:?}:{}: {}", path, line, reason),
+            Problem::BrokenContents { path, line: None, reason } => write!(f, "{:?}: {}", path, reason),
+            Problem::DuplicatePath { contents, path, identical: true } => {
+                write!(f, "Duplicate entries for {:?} in {:?}", path, contents)
+            }
+            Problem::DuplicatePath { contents, path, identical: false } => {
+                write!(f, "Conflicting entries for {:?} in {:?}", path, contents)
+            }
+            Problem::ConflictingOwners { path, owners } => write!(f, "{:?} is owned by {:?}", path, owners),
+            Problem::LeftoverTemp { path } => write!(f, "Leftover temporary file {:?}", path),
+            Problem::EmptyName { path } => write!(f, "Package without versions {:?}", path),
+        }
+    }
+}
+
+impl Repair {
+    /// Whether repair can be applied without risk of loosing any information.
+    pub fn is_safe(&self) -> bool {
+        match self {
+            Repair::Remove(_) | Repair::DropDuplicates(_) => true,
+            Repair::Manual(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Repair::Remove(path) => write!(f, "remove {:?}", path),
+            Repair::DropDuplicates(contents) => write!(f, "drop duplicate entries from {:?}", contents),
+            Repair::Manual(hint) => f.write_str(hint),
+        }
+    }
+}
+
+impl<'p> NDBAM<'p> {
+    /// Walks whole repository and reports every structural problem found.
+    pub fn fsck(&self) -> Result<Vec<Problem>> {
+        self.lock.shared()?;
+        let mut problems = Vec::new();
+        for dir in &[self.location.to_owned(), self.location.join("index"), self.location.join("index/paths")] {
+            leftover_temps(dir, &mut problems)?;
+        }
+
+        let mut owners: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+        let data = self.location.join("data");
+        let mut names: Vec<_> = data.read_dir().map_err(|err| Error::layout(&data, err))?
+            .map(|name| name.map(|name| name.path()))
+            .collect::<std::io::Result<_>>()?;
+        names.sort();
+        for name in names {
+            if !name.is_dir() {
+                continue;  // e.g. .keep
+            }
+            let mut versions: Vec<_> = name.read_dir()?
+                .map(|version| version.map(|version| version.path()))
+                .collect::<std::io::Result<_>>()?;
+            if versions.is_empty() {
+                problems.push(Problem::EmptyName { path: name });
+                continue;
+            }
+            versions.sort();
+            for version in versions {
+                match PackageView::new(version, self.lock.clone()) {
+                    Ok(pkg) => check_package(&pkg, &mut owners, &mut problems)?,
+                    Err(Error::Layout { path, reason }) => problems.push(Problem::MalformedEntry { path, reason }),
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+
+        let shared = SharedPaths::new(&self.lock);
+        for (path, owners) in owners {
+            if owners.len() > 1 && shared.owners(&path)?.is_none() {
+                problems.push(Problem::ConflictingOwners { path, owners });
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Applies repair suggested for some problem reported by [`fsck`](#method.fsck).
+    ///
+    /// Returns `false` if repair requires manual intervention.
+    pub fn repair(&self, repair: &Repair) -> Result<bool> {
+        journal::exclusive(&self.lock)?;
+        match repair {
+            Repair::Remove(path) if path.is_dir() && is_temp(path) => fs::remove_dir_all(path)?,
+            Repair::Remove(path) if path.is_dir() => fs::remove_dir(path)?,
+            Repair::Remove(path) => fs::remove_file(path)?,
+            Repair::DropDuplicates(path) => {
+                let mut kept: Vec<Entry> = Vec::new();
+                let mut positions: HashMap<PathBuf, usize> = HashMap::new();
+                for entry in read_contents(path)? {
+                    match positions.get(&canonical(entry.path())) {
+                        Some(&n) if kept[n] == entry => {}
+                        Some(_) => return Err(Error::Parse {
+                            path: path.to_owned(),
+                            line: None,
+                            reason: format!("conflicting entries for {:?}", entry.path()),
+                        }),
+                        None => {
+                            positions.insert(canonical(entry.path()), kept.len());
+                            kept.push(entry);
+                        }
+                    }
+                }
+                let mut writer = create(path.to_owned())?;
+                for entry in &kept {
+                    writer.write_entry(entry)?;
+                }
+                writer.commit()?;
+            }
+            Repair::Manual(_) => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+fn check_package(pkg: &PackageView, owners: &mut BTreeMap<PathBuf, Vec<PathBuf>>, problems: &mut Vec<Problem>) -> Result<()> {
+    leftover_temps(&pkg.location, problems)?;
+
+    let contents = pkg.location.join("contents");
+    if !contents.exists() {
+        problems.push(Problem::MissingContents { package: pkg.location.clone() });
+        return Ok(());
+    }
+
+    let mut seen: HashMap<PathBuf, Entry> = HashMap::new();
+    let mut reported = HashSet::new();
+    for entry in pkg.contents()? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(Error::Parse { path, line, reason }) => {
+                problems.push(Problem::BrokenContents { path, line, reason });
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        let path = canonical(entry.path());
+        if let Some(previous) = seen.get(&path) {
+            if reported.insert(path.clone()) {
+                problems.push(Problem::DuplicatePath {
+                    contents: contents.clone(),
+                    identical: *previous == entry,
+                    path,
+                });
+            }
+            continue;
+        }
+        if let Entry::File { .. } | Entry::Sym { .. } = entry {
+            owners.entry(path.clone()).or_default().push(pkg.location.clone());
+        }
+        seen.insert(path, entry);
+    }
+    Ok(())
+}
+
+/// Reports files left by [`AtomicFile`](crate::utils::atomic_file::AtomicFile) and directories
+/// left by interrupted unmerge in `dir`.
+fn leftover_temps(dir: &Path, problems: &mut Vec<Problem>) -> Result<()> {
+    let entries = match dir.read_dir() {
+        Ok(entries) => entries,
+        Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut temps = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_symlink() && is_temp(&entry.path()) {
+            temps.push(entry.path());
+        }
+    }
+    temps.sort();
+    problems.extend(temps.into_iter().map(|path| Problem::LeftoverTemp { path }));
+    Ok(())
+}
+
+fn is_temp(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name.to_string_lossy().starts_with(".tmp"))
+}
+
+fn read_contents(path: &Path) -> Result<Vec<Entry>> {
+    let data = fs::read(path)?;
+    data.split(|ch| *ch == b'\n')
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(n, line)| Entry::parse(line).map_err(|reason| Error::Parse {
+            path: path.to_owned(),
+            line: Some(n + 1),
+            reason,
+        }))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::virtual_root::*;
+
+    #[test]
+    fn merged_package_is_consistent() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("repo");
+        let ndbam = NDBAM::create(&location, "exndbam-1").unwrap();
+        let image = dir.path().join("image");
+        fs::create_dir_all(image.join("usr/share/doc/foo")).unwrap();
+        fs::write(image.join("usr/share/doc/foo/README"), "foo").unwrap();
+        fs::create_dir(dir.path().join("root")).unwrap();
+
+        let pkg = ndbam.new_package_version("app-misc/foo", "1", "0").unwrap();
+        pkg.merge(&root_at_buf(image), &root_at_buf(dir.path().join("root"))).unwrap();
+        assert_eq!(ndbam.fsck().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn repairs() {
+        assert!(Problem::LeftoverTemp { path: "/repo/.tmpAbC123".into() }.repair().is_safe());
+        assert!(!Problem::MissingContents { package: "/repo/data/foo/0:0".into() }.repair().is_safe());
+        let duplicate = |identical| Problem::DuplicatePath {
+            contents: "/repo/data/foo/0:0/contents".into(),
+            path: "/usr".into(),
+            identical,
+        };
+        assert_eq!(duplicate(true).repair(), Repair::DropDuplicates("/repo/data/foo/0:0/contents".into()));
+        assert!(!duplicate(false).repair().is_safe());
+    }
+}
@@ -0,0 +1,663 @@
+//! Write-ahead journal of operations that change repository together with managed file-system.
+//!
+//! ```text
+//! journal   (one record per line, fields separated by TAB)
+//!   package  <name entry>/<version entry>  package entry being populated
+//!   root     <escaped path>                root package is merged into
+//!   replaces <name entry>/<version entry>  package to retire once merge is committed
+//!   mkdir    <escaped path>                directory about to be created
+//!   move     <escaped path> <escaped path> file or directory about to be moved
+//!   replace  <escaped path> <escaped path> <escaped path>
+//!                                          file about to be moved over one of replaced
+//!                                          packages with the old one kept as hardlink backup
+//!   chmod    <escaped path> <octal mode>   directory about to get its permissions changed
+//!                                          from recorded ones
+//!   commit                                 package contents are in place
+//! ```
+//!
+//! Every record reaches the disk before the operation it describes is started. Journal left by
+//! interrupted process is rolled back (moves are undone, backups and permissions restored, created
+//! directories and package entry are removed) unless it reached `commit`. In the latter case
+//! everything that follows commit (dropping backups, retiring replaced packages, path index and
+//! shared paths) is re-done. Only the next writer (or [`NDBAM::recover`](crate::NDBAM::recover))
+//! recovers it under exclusive lock while readers fail with
+//! [`Error::Interrupted`] until then.
+//!
+//! New package entry is journaled (with lone `package` record) before it is created. Merge into
+//! it continues that journal. Otherwise it is dropped once the process is done with repository
+//! so only crash in between leaves it for recovery to remove empty package entry.
+//!
+//! Files moved across file-systems are copied into `.<name>.ndbam-copy` next to destination
+//! first. Roll back drops such leftovers as well as finished copies whose source still exists.
+
+use std::ffi::OsStr;
+use std::fmt;
+use std::io::prelude::*;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::{fs, io};
+
+use super::PackageView;
+use crate::contents::Entry;
+use crate::error::*;
+use crate::lock::RepositoryLock;
+use crate::package_id::PackageId;
+use crate::path_index::PathIndex;
+use crate::unmerger::{Removal, UnmergePolicy};
+use crate::utils::copy::{copy_path, move_node};
+use crate::utils::line_escape::*;
+use crate::utils::virtual_root::*;
+
+const JOURNAL: &str = "journal";
+
+/// Outcome of recovering operation that was interrupted (e.g. by crash or power loss).
+#[derive(Debug, PartialEq)]
+pub enum Recovery {
+    /// Nothing was committed yet and everything done so far is reverted.
+    RolledBack { package: PackageId },
+    /// Package was committed already and only remaining book-keeping was done.
+    RolledForward { package: PackageId },
+}
+
+impl fmt::Display for Recovery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Recovery::RolledBack { package } => write!(f, "Rolled back interrupted merge of {}", package),
+            Recovery::RolledForward { package } => write!(f, "Completed interrupted merge of {}", package),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Record {
+    Package(String),
+    Root(PathBuf),
+    Replaces(String),
+    Mkdir(PathBuf),
+    Move(PathBuf, PathBuf),
+    Replace(PathBuf, PathBuf, PathBuf),
+    Chmod(PathBuf, u32),
+    Commit,
+}
+
+impl Record {
+    fn to_line(&self) -> Vec<u8> {
+        let mode_field;
+        let (name, fields): (&[u8], Vec<&[u8]>) = match self {
+            Record::Package(entry) => (b"package", vec![entry.as_bytes()]),
+            Record::Root(path) => (b"root", vec![path.as_os_str().as_bytes()]),
+            Record::Replaces(entry) => (b"replaces", vec![entry.as_bytes()]),
+            Record::Mkdir(path) => (b"mkdir", vec![path.as_os_str().as_bytes()]),
+            Record::Move(from, to) => (b"move", vec![from.as_os_str().as_bytes(), to.as_os_str().as_bytes()]),
+            Record::Replace(from, to, backup) => {
+                (b"replace", vec![from.as_os_str().as_bytes(), to.as_os_str().as_bytes(), backup.as_os_str().as_bytes()])
+            }
+            Record::Chmod(path, mode) => {
+                mode_field = format!("{:o}", mode);
+                (b"chmod", vec![path.as_os_str().as_bytes(), mode_field.as_bytes()])
+            }
+            Record::Commit => (b"commit", vec![]),
+        };
+        let mut line = name.to_vec();
+        for field in fields {
+            line.push(b'\t');
+            line.extend_from_slice(&escape(field));
+        }
+        line.push(b'\n');
+        line
+    }
+
+    fn parse(line: &[u8]) -> Option<Record> {
+        let fields: Vec<_> = line.split(|ch| *ch == b'\t').collect();
+        let path = |field: &[u8]| Some(PathBuf::from(OsStr::from_bytes(&unescape(field)?)));
+        match fields.as_slice() {
+            [b"package", entry] => Some(Record::Package(String::from_utf8(unescape(entry)?).ok()?)),
+            [b"root", root] => Some(Record::Root(path(root)?)),
+            [b"replaces", entry] => Some(Record::Replaces(String::from_utf8(unescape(entry)?).ok()?)),
+            [b"mkdir", dir] => Some(Record::Mkdir(path(dir)?)),
+            [b"move", from, to] => Some(Record::Move(path(from)?, path(to)?)),
+            [b"replace", from, to, backup] => Some(Record::Replace(path(from)?, path(to)?, path(backup)?)),
+            [b"chmod", dir, mode] => {
+                let mode = u32::from_str_radix(std::str::from_utf8(mode).ok()?, 8).ok()?;
+                Some(Record::Chmod(path(dir)?, mode))
+            }
+            [b"commit"] => Some(Record::Commit),
+            _ => None,
+        }
+    }
+}
+
+/// Transaction that populates single package.
+///
+/// Dropping it without [`finish`](#method.finish) or [`roll_back`](#method.roll_back) leaves
+/// journal for recovery.
+pub(crate) struct Journal<'l> {
+    lock: &'l Rc<RepositoryLock>,
+    file: fs::File,
+    records: Vec<Record>,
+}
+
+impl<'l> Journal<'l> {
+    /// Starts transaction that merges `pkg` into `root` in place of `replaced` packages.
+    ///
+    /// Transaction left by interrupted process (if any) is completed first.
+    pub fn begin(lock: &'l Rc<RepositoryLock>, pkg: &PackageView, root: &Path, replaced: &[PackageView]) -> Result<Journal<'l>> {
+        exclusive(lock)?;
+        let entry = entry_of(lock, &pkg.location)?;
+        let replaced = replaced.iter().map(|old| entry_of(lock, &old.location)).collect::<Result<Vec<_>>>()?;
+        let mut journal = if created_entry(lock.location())?.as_ref() == Some(&entry) {
+            let file = fs::OpenOptions::new().append(true).open(lock.location().join(JOURNAL))?;
+            Journal { lock, file, records: vec![Record::Package(entry)] }
+        } else {
+            let mut journal = Journal::create(lock)?;
+            journal.record(Record::Package(entry))?;
+            journal
+        };
+        journal.record(Record::Root(root.to_owned()))?;
+        for old in replaced {
+            journal.record(Record::Replaces(old))?;
+        }
+        Ok(journal)
+    }
+
+    fn create(lock: &'l Rc<RepositoryLock>) -> Result<Journal<'l>> {
+        let file = fs::OpenOptions::new().write(true).create_new(true).open(lock.location().join(JOURNAL))?;
+        fs::File::open(lock.location())?.sync_all()?;
+        Ok(Journal { lock, file, records: Vec::new() })
+    }
+
+    /// Should be called right before creating directory at real `path`.
+    pub fn creating_dir(&mut self, path: &Path) -> Result<()> {
+        self.record(Record::Mkdir(path.to_owned()))
+    }
+
+    /// Should be called right before renaming real path `from` into `to`.
+    pub fn moving(&mut self, from: &Path, to: &Path) -> Result<()> {
+        self.record(Record::Move(from.to_owned(), to.to_owned()))
+    }
+
+    /// Should be called right before hard linking `to` as `backup` and then renaming `from`
+    /// over `to`.
+    pub fn replacing(&mut self, from: &Path, to: &Path, backup: &Path) -> Result<()> {
+        self.record(Record::Replace(from.to_owned(), to.to_owned(), backup.to_owned()))
+    }
+
+    /// Should be called right before changing permissions of existing directory at real `path`
+    /// which has `mode` now.
+    pub fn changing_mode(&mut self, path: &Path, mode: u32) -> Result<()> {
+        self.record(Record::Chmod(path.to_owned(), mode))
+    }
+
+    /// Should be called right after package contents was committed.
+    pub fn commit(&mut self) -> Result<()> {
+        self.record(Record::Commit)
+    }
+
+    /// Does everything that follows commit and forgets about transaction.
+    ///
+    /// Returns what happened to contents of replaced packages.
+    pub fn complete(self) -> Result<Vec<Removal>> {
+        let removals = complete(self.lock, &self.records)?;
+        self.finish()?;
+        Ok(removals)
+    }
+
+    fn finish(self) -> Result<()> {
+        Ok(fs::remove_file(self.lock.location().join(JOURNAL))?)
+    }
+
+    /// Reverts everything recorded so far.
+    pub fn roll_back(self) -> Result<()> {
+        roll_back(self.lock, &self.records)?;
+        self.finish()
+    }
+
+    fn record(&mut self, record: Record) -> Result<()> {
+        self.file.write_all(&record.to_line())?;
+        self.file.sync_data()?;
+        self.records.push(record);
+        Ok(())
+    }
+}
+
+/// Whether repository at `location` have journal left by (possibly still running) process.
+pub(crate) fn pending(location: &Path) -> bool {
+    location.join(JOURNAL).exists()
+}
+
+/// Should be called right before creating package entry at `location`.
+///
+/// Journal of previously created package (if any) is dropped.
+pub(crate) fn creating(lock: &Rc<RepositoryLock>, location: &Path) -> Result<()> {
+    exclusive(lock)?;
+    forget_created(lock.location())?;
+    Journal::create(lock)?.record(Record::Package(entry_of(lock, location)?))
+}
+
+/// Drops journal of created package which is not going to be merged. Should be called only while
+/// repository is recovered and locked exclusively.
+pub(crate) fn forget_created(location: &Path) -> Result<()> {
+    if created_entry(location)?.is_some() {
+        fs::remove_file(location.join(JOURNAL))?;
+    }
+    Ok(())
+}
+
+/// Entry of package whose creation is the only record of journal.
+fn created_entry(location: &Path) -> Result<Option<String>> {
+    let data = match fs::read(location.join(JOURNAL)) {
+        Ok(data) => data,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    match data.split_last() {
+        Some((b'\n', line)) if !line.contains(&b'\n') => match Record::parse(line) {
+            Some(Record::Package(entry)) => Ok(Some(entry)),
+            _ => Ok(None),
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Takes exclusive lock for changing repository recovering operation interrupted by other
+/// process first (if any).
+pub(crate) fn exclusive(lock: &Rc<RepositoryLock>) -> Result<()> {
+    if lock.is_recovered() {
+        return Ok(()); // when lock was taken (or being recovered right now)
+    }
+    recover(lock).map(drop)
+}
+
+/// Rolls journal left by interrupted process back or forward.
+///
+/// Returns `None` if there was nothing to recover. Failed recovery is attempted again by the next
+/// writer.
+pub(crate) fn recover(lock: &Rc<RepositoryLock>) -> Result<Option<Recovery>> {
+    lock.exclusive()?;
+    // Recovery changes repository itself
+    lock.set_recovered(true);
+    let recovery = recover_locked(lock);
+    lock.set_recovered(recovery.is_ok());
+    recovery
+}
+
+fn recover_locked(lock: &Rc<RepositoryLock>) -> Result<Option<Recovery>> {
+    let path = lock.location().join(JOURNAL);
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut records = Vec::new();
+    let mut lines = data.split(|ch| *ch == b'\n').enumerate().peekable();
+    while let Some((n, line)) = lines.next() {
+        if lines.peek().is_none() {
+            break; // incomplete record was never acted upon
+        }
+        match Record::parse(line) {
+            Some(record) => records.push(record),
+            None => return Err(Error::Parse { path, line: Some(n + 1), reason: "malformed journal record".to_string() }),
+        }
+    }
+
+    let entry = match records.first() {
+        Some(Record::Package(entry)) => entry.clone(),
+        Some(_) => return Err(Error::Parse { path, line: Some(1), reason: "journal should start with package".to_string() }),
+        None => {
+            // Interrupted before anything was done
+            fs::remove_file(&path)?;
+            return Ok(None);
+        }
+    };
+    let package = match entry.find('/') {
+        Some(sep) => PackageId::from_entry(&entry[..sep], &entry[sep + 1..]),
+        None => Err("package entry without version".to_string()),
+    };
+    let package = package.map_err(|reason| Error::Parse { path: path.clone(), line: Some(1), reason })?;
+
+    let recovery = if records.contains(&Record::Commit) {
+        complete(lock, &records)?;
+        Recovery::RolledForward { package }
+    } else {
+        roll_back(lock, &records)?;
+        Recovery::RolledBack { package }
+    };
+    fs::remove_file(&path)?;
+    Ok(Some(recovery))
+}
+
+/// Finishes committed transaction described by `records` tolerating steps that were done already.
+fn complete(lock: &Rc<RepositoryLock>, records: &[Record]) -> Result<Vec<Removal>> {
+    let data = lock.location().join("data");
+    let mut pkg = None;
+    let mut root = None;
+    let mut replaced = Vec::new();
+    for record in records {
+        match record {
+            Record::Package(entry) => pkg = Some(PackageView::new(data.join(entry), lock.clone())?),
+            Record::Root(path) => root = Some(root_at_buf(path.clone())),
+            Record::Replaces(entry) => replaced.push(data.join(entry)),
+            Record::Replace(_, _, backup) => remove_file_if_exists(backup)?,
+            Record::Mkdir(_) | Record::Move(..) | Record::Chmod(..) | Record::Commit => {}
+        }
+    }
+    let pkg = match pkg {
+        Some(pkg) => pkg,
+        None => return Err(Error::layout(lock.location().join(JOURNAL), "journal without package")),
+    };
+
+    let mut owned = Vec::new();
+    for entry in pkg.contents()? {
+        if let Entry::File { path, .. } | Entry::Sym { path, .. } = entry? {
+            owned.push(path);
+        }
+    }
+    PathIndex::new(lock).refresh_package(&pkg)?;
+    pkg.share_paths(&owned)?;
+
+    let mut removals = Vec::new();
+    for location in replaced.into_iter().filter(|location| location.is_dir()) {
+        let root = match root {
+            Some(ref root) => root,
+            None => return Err(Error::layout(lock.location().join(JOURNAL), "journal without root")),
+        };
+        let old = PackageView::new(location, lock.clone())?;
+        removals.extend(old.unmerge(root, UnmergePolicy::Safe)?);
+    }
+    Ok(removals)
+}
+
+/// Reverts `records` in reverse order tolerating operations that were never started.
+fn roll_back(lock: &Rc<RepositoryLock>, records: &[Record]) -> Result<()> {
+    for record in records.iter().rev() {
+        match record {
+            Record::Move(from, to) => {
+                remove_file_if_exists(&copy_path(to))?;
+                match (from.symlink_metadata(), to.symlink_metadata()) {
+                    (Err(_), Ok(_)) => move_node(to, from)?,
+                    // Copied across file-systems but source is still there
+                    (Ok(_), Ok(metadata)) if !metadata.is_dir() => fs::remove_file(to)?,
+                    _ => {}
+                }
+            }
+            Record::Replace(from, to, backup) => {
+                if backup.symlink_metadata().is_ok() {
+                    remove_file_if_exists(&copy_path(to))?;
+                    if from.symlink_metadata().is_err() && to.symlink_metadata().is_ok() {
+                        move_node(to, from)?;
+                    }
+                    fs::rename(backup, to)?;
+                }
+            }
+            Record::Mkdir(path) => remove_dir_if_empty(path)?,
+            Record::Chmod(path, mode) => {
+                if path.is_dir() {
+                    fs::set_permissions(path, fs::Permissions::from_mode(*mode))?;
+                }
+            }
+            Record::Package(entry) => {
+                let location = lock.location().join("data").join(entry);
+                match fs::remove_dir_all(&location) {
+                    Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+                    res => res?,
+                }
+                if let Some(name) = location.parent() {
+                    remove_dir_if_empty(name)?;
+                }
+                PathIndex::new(lock).refresh()?;
+            }
+            Record::Root(_) | Record::Replaces(_) | Record::Commit => {}
+        }
+    }
+    Ok(())
+}
+
+/// Package entry relative to `data/`.
+fn entry_of(lock: &Rc<RepositoryLock>, location: &Path) -> Result<String> {
+    match location.strip_prefix(lock.location().join("data")).ok().and_then(Path::to_str) {
+        Some(entry) => Ok(entry.to_string()),
+        None => Err(Error::layout(location, "package outside of repository")),
+    }
+}
+
+fn remove_file_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => Ok(res?),
+    }
+}
+
+fn remove_dir_if_empty(path: &Path) -> Result<()> {
+    match fs::remove_dir(path) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(ref err) if err.kind() == io::ErrorKind::DirectoryNotEmpty => Ok(()),
+        res => Ok(res?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{root_at_buf, NDBAM};
+
+    fn journal_of(ndbam: &NDBAM, records: &[Record]) {
+        let lines: Vec<u8> = records.iter().flat_map(Record::to_line).collect();
+        fs::write(ndbam.location.join(JOURNAL), lines).unwrap();
+    }
+
+    #[test]
+    fn records() {
+        for record in &[
+            Record::Package("app-misc---foo/1:0:C.1.2".to_string()),
+            Record::Root(PathBuf::from("/")),
+            Record::Replaces("app-misc---foo/0:0:C.0.1".to_string()),
+            Record::Mkdir(PathBuf::from("/usr/share/tab\tbed")),
+            Record::Move(PathBuf::from("/image/multiple\nlines"), PathBuf::from("/multiple\nlines")),
+            Record::Replace(PathBuf::from("/image/bin/foo"), PathBuf::from("/bin/foo"), PathBuf::from("/bin/.foo.ndbam-replaced")),
+            Record::Chmod(PathBuf::from("/var/tmp"), 0o1777),
+            Record::Commit,
+        ] {
+            let line = record.to_line();
+            assert_eq!(Record::parse(&line[..line.len() - 1]).as_ref(), Some(record));
+        }
+        assert_eq!(Record::parse(b"move\t/just-one"), None);
+    }
+
+    #[test]
+    fn roll_back_interrupted_merge() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("db");
+        let ndbam = NDBAM::create(&location, "exndbam-1").unwrap();
+        let pkg = ndbam.new_package_version("app-misc/foo", "1", "0").unwrap();
+        let entry = format!("app-misc---foo/{}", pkg.location.file_name().unwrap().to_str().unwrap());
+        fs::create_dir_all(dir.path().join("image/usr/bin")).unwrap();
+        fs::write(dir.path().join("image/usr/bin/foo"), "#!/bin/sh\n").unwrap();
+
+        // Crashed right after moving file and in the middle of writing next record
+        fs::create_dir_all(dir.path().join("root/usr/bin")).unwrap();
+        fs::rename(dir.path().join("image/usr/bin/foo"), dir.path().join("root/usr/bin/foo")).unwrap();
+        journal_of(&ndbam, &[
+            Record::Package(entry),
+            Record::Mkdir(dir.path().join("root/usr")),
+            Record::Mkdir(dir.path().join("root/usr/bin")),
+            Record::Move(dir.path().join("image/usr/bin/foo"), dir.path().join("root/usr/bin/foo")),
+            Record::Mkdir(dir.path().join("root/usr/lib")),
+        ]);
+        fs::OpenOptions::new().append(true).open(location.join(JOURNAL)).unwrap().write_all(b"move\t/hal").unwrap();
+        drop((pkg, ndbam));
+
+        let ndbam = NDBAM::new(&location).unwrap();
+        assert!(matches!(ndbam.all_packages().map(drop), Err(Error::Interrupted { .. })));
+        assert!(pending(&location));
+        match ndbam.recover().unwrap() {
+            Some(Recovery::RolledBack { package }) => assert_eq!(package.qualified_name(), "app-misc/foo"),
+            other => panic!("unexpected recovery {:?}", other),
+        }
+        assert!(dir.path().join("image/usr/bin/foo").exists());
+        assert!(!dir.path().join("root/usr").exists());
+        assert!(!location.join("data/app-misc---foo").exists());
+        assert!(!pending(&location));
+        assert_eq!(ndbam.recover().unwrap(), None);
+        assert_eq!(ndbam.fsck().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn remove_package_created_before_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("db");
+        let ndbam = NDBAM::create(&location, "exndbam-1").unwrap();
+        let old = ndbam.new_package_version("app-misc/foo", "1", "0").unwrap();
+        crate::contents::AtomicSession::commit(old.content_writer().unwrap()).unwrap();
+        let new = ndbam.new_package_version("app-misc/foo", "1", "0").unwrap();
+        let journal = fs::read(location.join(JOURNAL)).unwrap();
+        assert_eq!(journal, Record::Package(entry_of(&ndbam.lock, &new.location).unwrap()).to_line());
+        drop((old, new, ndbam));
+
+        // Process that is done with repository keeps what it created
+        assert!(!pending(&location));
+        let ndbam = NDBAM::new(&location).unwrap();
+        assert_eq!(ndbam.all_packages().unwrap().count(), 1);
+        drop(ndbam);
+
+        // Crashed before merge
+        fs::write(location.join(JOURNAL), journal).unwrap();
+        let ndbam = NDBAM::new(&location).unwrap();
+        assert!(matches!(ndbam.recover().unwrap(), Some(Recovery::RolledBack { .. })));
+        let versions: Vec<_> = fs::read_dir(location.join("data/app-misc---foo")).unwrap().collect();
+        assert_eq!(versions.len(), 1);
+        assert!(ndbam.latest_of("app-misc/foo").unwrap().unwrap().location.join("contents").exists());
+    }
+
+    #[test]
+    fn failed_recovery_blocks_writers() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("db");
+        let ndbam = NDBAM::create(&location, "exndbam-1").unwrap();
+        fs::write(location.join(JOURNAL), "rename\t/a\t/b\ncommit\n").unwrap();
+
+        assert!(matches!(ndbam.lock_exclusive(), Err(Error::Parse { .. })));
+        assert!(matches!(ndbam.new_package_version("app-misc/foo", "1", "0"), Err(Error::Parse { .. })));
+        assert!(!location.join("data/app-misc---foo").exists());
+        assert!(pending(&location));
+    }
+
+    #[test]
+    fn roll_back_interrupted_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("db");
+        let ndbam = NDBAM::create(&location, "exndbam-1").unwrap();
+        let pkg = ndbam.new_package_version("app-misc/foo", "1", "0").unwrap();
+        let entry = format!("app-misc---foo/{}", pkg.location.file_name().unwrap().to_str().unwrap());
+        fs::create_dir_all(dir.path().join("image")).unwrap();
+        fs::create_dir_all(dir.path().join("root")).unwrap();
+        for name in &["copied", "half-copied"] {
+            fs::write(dir.path().join("image").join(name), "data").unwrap();
+        }
+
+        // Crashed before removing source of one copy and in the middle of the other
+        fs::write(dir.path().join("root/copied"), "data").unwrap();
+        fs::write(dir.path().join("root/.half-copied.ndbam-copy"), "da").unwrap();
+        journal_of(&ndbam, &[
+            Record::Package(entry),
+            Record::Move(dir.path().join("image/copied"), dir.path().join("root/copied")),
+            Record::Move(dir.path().join("image/half-copied"), dir.path().join("root/half-copied")),
+        ]);
+        drop((pkg, ndbam));
+
+        // Any writer recovers first
+        let ndbam = NDBAM::new(&location).unwrap();
+        ndbam.lock_exclusive().unwrap();
+        assert!(!pending(&location));
+        assert_eq!(fs::read_dir(dir.path().join("root")).unwrap().count(), 0);
+        assert_eq!(fs::read_dir(dir.path().join("image")).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn roll_forward_committed_merge() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("db");
+        let ndbam = NDBAM::create(&location, "exndbam-1").unwrap();
+        fs::create_dir(dir.path().join("root")).unwrap();
+        for name in &["app-misc/foo", "app-misc/bar"] {
+            let image = dir.path().join("image");
+            fs::create_dir_all(image.join("etc")).unwrap();
+            fs::write(image.join("etc/shared.conf"), "shared\n").unwrap();
+            let pkg = ndbam.new_package_version(name, "1", "0").unwrap();
+            if *name == "app-misc/bar" {
+                // Pretend we crashed right after commit of second package
+                let mut content = pkg.content_writer().unwrap();
+                content.write_entry(&Entry::Dir { path: PathBuf::from("/etc") }).unwrap();
+                content.write_entry(&Entry::from_path(&image.join("etc/shared.conf"), &root_at_buf(image.clone())).unwrap()).unwrap();
+                crate::contents::AtomicSession::commit(content).unwrap();
+                let entry = format!("app-misc---bar/{}", pkg.location.file_name().unwrap().to_str().unwrap());
+                journal_of(&ndbam, &[Record::Package(entry), Record::Commit]);
+            } else {
+                pkg.merge(&root_at_buf(image), &root_at_buf(dir.path().join("root"))).unwrap();
+            }
+        }
+        fs::remove_dir_all(dir.path().join("image")).unwrap();
+        drop(ndbam);
+
+        let ndbam = NDBAM::new(&location).unwrap();
+        match ndbam.recover().unwrap() {
+            Some(Recovery::RolledForward { package }) => assert_eq!(package.qualified_name(), "app-misc/bar"),
+            other => panic!("unexpected recovery {:?}", other),
+        }
+        let mut owners: Vec<_> = ndbam.owners_of(Path::new("/etc/shared.conf")).unwrap().iter().map(PackageView::name).collect();
+        owners.sort();
+        assert_eq!(owners, vec!["app-misc/bar", "app-misc/foo"]);
+        assert_eq!(ndbam.fsck().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn restore_replaced_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("db");
+        let ndbam = NDBAM::create(&location, "exndbam-1").unwrap();
+        let old = ndbam.new_package_version("app-misc/foo", "1", "0").unwrap();
+        let new = ndbam.new_package_version("app-misc/foo", "2", "0").unwrap();
+        let entry = |pkg: &PackageView| entry_of(&ndbam.lock, &pkg.location).unwrap();
+        fs::create_dir_all(dir.path().join("image")).unwrap();
+        fs::create_dir_all(dir.path().join("root")).unwrap();
+        fs::write(dir.path().join("root/foo"), "v2").unwrap();
+        fs::write(dir.path().join("root/.foo.ndbam-replaced"), "v1").unwrap();
+        journal_of(&ndbam, &[
+            Record::Package(entry(&new)),
+            Record::Root(dir.path().join("root")),
+            Record::Replaces(entry(&old)),
+            Record::Replace(dir.path().join("image/foo"), dir.path().join("root/foo"), dir.path().join("root/.foo.ndbam-replaced")),
+        ]);
+
+        assert!(matches!(ndbam.recover().unwrap(), Some(Recovery::RolledBack { .. })));
+        assert_eq!(fs::read_to_string(dir.path().join("root/foo")).unwrap(), "v1");
+        assert_eq!(fs::read_to_string(dir.path().join("image/foo")).unwrap(), "v2");
+        assert!(!dir.path().join("root/.foo.ndbam-replaced").exists());
+        assert!(old.location.exists());
+        assert!(!new.location.exists());
+    }
+
+    #[test]
+    fn failed_merge_is_rolled_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("db");
+        let ndbam = NDBAM::create(&location, "exndbam-1").unwrap();
+        fs::create_dir_all(dir.path().join("image/opt")).unwrap();
+        fs::write(dir.path().join("image/opt/first"), "").unwrap();
+        fs::write(dir.path().join("image/opt/second"), "").unwrap();
+        fs::create_dir_all(dir.path().join("root/opt")).unwrap();
+        fs::write(dir.path().join("root/opt/second"), "collision").unwrap();
+
+        let pkg = ndbam.new_package_version("app-misc/foo", "1", "0").unwrap();
+        let image = root_at_buf(dir.path().join("image"));
+        assert!(pkg.merge(&image, &root_at_buf(dir.path().join("root"))).is_err());
+        assert!(dir.path().join("image/opt/first").exists());
+        assert!(dir.path().join("image/opt/second").exists());
+        assert!(!dir.path().join("root/opt/first").exists());
+        assert!(dir.path().join("root/opt").exists());
+        assert!(!pending(&location));
+        assert_eq!(ndbam.all_packages().unwrap().count(), 0);
+    }
+}
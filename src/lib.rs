@@ -1,52 +1,164 @@
+pub mod config;
 pub mod contents;
+pub mod dep_spec;
+mod error;
+pub mod fsck;
+pub mod journal;
+mod lock;
 pub mod merger;
+pub mod metadata;
+pub mod migrate;
+pub mod package_id;
+mod path_index;
+pub mod repository;
+mod shared_paths;
+pub mod unmerger;
 mod utils;
+pub mod vdb;
+pub mod version;
+pub mod yields;
 
+use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
 use std::fs::ReadDir;
 use std::io::prelude::*;
 use std::process;
-use std::time::UNIX_EPOCH;
+use std::rc::Rc;
+use std::cmp::Ordering;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{fs, io};
+pub use error::{Error, Result};
 pub use utils::virtual_root::*;
+use config::*;
+use dep_spec::PackageDepSpec;
+use journal::Recovery;
+use lock::RepositoryLock;
+use package_id::PackageId;
 
 pub struct NDBAM<'p> {
     location: &'p Path,
+    config: RepositoryConfig,
+    lock: Rc<RepositoryLock>,
 }
 
 impl<'p> NDBAM<'p> {
-    pub fn new(location: &Path) -> NDBAM {
-        let mut sub = location.to_path_buf();
-        sub.push("ndbam.conf");
-        assert!(sub.is_file(), "Only existing ndbam repositories supported at this moment");
-        // TODO: check ndbam_format == 1
-        // TODO: consider repostiroy_format for specific content
-        // TODO: create new if absent
-        NDBAM { location: location }
-    }
-
-    pub fn versions_of(&self, name: &str) -> Option<impl Iterator<Item=PackageView>> {
-        if let Ok(versions) = self.versions_path(name).read_dir() {
-            Some(PackageVersionsIter { versions })
-        } else {
-            None
+    /// Opens existing repository.
+    ///
+    /// Operation interrupted by other process is left as is. Reading such repository fails with
+    /// [`Error::Interrupted`] until it is either [`recover`](#method.recover)ed or changed (every
+    /// change recovers it first).
+    ///
+    /// Fails if `ndbam.conf` is absent or describes a format we do not support.
+    pub fn new(location: &Path) -> Result<NDBAM<'_>> {
+        let config = RepositoryConfig::load(&location.join("ndbam.conf"))?;
+        let lock = Rc::new(RepositoryLock::open(location)?);
+        Ok(NDBAM { location, config, lock })
+    }
+
+    /// Initializes new repository at `location` with specified `repository_format` (e.g.
+    /// `exndbam-1`).
+    ///
+    /// Fails with [`Error::Layout`] if there is a repository already.
+    pub fn create<'a>(location: &'a Path, format: &str) -> Result<NDBAM<'a>> {
+        let conf = location.join("ndbam.conf");
+        if conf.exists() {
+            return Err(Error::layout(location, "repository already exists"));
         }
+        fs::create_dir_all(location.join("data"))?;
+
+        let config = RepositoryConfig::new(format.into());
+        let mut f = fs::OpenOptions::new().write(true).create_new(true).open(&conf)?;
+        config.write_to(&mut f)?;
+        let lock = Rc::new(RepositoryLock::open(location)?);
+        Ok(NDBAM { location, config, lock })
     }
 
-    pub fn all_packages(&self) -> Option<impl Iterator<Item=PackageView>> {
-        let mut names = self.location.join("data").read_dir().expect("broken layout");
-        AllPackagesIter::next_versions(&mut names).map(|versions| {
-            AllPackagesIter {
-                names: names,
-                versions: versions,
-            }
-        })
+    pub fn config(&self) -> &RepositoryConfig {
+        &self.config
+    }
+
+    /// Whether to block when repository is locked by other process (default) or fail with
+    /// [`Error::Locked`] instead.
+    pub fn wait_for_lock(self, wait: bool) -> Self {
+        self.lock.set_wait(wait);
+        self
+    }
+
+    /// Ensure no one else modifies repository while we are alive.
+    ///
+    /// Normally acquired implicitly by any read operation.
+    pub fn lock_shared(&self) -> Result<()> {
+        self.lock.shared()
+    }
+
+    /// Ensure no one else accesses repository while we are alive.
+    ///
+    /// Normally acquired implicitly by any mutating operation.
+    pub fn lock_exclusive(&self) -> Result<()> {
+        journal::exclusive(&self.lock)
+    }
+
+    /// Rolls operation interrupted by other process back or forward.
+    ///
+    /// Waits for repository to be released by other process (if allowed). Returns `None` if there
+    /// is nothing to recover.
+    pub fn recover(&self) -> Result<Option<Recovery>> {
+        journal::recover(&self.lock)
+    }
+
+    /// Lists all installed versions of package with `name` from oldest to newest.
+    ///
+    /// Returns `None` if there is no such package at all.
+    pub fn versions_of(&self, name: &str) -> Result<Option<impl Iterator<Item=Result<PackageView>>>> {
+        self.lock.shared()?;
+        match self.versions_path(name).read_dir() {
+            Ok(versions) => Ok(Some(sorted_versions(versions, &self.lock, Installs::Authoritative))),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
     }
 
-    pub fn new_package_version(&self, name: &str, version: &str, slot: &str) -> PackageView {
+    /// Newest installed version of package with `name` across all slots.
+    pub fn latest_of(&self, name: &str) -> Result<Option<PackageView>> {
+        match self.versions_of(name)? {
+            Some(versions) => versions.last().transpose(),
+            None => Ok(None),
+        }
+    }
+
+    pub fn all_packages(&self) -> Result<impl Iterator<Item=Result<PackageView>>> {
+        all_packages_at(&self.lock)
+    }
+
+    /// Lists installs that are hidden by newer install of the same `name:version:slot` (e.g.
+    /// left after interrupted re-install).
+    pub fn shadowed_packages(&self) -> Result<impl Iterator<Item=Result<PackageView>>> {
+        AllPackagesIter::new(&self.lock, Installs::Shadowed)
+    }
+
+    /// Lists every installed package that matches `spec`.
+    pub fn matching(&self, spec: &PackageDepSpec) -> Result<Box<dyn Iterator<Item=Result<PackageView>>>> {
+        let candidates: Box<dyn Iterator<Item=Result<PackageView>>> = match spec.exact_name() {
+            Some(name) => match self.versions_of(&name)? {
+                Some(versions) => Box::new(versions),
+                None => Box::new(std::iter::empty()),
+            },
+            None => Box::new(self.all_packages()?),
+        };
+        let spec = spec.clone();
+        Ok(Box::new(candidates.filter(move |pkg| match pkg {
+            Ok(pkg) => spec.matches(pkg.id()),
+            Err(_) => true,  // let caller know about problems
+        })))
+    }
+
+    pub fn new_package_version(&self, name: &str, version: &str, slot: &str) -> Result<PackageView> {
         let location = self.versions_path(name).join(format!("{}:{}:{}", version, slot, magic_cookie()));
-        fs::create_dir_all(&location).unwrap();
-        PackageView { location }
+        journal::creating(&self.lock, &location)?;
+        fs::create_dir_all(&location)?;
+        let pkg = PackageView::new(location, self.lock.clone())?;
+        path_index::PathIndex::new(&self.lock).refresh_package(&pkg)?;
+        Ok(pkg)
     }
 
     fn versions_path(&self, name: &str) -> PathBuf {
@@ -54,98 +166,219 @@ impl<'p> NDBAM<'p> {
     }
 }
 
+type PackageVersionsIter = std::vec::IntoIter<Result<PackageView>>;
+
+/// Which of multiple installs of the same `name:version:slot` to select.
+#[derive(Clone, Copy, PartialEq)]
+enum Installs {
+    /// The one with the newest magic cookie
+    Authoritative,
+    /// Everything except authoritative one
+    Shadowed,
+}
+
+/// Collects package versions with errors placed first and rest ordered by version.
+fn sorted_versions(versions: ReadDir, lock: &Rc<RepositoryLock>, installs: Installs) -> PackageVersionsIter {
+    let mut versions: Vec<_> = versions
+        .map(|version| PackageView::new(version?.path(), lock.clone()))
+        .collect();
+    versions.sort_by(|a, b| match (a, b) {
+        (Ok(a), Ok(b)) => a.cmp_install(b).then_with(|| cmp_cookies(a.cookie(), b.cookie())),
+        (Ok(_), Err(_)) => Ordering::Greater,
+        (Err(_), Ok(_)) => Ordering::Less,
+        (Err(_), Err(_)) => Ordering::Equal,
+    });
+
+    // Newest cookie goes last among the same installs
+    let mut selected = Vec::with_capacity(versions.len());
+    let mut versions = versions.into_iter().peekable();
+    while let Some(version) = versions.next() {
+        let shadowed = match (&version, versions.peek()) {
+            (Ok(version), Some(Ok(next))) => version.cmp_install(next) == Ordering::Equal,
+            _ => false,
+        };
+        let wanted = match installs {
+            Installs::Authoritative => !shadowed,
+            Installs::Shadowed => shadowed,
+        };
+        if wanted || (version.is_err() && installs == Installs::Authoritative) {
+            selected.push(version);
+        }
+    }
+    selected.into_iter()
+}
+
+/// Orders cookies by the time they were generated (absent cookie is the oldest one).
+fn cmp_cookies(a: Option<&str>, b: Option<&str>) -> Ordering {
+    let time = |cookie: Option<&str>| cookie.and_then(magic_cookie_time);
+    time(a).cmp(&time(b)).then_with(|| a.cmp(&b))
+}
+
+/// Authoritative installs of every package in repository guarded by `lock`.
+fn all_packages_at(lock: &Rc<RepositoryLock>) -> Result<AllPackagesIter> {
+    AllPackagesIter::new(lock, Installs::Authoritative)
+}
+
 struct AllPackagesIter {
     names: ReadDir,
-    versions: PackageVersionsIter,
+    versions: Option<PackageVersionsIter>,
+    lock: Rc<RepositoryLock>,
+    installs: Installs,
 }
 
 impl AllPackagesIter {
-    fn next_versions(names: &mut ReadDir) -> Option<PackageVersionsIter> {
-        while let Some(name) = names.next().map(Result::unwrap) {
-            if let Ok(versions) = name.path().read_dir() {
-                return Some(PackageVersionsIter { versions });
-            } else {
-                assert!(!name.file_type().unwrap().is_dir())
+    fn new(lock: &Rc<RepositoryLock>, installs: Installs) -> Result<AllPackagesIter> {
+        lock.shared()?;
+        let data = lock.location().join("data");
+        let names = data.read_dir().map_err(|err| Error::layout(&data, err))?;
+        Ok(AllPackagesIter { names, versions: None, lock: lock.clone(), installs })
+    }
+
+    fn next_versions(&mut self) -> Option<Result<PackageVersionsIter>> {
+        for name in &mut self.names {
+            let name = match name {
+                Ok(name) => name,
+                Err(err) => return Some(Err(err.into())),
+            };
+            match name.file_type() {
+                Ok(file_type) if file_type.is_dir() => {}
+                Ok(_) => continue,  // e.g. .keep
+                Err(err) => return Some(Err(err.into())),
             }
+            return Some(name.path().read_dir()
+                .map(|versions| sorted_versions(versions, &self.lock, self.installs))
+                .map_err(Error::from));
         }
         None
     }
 }
 
 impl Iterator for AllPackagesIter {
-    type Item = PackageView;
+    type Item = Result<PackageView>;
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
-            if let Some(version) = self.versions.next() {
+            if let Some(version) = self.versions.as_mut().and_then(Iterator::next) {
                 return Some(version)
-            } else if let Some(versions) = AllPackagesIter::next_versions(&mut self.names) {
-                self.versions = versions
-            } else {
-                return None
+            }
+            match self.next_versions()? {
+                Ok(versions) => self.versions = Some(versions),
+                Err(err) => return Some(Err(err)),
             }
         }
     }
 }
 
-struct PackageVersionsIter {
-    versions: ReadDir,
-}
-
-impl Iterator for PackageVersionsIter {
-    type Item = PackageView;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        self.versions.next()
-            .map(|version| PackageView { location: version.unwrap().path() })
-    }
-}
-
+#[derive(Clone)]
 pub struct PackageView {
     location: PathBuf,
+    id: PackageId,
+    lock: Rc<RepositoryLock>,
 }
 
 impl PackageView {
+    fn new(location: PathBuf, lock: Rc<RepositoryLock>) -> Result<PackageView> {
+        if !location.is_dir() {
+            return Err(Error::layout(location, "package entry should be a directory"));
+        }
+        let name_entry = location.parent().and_then(Path::file_name).and_then(OsStr::to_str);
+        let version_entry = location.file_name().and_then(OsStr::to_str);
+        let id = match (name_entry, version_entry) {
+            (Some(name_entry), Some(version_entry)) => PackageId::from_entry(name_entry, version_entry),
+            _ => Err("non UTF-8 entry".to_string()),
+        };
+        match id {
+            Ok(id) => Ok(PackageView { location, id, lock }),
+            Err(reason) => Err(Error::layout(location, reason)),
+        }
+    }
+
+    pub fn id(&self) -> &PackageId {
+        &self.id
+    }
+
     pub fn name(&self) -> String {
-        self.location.parent().unwrap().file_name().unwrap().to_str().unwrap().replace("---", "/")
+        self.id.qualified_name()
     }
 
     pub fn version(&self) -> &str {
-        self.location.file_name().unwrap().to_str().unwrap().split(':').next().unwrap()
+        self.id.version.as_str()
     }
 
     pub fn slot(&self) -> Option<&str> {
-        let mut tokens = self.location.file_name().unwrap().to_str().unwrap().split(':');
-        tokens.next().unwrap();
-        tokens.next()
+        self.id.slot.as_deref()
+    }
+
+    /// Magic cookie that distinguishes multiple installs of the same `name:version:slot`.
+    pub fn cookie(&self) -> Option<&str> {
+        self.id.cookie.as_deref()
+    }
+
+    /// Orders by version and slot (ignoring cookie).
+    fn cmp_install(&self, other: &PackageView) -> Ordering {
+        self.id.version.cmp(&other.id.version).then_with(|| self.id.slot.cmp(&other.id.slot))
     }
 
     pub fn full_name(&self) -> String {
         format!("{}-{}", self.name(), self.version())
     }
 
-    pub fn read_key(&self, key: &str) -> io::Result<String> {
-        std::fs::read_to_string(self.location.join(key))
+    pub fn read_key(&self, key: &str) -> Result<String> {
+        Ok(std::fs::read_to_string(self.location.join(key))?)
     }
 
-    pub fn contents(&self) -> impl Iterator<Item=contents::Entry> {
-        let f = std::fs::File::open(self.location.join("contents")).expect("invalid package entry");
-        io::BufReader::new(f)
+    pub fn contents(&self) -> Result<impl Iterator<Item=Result<contents::Entry>>> {
+        let path = self.location.join("contents");
+        let f = std::fs::File::open(&path).map_err(|err| Error::layout(&self.location, err))?;
+        Ok(io::BufReader::new(f)
             .split(b'\n')
-            .map(|row| contents::Entry::parse(&row.unwrap()).unwrap())
+            .enumerate()
+            .map(move |(n, row)| {
+                contents::Entry::parse(&row?).map_err(|reason| Error::Parse {
+                    path: path.clone(),
+                    line: Some(n + 1),
+                    reason,
+                })
+            }))
     }
 
-    pub fn content_writer(&self) -> io::Result<contents::ContentsWriter> {
-        contents::create(self.location.join("contents"))
+    pub fn content_writer(&self) -> Result<contents::ContentsWriter> {
+        journal::exclusive(&self.lock)?;
+        Ok(contents::create(self.location.join("contents"))?)
     }
 }
 
 /// Generates pseudo-unique string suitable for using in filenames.
 pub fn magic_cookie() -> String {
-    let epoch = UNIX_EPOCH.elapsed().unwrap();
+    let epoch = UNIX_EPOCH.elapsed().unwrap_or_default();
 
     format!("C.{}.{}.{}.C",
             process::id(),
             epoch.as_secs(),
             epoch.subsec_micros())
 }
+
+/// Extracts moment when cookie were generated by [`magic_cookie`].
+///
+/// # Examples
+///
+/// ```
+/// # use std::time::{Duration, UNIX_EPOCH};
+/// # use ndbam::magic_cookie_time;
+/// assert_eq!(magic_cookie_time("C.42.1558050745.500.C"),
+///            Some(UNIX_EPOCH + Duration::from_secs(1558050745) + Duration::from_micros(500)));
+/// assert_eq!(magic_cookie_time("whatever"), None);
+/// ```
+pub fn magic_cookie_time(cookie: &str) -> Option<SystemTime> {
+    let mut tokens = cookie.split('.');
+    if tokens.next() != Some("C") {
+        return None;
+    }
+    let _pid = tokens.next()?.parse::<u32>().ok()?;
+    let secs = tokens.next()?.parse::<u64>().ok()?;
+    let micros = tokens.next()?.parse::<u64>().ok()?;
+    if tokens.next() != Some("C") || tokens.next().is_some() {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_micros(micros))
+}
@@ -0,0 +1,142 @@
+use std::cell::Cell;
+use std::fs::{File, TryLockError};
+use std::path::{Path, PathBuf};
+
+use crate::error::*;
+use crate::journal;
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum LockMode {
+    Unlocked,
+    Shared,
+    Exclusive,
+}
+
+/// Advisory lock over repository directory.
+///
+/// Lock is acquired lazily on first access and held until every user of it is gone. Once
+/// exclusive lock is taken it is never downgraded.
+///
+/// Shared lock is refused with [`Error::Interrupted`] while journal of interrupted operation is
+/// pending since only writers recover it (see [`journal::exclusive`](crate::journal::exclusive)).
+pub(crate) struct RepositoryLock {
+    location: PathBuf,
+    dir: File,
+    mode: Cell<LockMode>,
+    wait: Cell<bool>,
+    /// Whether journal of interrupted operation was dealt with under exclusive lock.
+    recovered: Cell<bool>,
+}
+
+impl RepositoryLock {
+    pub fn open(location: &Path) -> Result<RepositoryLock> {
+        Ok(RepositoryLock {
+            location: location.to_path_buf(),
+            dir: File::open(location)?,
+            mode: Cell::new(LockMode::Unlocked),
+            wait: Cell::new(true),
+            recovered: Cell::new(false),
+        })
+    }
+
+    /// Location of repository this lock belongs to.
+    pub fn location(&self) -> &Path {
+        &self.location
+    }
+
+    /// Whether to block until lock is released by other process or fail with [`Error::Locked`].
+    pub fn set_wait(&self, wait: bool) {
+        self.wait.set(wait)
+    }
+
+    pub fn shared(&self) -> Result<()> {
+        if self.mode.get() >= LockMode::Shared {
+            return Ok(());
+        }
+        if self.wait.get() {
+            self.dir.lock_shared()?;
+        } else {
+            self.dir.try_lock_shared().map_err(|err| self.try_lock_error(err))?;
+        }
+        if journal::pending(&self.location) {
+            self.dir.unlock()?;
+            return Err(Error::Interrupted { path: self.location.clone() });
+        }
+        self.mode.set(LockMode::Shared);
+        Ok(())
+    }
+
+    pub fn exclusive(&self) -> Result<()> {
+        if self.mode.get() >= LockMode::Exclusive {
+            return Ok(());
+        }
+        // Note that upgrade of shared lock is not atomic and failed attempt may release it
+        let locked = if self.wait.get() {
+            self.dir.lock().map_err(Error::from)
+        } else {
+            self.dir.try_lock().map_err(|err| self.try_lock_error(err))
+        };
+        self.mode.set(if locked.is_ok() { LockMode::Exclusive } else { LockMode::Unlocked });
+        locked
+    }
+
+    pub fn is_exclusive(&self) -> bool {
+        self.mode.get() == LockMode::Exclusive
+    }
+
+    /// Whether it is safe to change repository (see [`set_recovered`](#method.set_recovered)).
+    pub fn is_recovered(&self) -> bool {
+        self.recovered.get() && self.is_exclusive()
+    }
+
+    /// Marks journal of interrupted operation as dealt with (or not, e.g. if recovery failed).
+    pub fn set_recovered(&self, recovered: bool) {
+        self.recovered.set(recovered)
+    }
+
+    fn try_lock_error(&self, err: TryLockError) -> Error {
+        match err {
+            TryLockError::WouldBlock => Error::Locked { path: self.location.clone() },
+            TryLockError::Error(err) => err.into(),
+        }
+    }
+}
+
+impl Drop for RepositoryLock {
+    fn drop(&mut self) {
+        if self.is_recovered() {
+            // Package created by us is complete as far as we know
+            let _ = journal::forget_created(&self.location);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use totems::*;
+
+    #[test]
+    fn shared_with_shared() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (RepositoryLock::open(dir.path()).unwrap(), RepositoryLock::open(dir.path()).unwrap());
+        b.set_wait(false);
+        assert_ok!(a.shared());
+        assert_ok!(b.shared());
+        assert_err!(b.exclusive());
+    }
+
+    #[test]
+    fn exclusive_with_shared() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (RepositoryLock::open(dir.path()).unwrap(), RepositoryLock::open(dir.path()).unwrap());
+        b.set_wait(false);
+        assert_ok!(a.exclusive());
+        assert_err!(b.shared());
+        assert_err!(b.exclusive());
+
+        drop(a);
+        assert_ok!(b.exclusive());
+        assert_ok!(b.shared());
+    }
+}
@@ -1,51 +1,273 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fmt;
 use std::fs::*;
 use std::io;
-use std::path::Path;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use walkdir::WalkDir;
 
 use super::PackageView;
+use crate::config::ConfigProtect;
 use crate::contents::*;
+use crate::error::*;
+use crate::journal::Journal;
+use crate::package_id::PackageId;
+use crate::path_index::{canonical, PathIndex};
+use crate::unmerger::Removal;
+use crate::yields::yielding;
+use crate::utils::copy::*;
 use crate::utils::virtual_root::*;
 
+/// What to do with file or symlink that already exists where merge wants to put its own.
+///
+/// Existing directories are merged into as usual unless they differ in permissions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CollisionPolicy {
+    /// Only install packages with no conflicting files.
+    NoConflicts,
+    /// Keep existing files and symlinks identical to merged ones shared between owners.
+    AllowIdentical,
+    /// Same as `AllowIdentical` but install different ones under side name (e.g.
+    /// `._cfg0000_hosts`) for other tools to merge them (see [`yields`](crate::yields)).
+    Yield,
+    /// Simply overwrite whatever exists (including permissions of directories).
+    Clobber,
+}
+
+impl CollisionPolicy {
+    pub fn variants() -> [&'static str; 4] {
+        ["no-conflicts", "allow-identical", "yield", "clobber"]
+    }
+}
+
+impl FromStr for CollisionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<CollisionPolicy, String> {
+        match s {
+            "no-conflicts" => Ok(CollisionPolicy::NoConflicts),
+            "allow-identical" => Ok(CollisionPolicy::AllowIdentical),
+            "yield" => Ok(CollisionPolicy::Yield),
+            "clobber" => Ok(CollisionPolicy::Clobber),
+            _ => Err(format!("unknown collision policy {:?}", s)),
+        }
+    }
+}
+
+/// Path that existed before merge and was resolved according to [`CollisionPolicy`].
+#[derive(Debug, PartialEq)]
+pub struct Collision {
+    pub path: PathBuf,
+    /// Packages that owned path before merge.
+    pub owners: Vec<PackageId>,
+    pub resolution: Resolution,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Resolution {
+    /// Existing path was identical and now belongs to merged package as well.
+    Shared,
+    /// Merged file was installed under the other (real) path instead.
+    Yielded(PathBuf),
+    /// Existing path was overwritten.
+    Clobbered,
+}
+
+impl fmt::Display for Collision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} ({}) ", self.path, describe_owners(&self.owners))?;
+        match &self.resolution {
+            Resolution::Shared => f.write_str("is identical and shared"),
+            Resolution::Yielded(side) => write!(f, "yielded to {:?}", side),
+            Resolution::Clobbered => f.write_str("clobbered"),
+        }
+    }
+}
+
+/// File or symlink moved from image into root.
+#[derive(Debug, PartialEq)]
+pub struct Move {
+    /// Real path in image.
+    pub from: PathBuf,
+    /// Real path in root (side path for yielded ones).
+    pub to: PathBuf,
+    /// Whether something existed at `to` and was overwritten.
+    pub replacing: bool,
+}
+
+impl fmt::Display for Move {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.replacing {
+            write!(f, "replacing {:?} with {:?}", self.to, self.from)
+        } else {
+            write!(f, "moving {:?} to {:?}", self.from, self.to)
+        }
+    }
+}
+
+/// Outcome of successful merge.
+#[derive(Debug, Default)]
+pub struct Merged {
+    /// Everything moved into root in the order it was merged.
+    pub moves: Vec<Move>,
+    /// Paths that existed already and how they were resolved.
+    pub collisions: Vec<Collision>,
+    /// What happened to contents of replaced packages.
+    pub removals: Vec<Removal>,
+}
+
 impl PackageView {
-    pub fn merge(&self, image: &RootPath, root: &RootPath) -> io::Result<()> {
-        let mut content = self.content_writer()?;
+    /// Moves everything from `image` into `root` recording it as contents of this package.
+    ///
+    /// Whole operation is journaled. If it fails (or gets interrupted) everything moved so far is
+    /// put back into `image`.
+    ///
+    /// Files hardlinked within `image` stay hardlinked in `root` (see
+    /// [`HARDLINK_KEY`](crate::contents::HARDLINK_KEY)).
+    pub fn merge(&self, image: &dyn RootPath, root: &dyn RootPath) -> Result<()> {
+        self.merge_replacing(image, root, &[], CollisionPolicy::NoConflicts, &ConfigProtect::default()).map(|_| ())
+    }
+
+    /// Same as [`merge`](#method.merge) but resolves collisions according to `policy` and also
+    /// retires any number of `replaced` packages (e.g. older version in the same slot or every
+    /// package this one was split from).
+    ///
+    /// Files and symlinks of replaced packages are overwritten regardless of `policy` and then
+    /// belong to this package. Once merge is committed, replaced packages are unmerged with
+    /// [`UnmergePolicy::Safe`](crate::unmerger::UnmergePolicy::Safe) which removes only what
+    /// nobody ships anymore.
+    ///
+    /// The only exception are files under `protect`ed paths. Those that were modified since they
+    /// were recorded by their owners (or were never recorded) are never overwritten and merged
+    /// file is yielded instead.
+    pub fn merge_replacing(&self, image: &dyn RootPath, root: &dyn RootPath, replaced: &[PackageView], policy: CollisionPolicy, protect: &ConfigProtect) -> Result<Merged> {
+        let mut unique: Vec<PackageView> = Vec::with_capacity(replaced.len());
+        for old in replaced {
+            if old.location != self.location && !unique.iter().any(|other| other.location == old.location) {
+                unique.push(old.clone());
+            }
+        }
+        let replaced = &unique;
+
+        let journal = Journal::begin(&self.lock, self, root.real_root(), replaced)?;
+        let mut merge = Merge {
+            pkg: self, journal, image, root, policy, protect,
+            replaceable: HashSet::new(),
+            index: PathIndex::new(&self.lock),
+            links: HashMap::new(),
+            moves: Vec::new(),
+            collisions: Vec::new(),
+        };
+        // From now on failure rolls back package entry as well
+        if let Err(err) = merge.prepare(replaced).and_then(|()| merge.run()) {
+            // Failed roll back leaves journal for recovery
+            let _ = merge.journal.roll_back();
+            return Err(err);
+        }
+        let removals = merge.journal.complete()?;
+        Ok(Merged { moves: merge.moves, collisions: merge.collisions, removals })
+    }
+}
+
+/// State of single merge.
+struct Merge<'a> {
+    pkg: &'a PackageView,
+    journal: Journal<'a>,
+    image: &'a dyn RootPath,
+    root: &'a dyn RootPath,
+    /// Paths of packages being replaced.
+    replaceable: HashSet<PathBuf>,
+    policy: CollisionPolicy,
+    protect: &'a ConfigProtect,
+    index: PathIndex<'a>,
+    /// Hardlink groups seen so far by device and inode of their image files.
+    links: HashMap<Inode, Link>,
+    moves: Vec<Move>,
+    collisions: Vec<Collision>,
+}
+
+type Inode = (u64, u64);
+
+/// Files hardlinked to each other.
+struct Link {
+    /// Entry of the first member (its path names the whole group).
+    entry: Entry,
+    /// Real path where content of the group was merged (if it was).
+    merged: Option<PathBuf>,
+}
+
+impl<'a> Merge<'a> {
+    fn prepare(&mut self, replaced: &[PackageView]) -> Result<()> {
+        self.index.refresh()?;
+        for old in replaced {
+            for entry in old.contents()? {
+                if let Entry::File { path, .. } | Entry::Sym { path, .. } = entry? {
+                    self.replaceable.insert(canonical(&path));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<()> {
+        let (image, root) = (self.image, self.root);
+        let mut content = self.pkg.content_writer()?;
         let mut walker = WalkDir::new(image.real_root()).into_iter();
         while let Some(node) = walker.next() {
-            let node = node?;
+            let node = node.map_err(io::Error::from)?;
             if node.path() == image.real_root() {
                 continue; // skip root dir
             }
 
-            let entry = Entry::from_path(node.path(), image)?;
-            let merged_path = root.real_path(entry.path()).unwrap();
-            content.write_entry(&entry)?;
+            let (entry, inode) = self.entry_of(node.path(), image)?;
+            let merged_path = root.real_path(entry.path())?;
             match entry {
                 Entry::Dir { .. } => {
+                    content.write_entry(&entry)?;
                     if let Ok(metadata) = merged_path.symlink_metadata() {
-                        assert!(metadata.is_dir(), "TODO: report conflicting types");
-                        assert!(
-                            metadata.permissions() == node.path().metadata()?.permissions(),
-                            "TODO: resolve permissions conflict"
-                        )
+                        if !metadata.is_dir() {
+                            return Err(self.collision(entry.path(), "not a directory")?);
+                        }
+                        let permissions = node.path().metadata()?.permissions();
+                        if metadata.permissions() != permissions {
+                            if self.policy != CollisionPolicy::Clobber {
+                                return Err(self.collision(entry.path(), "permissions differ")?);
+                            }
+                            self.journal.changing_mode(&merged_path, metadata.permissions().mode())?;
+                            set_permissions(&merged_path, permissions)?;
+                            self.resolved(entry.path(), Resolution::Clobbered)?;
+                        }
                     } else {
-                        // TODO: use ErrorKind to fail fast if not cross-filesystem case
-                        if rename(node.path(), &merged_path).is_ok() {
+                        self.journal.moving(node.path(), &merged_path)?;
+                        let moved = match rename(node.path(), &merged_path) {
+                            Ok(()) => true,
+                            // Walk in and move its content one by one
+                            Err(ref err) if is_cross_device(err) => false,
+                            Err(err) => return Err(err.into()),
+                        };
+                        if moved {
                             // Record moved folder recursively
                             for subnode in WalkDir::new(&merged_path) {
-                                let subnode = subnode?;
-                                if node.path() == entry.path() {
+                                let subnode = subnode.map_err(io::Error::from)?;
+                                if subnode.path() == merged_path {
                                     continue; // skip dir we just moved
                                 }
-                                content.write_entry(&Entry::from_path(subnode.path(), root)?)?;
+                                let (entry, inode) = self.entry_of(subnode.path(), root)?;
+                                if let Some(link) = inode.and_then(|inode| self.links.get_mut(&inode)) {
+                                    link.merged.get_or_insert_with(|| subnode.path().to_owned());
+                                }
+                                content.write_entry(&self.linked(&entry, inode))?;
                             }
 
                             // No need to dive in
                             walker.skip_current_dir();
                         } else {
+                            self.journal.creating_dir(&merged_path)?;
                             create_dir(&merged_path)?;
-                            // TODO: ensure permissions include owner, caps, etc
-                            set_permissions(&merged_path, node.path().metadata()?.permissions())?;
+                            copy_dir_attributes(node.path(), &merged_path)?;
                         }
                     }
                 }
@@ -56,7 +278,10 @@ impl PackageView {
                             target.to_owned()
                         } else {
                             // TODO: ran-away link check
-                            path.parent().expect("Root cannot be symlink").join(target)
+                            match path.parent() {
+                                Some(parent) => parent.join(target),
+                                None => return Err(Error::symlink(path, target, "root cannot be symlink")),
+                            }
                         };
 
                         // XXX: This check is ineffective since we might have symlinks that leads
@@ -67,34 +292,223 @@ impl PackageView {
                             // plan to install. Now just ensure it will not be deleted during
                             // further merge. I.e. ensure that we are not pointing into image
                             // itself.
-                            let merged_target = root.real_path(&target).unwrap();
-                            image.inner_path(&merged_target)
-                                .expect_err("Symlink target should not point back into image");
+                            let merged_target = root.real_path(&target)?;
+                            if image.inner_path(&merged_target).is_ok() {
+                                return Err(Error::symlink(path, &target, "points back into image"));
+                            }
                         } else {
                             // Probably we didn't installed path that symlink is pointing to. Let's
                             // check if it exists in the image itself.
-                            image
-                                .canonicalize_to_real(&target)
-                                .expect("Symlink pointing to some existing object");
+                            if let Err(err) = image.canonicalize_to_real(&target) {
+                                return Err(Error::symlink(path, &target, err));
+                            }
+                        }
+                    }
+                    let metadata = match merged_path.symlink_metadata() {
+                        Ok(metadata) => metadata,
+                        Err(_) => {
+                            self.journal.moving(node.path(), &merged_path)?;
+                            self.place(node.path(), &merged_path, inode)?;
+                            self.moved(node.path(), &merged_path, false);
+                            content.write_entry(&self.linked(&entry, inode))?;
+                            continue;
+                        }
+                    };
+                    if metadata.is_dir() {
+                        return Err(self.collision(entry.path(), "already exists as directory")?);
+                    }
+                    let replacing = self.replaceable.contains(&canonical(entry.path()));
+                    let identical = match &entry {
+                        Entry::File { md5, .. } => metadata.is_file() && file_hash(Algorithm::MD5, &merged_path)? == *md5,
+                        Entry::Sym { target, .. } => metadata.file_type().is_symlink() && merged_path.read_link()? == *target,
+                        Entry::Dir { .. } => unreachable!(),
+                    };
+                    let overwrite = replacing || self.policy == CollisionPolicy::Clobber;
+                    let protected = overwrite && !identical && self.protected(&entry, &merged_path)?;
+                    match self.policy {
+                        _ if overwrite && !protected => {
+                            let backup = backup_path(&merged_path);
+                            if backup.symlink_metadata().is_ok() {
+                                remove_file(&backup)?; // stale one
+                            }
+                            self.journal.replacing(node.path(), &merged_path, &backup)?;
+                            hard_link(&merged_path, &backup)?;
+                            self.place(node.path(), &merged_path, inode)?;
+                            self.moved(node.path(), &merged_path, true);
+                            content.write_entry(&self.linked(&entry, inode))?;
+                            if !replacing {
+                                self.resolved(entry.path(), Resolution::Clobbered)?;
+                            }
+                        }
+                        CollisionPolicy::AllowIdentical | CollisionPolicy::Yield if identical => {
+                            // Take what is installed already (e.g. with its modification time)
+                            content.write_entry(&Entry::from_path(&merged_path, root)?)?;
+                            self.resolved(entry.path(), Resolution::Shared)?;
+                        }
+                        _ if protected || self.policy == CollisionPolicy::Yield => {
+                            let side = side_path(&merged_path);
+                            self.journal.moving(node.path(), &side)?;
+                            self.place(node.path(), &side, inode)?;
+                            self.moved(node.path(), &side, false);
+                            let side_entry = yielding(Entry::from_path(&side, root)?, entry.path())?;
+                            content.write_entry(&self.linked(&side_entry, inode))?;
+                            self.resolved(entry.path(), Resolution::Yielded(side))?;
                         }
+                        _ => return Err(self.collision(entry.path(), "already exists")?),
                     }
-                    println!("moving {:?} to {:?}", node.path(), merged_path);
-                    assert!(
-                        !merged_path.exists(),
-                        "TODO: handle file/symlink collisions"
-                    );
-                    rename(node.path(), &merged_path)?; // TODO: handle cross-fileystem via copy
                 }
             }
         }
         content.commit()?;
+        self.journal.commit()
+    }
+
+    /// Entry of file or symlink at `real_path` (under `root`) and inode of its hardlink group if
+    /// it has other links.
+    fn entry_of(&mut self, real_path: &Path, root: &dyn RootPath) -> Result<(Entry, Option<Inode>)> {
+        let metadata = real_path.symlink_metadata()?;
+        let inode = (metadata.dev(), metadata.ino());
+        // Last member left in image after others were copied away has no other links anymore
+        if !metadata.is_file() || (metadata.nlink() < 2 && !self.links.contains_key(&inode)) {
+            return Ok((Entry::from_path(real_path, root)?, None));
+        }
+        let entry = match self.links.get(&inode) {
+            // The same content, no need to hash it again
+            Some(link) => relocated(&link.entry, root.inner_path(real_path)?.into_owned()),
+            None => {
+                let entry = Entry::from_path(real_path, root)?;
+                self.links.insert(inode, Link { entry: entry.clone(), merged: None });
+                entry
+            }
+        };
+        Ok((entry, Some(inode)))
+    }
+
+    /// Moves image file `from` to `to` unless other member of its hardlink group is merged
+    /// already. Then `to` becomes hardlink of that one instead (even across file-systems).
+    fn place(&mut self, from: &Path, to: &Path, inode: Option<Inode>) -> io::Result<()> {
+        let link = match inode.and_then(|inode| self.links.get_mut(&inode)) {
+            Some(link) => link,
+            None => return move_node(from, to),
+        };
+        match &link.merged {
+            Some(merged) => {
+                link_node(merged, to)?;
+                remove_file(from)
+            }
+            None => {
+                move_node(from, to)?;
+                link.merged = Some(to.to_owned());
+                Ok(())
+            }
+        }
+    }
+
+    /// Marks `entry` as a member of hardlink group (if any).
+    fn linked<'e>(&self, entry: &'e Entry, inode: Option<Inode>) -> Cow<'e, Entry> {
+        // Non UTF-8 name cannot be recorded and group is just not checked then
+        let group = match inode.and_then(|inode| self.links.get(&inode)).and_then(|link| link.entry.path().to_str()) {
+            Some(group) => group,
+            None => return Cow::Borrowed(entry),
+        };
+        let mut entry = entry.clone();
+        if let Entry::File { extra, .. } = &mut entry {
+            extra.insert(HARDLINK_KEY.to_string(), group.to_string());
+        }
+        Cow::Owned(entry)
+    }
+
+    /// Packages (other than one being merged) that own `path`.
+    fn owners(&self, path: &Path) -> Result<Vec<PackageId>> {
+        Ok(self.index.lookup(path)?.into_iter()
+            .filter(|owner| owner.location != self.pkg.location)
+            .map(|owner| owner.id)
+            .collect())
+    }
+
+    /// Whether `entry` should not overwrite file at `real_path` because of config protection.
+    fn protected(&self, entry: &Entry, real_path: &Path) -> Result<bool> {
+        let path = match entry {
+            Entry::File { path, .. } if self.protect.is_protected(path) => path,
+            _ => return Ok(false),
+        };
+        if !real_path.symlink_metadata()?.is_file() {
+            return Ok(true);
+        }
+        let md5 = file_hash(Algorithm::MD5, real_path)?;
+        let path = canonical(path);
+        for owner in self.index.lookup(&path)? {
+            if owner.location == self.pkg.location {
+                continue;
+            }
+            for recorded in owner.contents()? {
+                if let Entry::File { path: recorded, md5: recorded_md5, .. } = recorded? {
+                    if canonical(&recorded) == path && recorded_md5 == md5 {
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    fn collision(&self, path: &Path, reason: &str) -> Result<Error> {
+        let owners = self.owners(path)?;
+        Ok(Error::collision(path, format!("{} ({})", reason, describe_owners(&owners))))
+    }
+
+    fn moved(&mut self, from: &Path, to: &Path, replacing: bool) {
+        self.moves.push(Move { from: from.to_owned(), to: to.to_owned(), replacing });
+    }
+
+    fn resolved(&mut self, path: &Path, resolution: Resolution) -> Result<()> {
+        let owners = self.owners(path)?;
+        self.collisions.push(Collision { path: path.to_owned(), owners, resolution });
         Ok(())
     }
 }
 
+fn describe_owners(owners: &[PackageId]) -> String {
+    if owners.is_empty() {
+        return "not owned by any package".to_string();
+    }
+    let owners: Vec<_> = owners.iter().map(ToString::to_string).collect();
+    format!("owned by {}", owners.join(", "))
+}
+
+/// Free name next to `real_path` for yielded file, e.g. `._cfg0000_hosts` for `hosts`.
+fn side_path(real_path: &Path) -> PathBuf {
+    for n in 0.. {
+        let mut name = OsString::from(format!("._cfg{:04}_", n));
+        name.push(real_path.file_name().unwrap_or_default());
+        let side = real_path.with_file_name(name);
+        if side.symlink_metadata().is_err() {
+            return side;
+        }
+    }
+    unreachable!("ran out of side names for {:?}", real_path)
+}
+
+/// The same `entry` recorded under other `path`.
+fn relocated(entry: &Entry, path: PathBuf) -> Entry {
+    match entry.clone() {
+        Entry::Dir { .. } => Entry::Dir { path },
+        Entry::File { md5, mtime, extra, .. } => Entry::File { path, md5, mtime, extra },
+        Entry::Sym { target, mtime, extra, .. } => Entry::Sym { path, target, mtime, extra },
+    }
+}
+
+/// Where old version of file replaced by merge is kept until commit.
+fn backup_path(real_path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(real_path.file_name().unwrap_or_default());
+    name.push(".ndbam-replaced");
+    real_path.with_file_name(name)
+}
+
 impl Entry {
-    pub fn from_path(real_path: &Path, root: &RootPath) -> io::Result<Entry> {
-        let path = root.inner_path(real_path).unwrap().into_owned();
+    pub fn from_path(real_path: &Path, root: &dyn RootPath) -> Result<Entry> {
+        let path = root.inner_path(real_path)?.into_owned();
 
         let metadata = real_path.symlink_metadata()?;
         if metadata.is_dir() {
@@ -104,15 +518,315 @@ impl Entry {
                 path,
                 md5: file_hash(Algorithm::MD5, real_path)?,
                 mtime: metadata.modified()?,
-                extra: Default::default(),
+                extra: attributes_of(real_path, &metadata)?,
             })
         } else {
             Ok(Entry::Sym {
                 path,
                 target: real_path.read_link()?,
                 mtime: metadata.modified()?,
-                extra: Default::default(),
+                extra: attributes_of(real_path, &metadata)?,
             })
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NDBAM;
+
+    fn image_with(dir: &Path, name: &str, files: &[(&str, &str)]) -> impl RootPath {
+        let image = dir.join(name);
+        for (path, data) in files {
+            let path = image.join(path);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            write(path, data).unwrap();
+        }
+        root_at_buf(image)
+    }
+
+    #[test]
+    fn replace_older_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("db");
+        let ndbam = NDBAM::create(&location, "exndbam-1").unwrap();
+        create_dir(dir.path().join("root")).unwrap();
+        let root = root_at_buf(dir.path().join("root"));
+
+        let old = ndbam.new_package_version("app-misc/foo", "1", "0").unwrap();
+        let image = image_with(dir.path(), "image1", &[("usr/bin/foo", "v1"), ("usr/share/foo/old.txt", ""), ("etc/foo.conf", "")]);
+        old.merge(&image, &root).unwrap();
+        let other_slot = ndbam.new_package_version("app-misc/foo", "1", "1").unwrap();
+        other_slot.merge(&image_with(dir.path(), "image2", &[("opt/foo-1/foo", "")]), &root).unwrap();
+
+        let new = ndbam.new_package_version("app-misc/foo", "2", "0").unwrap();
+        let image = image_with(dir.path(), "image3", &[("usr/bin/foo", "v2"), ("usr/share/foo/new.txt", "")]);
+        let merged = new.merge_replacing(&image, &root, &[old], CollisionPolicy::NoConflicts, &ConfigProtect::default()).unwrap();
+        let mut moves: Vec<_> = merged.moves.iter().map(|moved| (moved.to.strip_prefix(dir.path()).unwrap(), moved.replacing)).collect();
+        moves.sort();
+        assert_eq!(moves, vec![(Path::new("root/usr/bin/foo"), true), (Path::new("root/usr/share/foo/new.txt"), false)]);
+        assert!(merged.removals.iter().all(|removal| match removal {
+            Removal::Keep(_, reason) => !reason.to_string().starts_with("modified"),
+            Removal::Remove(_) => true,
+        }));
+
+        assert_eq!(read_to_string(dir.path().join("root/usr/bin/foo")).unwrap(), "v2");
+        assert!(dir.path().join("root/usr/share/foo/new.txt").exists());
+        assert!(!dir.path().join("root/usr/share/foo/old.txt").exists());
+        assert!(!dir.path().join("root/etc").exists());
+        assert!(!dir.path().join("root/usr/bin/.foo.ndbam-replaced").exists());
+        let versions: Vec<_> = ndbam.versions_of("app-misc/foo").unwrap().unwrap().map(|pkg| pkg.unwrap().id().to_string()).collect();
+        assert_eq!(versions, vec!["app-misc/foo-1:1", "app-misc/foo-2:0"]);
+        let owners: Vec<_> = ndbam.owners_of(Path::new("/usr/bin/foo")).unwrap().iter().map(|pkg| pkg.id().to_string()).collect();
+        assert_eq!(owners, vec!["app-misc/foo-2:0"]);
+        assert_eq!(ndbam.fsck().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn replace_many_packages() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("db");
+        let ndbam = NDBAM::create(&location, "exndbam-1").unwrap();
+        create_dir(dir.path().join("root")).unwrap();
+        let root = root_at_buf(dir.path().join("root"));
+
+        let a = ndbam.new_package_version("app-misc/a", "1", "0").unwrap();
+        a.merge(&image_with(dir.path(), "image4", &[("usr/bin/a", "a"), ("usr/lib/liba", "")]), &root).unwrap();
+        let b = ndbam.new_package_version("app-misc/b", "1", "0").unwrap();
+        b.merge(&image_with(dir.path(), "image5", &[("usr/bin/b", "b"), ("usr/lib/libb", "")]), &root).unwrap();
+
+        let ab = ndbam.new_package_version("app-misc/ab", "1", "0").unwrap();
+        let image = image_with(dir.path(), "image6", &[("usr/bin/a", "ab"), ("usr/bin/b", "ab")]);
+        ab.merge_replacing(&image, &root, &[a.clone(), b, a], CollisionPolicy::NoConflicts, &ConfigProtect::default()).unwrap();
+
+        assert_eq!(read_to_string(dir.path().join("root/usr/bin/a")).unwrap(), "ab");
+        assert_eq!(read_to_string(dir.path().join("root/usr/bin/b")).unwrap(), "ab");
+        assert!(!dir.path().join("root/usr/lib").exists());
+        let names: Vec<_> = ndbam.all_packages().unwrap().map(|pkg| pkg.unwrap().name()).collect();
+        assert_eq!(names, vec!["app-misc/ab"]);
+        let owners: Vec<_> = ndbam.owners_of(Path::new("/usr/bin/b")).unwrap().iter().map(PackageView::name).collect();
+        assert_eq!(owners, vec!["app-misc/ab"]);
+        assert_eq!(ndbam.fsck().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn broken_replaced_package() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("db");
+        let ndbam = NDBAM::create(&location, "exndbam-1").unwrap();
+        create_dir(dir.path().join("root")).unwrap();
+        let root = root_at_buf(dir.path().join("root"));
+        let old = ndbam.new_package_version("app-misc/foo", "1", "0").unwrap();
+        old.merge(&image_with(dir.path(), "image1", &[("usr/bin/foo", "v1")]), &root).unwrap();
+        write(old.location.join("contents"), "garbage\n").unwrap();
+
+        let new = ndbam.new_package_version("app-misc/foo", "2", "0").unwrap();
+        let image = image_with(dir.path(), "image2", &[("usr/bin/foo", "v2")]);
+        let err = new.merge_replacing(&image, &root, std::slice::from_ref(&old), CollisionPolicy::NoConflicts, &ConfigProtect::default()).unwrap_err();
+        assert!(matches!(err, Error::Parse { .. }), "{}", err);
+        assert!(!new.location.exists());
+        assert!(old.location.exists());
+        assert_eq!(read_to_string(dir.path().join("image2/usr/bin/foo")).unwrap(), "v2");
+    }
+
+    #[test]
+    fn collision_policies() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("db");
+        let ndbam = NDBAM::create(&location, "exndbam-1").unwrap();
+        create_dir(dir.path().join("root")).unwrap();
+        let root = root_at_buf(dir.path().join("root"));
+        let owner = ndbam.new_package_version("app-misc/owner", "1", "0").unwrap();
+        owner.merge(&image_with(dir.path(), "image0", &[("etc/same.conf", "same"), ("etc/other.conf", "old")]), &root).unwrap();
+        write(dir.path().join("root/etc/unowned.conf"), "old").unwrap();
+        let files = [("etc/same.conf", "same"), ("etc/other.conf", "new"), ("etc/unowned.conf", "new")];
+
+        let pkg = || ndbam.new_package_version("app-misc/a", "1", "0").unwrap();
+        let err = pkg().merge(&image_with(dir.path(), "image1", &files), &root).unwrap_err().to_string();
+        assert!(err.contains("owned by app-misc/owner-1:0") || err.contains("not owned by any package"), "{}", err);
+        let err = pkg().merge_replacing(&image_with(dir.path(), "image2", &files[..2]), &root, &[], CollisionPolicy::AllowIdentical, &ConfigProtect::default()).unwrap_err();
+        assert_eq!(err.to_string(), format!("Collision at {:?}: already exists (owned by app-misc/owner-1:0)", Path::new("/etc/other.conf")));
+        assert!(ndbam.all_packages().unwrap().all(|pkg| pkg.unwrap().name() == "app-misc/owner"));
+
+        let mut merged = pkg().merge_replacing(&image_with(dir.path(), "image3", &files), &root, &[], CollisionPolicy::Yield, &ConfigProtect::default()).unwrap();
+        merged.collisions.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(merged.collisions, vec![
+            Collision { path: PathBuf::from("/etc/other.conf"), owners: vec![owner.id().clone()], resolution: Resolution::Yielded(dir.path().join("root/etc/._cfg0000_other.conf")) },
+            Collision { path: PathBuf::from("/etc/same.conf"), owners: vec![owner.id().clone()], resolution: Resolution::Shared },
+            Collision { path: PathBuf::from("/etc/unowned.conf"), owners: vec![], resolution: Resolution::Yielded(dir.path().join("root/etc/._cfg0000_unowned.conf")) },
+        ]);
+        assert_eq!(read_to_string(dir.path().join("root/etc/other.conf")).unwrap(), "old");
+        assert_eq!(read_to_string(dir.path().join("root/etc/._cfg0000_other.conf")).unwrap(), "new");
+        let owners: Vec<_> = ndbam.owners_of(Path::new("/etc/same.conf")).unwrap().iter().map(PackageView::name).collect();
+        assert_eq!(owners.len(), 2);
+        assert_eq!(ndbam.fsck().unwrap(), vec![]);
+
+        let pkg = ndbam.new_package_version("app-misc/b", "1", "0").unwrap();
+        let merged = pkg.merge_replacing(&image_with(dir.path(), "image4", &files), &root, &[], CollisionPolicy::Clobber, &ConfigProtect::default()).unwrap();
+        assert!(merged.collisions.iter().all(|collision| collision.resolution == Resolution::Clobbered));
+        assert_eq!(merged.collisions.len(), 3);
+        assert_eq!(read_to_string(dir.path().join("root/etc/other.conf")).unwrap(), "new");
+        assert!(!dir.path().join("root/etc/.other.conf.ndbam-replaced").exists());
+    }
+
+    #[test]
+    fn clobber_directory_permissions() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("db");
+        let ndbam = NDBAM::create(&location, "exndbam-1").unwrap();
+        create_dir_all(dir.path().join("root/var/tmp")).unwrap();
+        set_permissions(dir.path().join("root/var/tmp"), Permissions::from_mode(0o755)).unwrap();
+        let root = root_at_buf(dir.path().join("root"));
+        let image = image_with(dir.path(), "image", &[("var/tmp/.keep", "")]);
+        set_permissions(dir.path().join("image/var/tmp"), Permissions::from_mode(0o1777)).unwrap();
+        let mode = || metadata(dir.path().join("root/var/tmp")).unwrap().permissions().mode() & 0o7777;
+
+        let pkg = || ndbam.new_package_version("sys-apps/tmp", "1", "0").unwrap();
+        let err = pkg().merge_replacing(&image, &root, &[], CollisionPolicy::Yield, &ConfigProtect::default()).unwrap_err();
+        assert_eq!(err.to_string(), format!("Collision at {:?}: permissions differ (not owned by any package)", Path::new("/var/tmp")));
+        assert_eq!(mode(), 0o755);
+
+        let merged = pkg().merge_replacing(&image, &root, &[], CollisionPolicy::Clobber, &ConfigProtect::default()).unwrap();
+        assert_eq!(merged.collisions, vec![Collision { path: PathBuf::from("/var/tmp"), owners: vec![], resolution: Resolution::Clobbered }]);
+        assert_eq!(mode(), 0o1777);
+    }
+
+    #[test]
+    fn protected_upgrade() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("db");
+        let ndbam = NDBAM::create(&location, "exndbam-1").unwrap();
+        create_dir(dir.path().join("root")).unwrap();
+        let root = root_at_buf(dir.path().join("root"));
+        let protect = ConfigProtect { protect: vec![PathBuf::from("/etc")], mask: vec![PathBuf::from("/etc/env.d")] };
+        let files = |version| [("etc/kept.conf", version), ("etc/pristine.conf", version), ("etc/env.d/50foo", version), ("usr/bin/foo", version)];
+
+        let old = ndbam.new_package_version("app-misc/foo", "1", "0").unwrap();
+        old.merge(&image_with(dir.path(), "image1", &files("v1")), &root).unwrap();
+        for path in &["etc/kept.conf", "etc/env.d/50foo", "usr/bin/foo"] {
+            write(dir.path().join("root").join(path), "local").unwrap();
+        }
+
+        let new = ndbam.new_package_version("app-misc/foo", "2", "0").unwrap();
+        let image = image_with(dir.path(), "image2", &files("v2"));
+        let merged = new.merge_replacing(&image, &root, std::slice::from_ref(&old), CollisionPolicy::NoConflicts, &protect).unwrap();
+        let side = dir.path().join("root/etc/._cfg0000_kept.conf");
+        assert_eq!(merged.collisions, vec![Collision {
+            path: PathBuf::from("/etc/kept.conf"),
+            owners: vec![old.id().clone()],
+            resolution: Resolution::Yielded(side.clone()),
+        }]);
+        assert_eq!(read_to_string(dir.path().join("root/etc/kept.conf")).unwrap(), "local");
+        assert_eq!(read_to_string(side).unwrap(), "v2");
+        for path in &["etc/pristine.conf", "etc/env.d/50foo", "usr/bin/foo"] {
+            assert_eq!(read_to_string(dir.path().join("root").join(path)).unwrap(), "v2", "{}", path);
+        }
+        assert!(merged.removals.iter().any(|removal| match removal {
+            Removal::Keep(entry, reason) => entry.path() == Path::new("/etc/kept.conf") && reason.to_string().starts_with("modified"),
+            Removal::Remove(_) => false,
+        }));
+        assert_eq!(ndbam.pending_yields().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn merge_across_filesystems() {
+        // Image on tmpfs is the only way to get there without privileges
+        let other = match tempfile::tempdir_in("/dev/shm") {
+            Ok(other) => other,
+            Err(_) => return,
+        };
+        let dir = tempfile::tempdir().unwrap();
+        write(other.path().join("probe"), "").unwrap();
+        if hard_link(other.path().join("probe"), dir.path().join("probe")).map_err(|err| is_cross_device(&err)) != Err(true) {
+            return; // the same file-system after all
+        }
+        let location = dir.path().join("db");
+        let ndbam = NDBAM::create(&location, "exndbam-1").unwrap();
+        create_dir_all(dir.path().join("root/usr/bin")).unwrap();
+        let root = root_at_buf(dir.path().join("root"));
+
+        let old = ndbam.new_package_version("app-misc/foo", "1", "0").unwrap();
+        old.merge(&image_with(other.path(), "image1", &[("usr/bin/foo", "v1")]), &root).unwrap();
+        let image = image_with(other.path(), "image2", &[("usr/bin/foo", "v2"), ("usr/share/foo/data", "data")]);
+        std::os::unix::fs::symlink("foo", other.path().join("image2/usr/bin/bar")).unwrap();
+        hard_link(other.path().join("image2/usr/share/foo/data"), other.path().join("image2/usr/share/foo/link")).unwrap();
+        let new = ndbam.new_package_version("app-misc/foo", "2", "0").unwrap();
+        new.merge_replacing(&image, &root, &[old], CollisionPolicy::NoConflicts, &ConfigProtect::default()).unwrap();
+
+        assert_eq!(read_to_string(dir.path().join("root/usr/bin/foo")).unwrap(), "v2");
+        assert_eq!(read_link(dir.path().join("root/usr/bin/bar")).unwrap(), PathBuf::from("foo"));
+        assert_eq!(read_to_string(dir.path().join("root/usr/share/foo/data")).unwrap(), "data");
+        let data = metadata(dir.path().join("root/usr/share/foo/data")).unwrap();
+        assert_eq!(metadata(dir.path().join("root/usr/share/foo/link")).unwrap().ino(), data.ino());
+        assert_eq!(data.nlink(), 2);
+        assert!(!other.path().join("image2/usr/bin/foo").exists());
+        assert_eq!(read_dir(dir.path().join("root/usr/bin")).unwrap().count(), 2);
+        assert_eq!(ndbam.fsck().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn merge_hardlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("db");
+        let ndbam = NDBAM::create(&location, "exndbam-1").unwrap();
+        create_dir_all(dir.path().join("root/usr/bin")).unwrap();
+        let root = root_at_buf(dir.path().join("root"));
+        let image = image_with(dir.path(), "image", &[("usr/bin/git", "git"), ("usr/bin/other", "other")]);
+        create_dir_all(dir.path().join("image/usr/libexec/git-core")).unwrap();
+        hard_link(dir.path().join("image/usr/bin/git"), dir.path().join("image/usr/bin/git-upload-pack")).unwrap();
+        hard_link(dir.path().join("image/usr/bin/git"), dir.path().join("image/usr/libexec/git-core/git-add")).unwrap();
+
+        let pkg = ndbam.new_package_version("dev-vcs/git", "1", "0").unwrap();
+        pkg.merge(&image, &root).unwrap();
+
+        let inode = |path: &str| metadata(dir.path().join("root").join(path)).unwrap().ino();
+        assert_eq!(inode("usr/bin/git-upload-pack"), inode("usr/bin/git"));
+        assert_eq!(inode("usr/libexec/git-core/git-add"), inode("usr/bin/git"));
+        assert_ne!(inode("usr/bin/other"), inode("usr/bin/git"));
+        let mut groups = HashMap::new();
+        for entry in pkg.contents().unwrap() {
+            let entry = entry.unwrap();
+            if let Entry::File { path, md5, .. } = &entry {
+                groups.insert(path.clone(), (entry.hardlink_group().map(str::to_string), md5.clone()));
+            }
+        }
+        let (group, md5) = &groups[Path::new("/usr/bin/git")];
+        assert!(["/usr/bin/git", "/usr/bin/git-upload-pack", "/usr/libexec/git-core/git-add"].contains(&group.as_ref().unwrap().as_str()));
+        assert_eq!(groups[Path::new("/usr/bin/git-upload-pack")], (group.clone(), md5.clone()));
+        assert_eq!(groups[Path::new("/usr/libexec/git-core/git-add")], (group.clone(), md5.clone()));
+        assert_eq!(groups[Path::new("/usr/bin/other")].0, None);
+    }
+
+    #[test]
+    fn keep_file_capabilities() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("db");
+        let ndbam = NDBAM::create(&location, "exndbam-1").unwrap();
+        create_dir(dir.path().join("root")).unwrap();
+        let root = root_at_buf(dir.path().join("root"));
+        let image = image_with(dir.path(), "image", &[("bin/ping", "")]);
+        let ping = dir.path().join("image/bin/ping");
+        set_permissions(&ping, Permissions::from_mode(0o4711)).unwrap();
+        // cap_net_raw+p (vfs_cap_data revision 2)
+        let caps = b"\x02\x00\x00\x02\x00\x20\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+        let name = std::ffi::CString::new("security.capability").unwrap();
+        if set_xattr(&ping, &name, caps).is_err() {
+            return; // takes CAP_SETFCAP
+        }
+
+        let pkg = ndbam.new_package_version("net-misc/iputils", "1", "0").unwrap();
+        pkg.merge(&image, &root).unwrap();
+        let ping = dir.path().join("root/bin/ping");
+        assert_eq!(xattr_value(&ping, &name).unwrap(), caps.to_vec());
+        let entry = pkg.contents().unwrap().map(Result::unwrap).find(|entry| entry.path() == Path::new("/bin/ping")).unwrap();
+        match &entry {
+            Entry::File { extra, .. } => {
+                assert_eq!(extra.get("xattr:security.capability").map(String::as_str), Some("0200000200200000000000000000000000000000"));
+                assert_eq!(extra.get(MODE_KEY).map(String::as_str), Some("4711"));
+            }
+            other => panic!("unexpected entry {:?}", other),
+        }
+        assert_eq!(entry.changed_attributes(&ping).unwrap(), Vec::<String>::new());
+    }
+}
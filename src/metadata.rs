@@ -0,0 +1,180 @@
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fs, io};
+
+use super::PackageView;
+use crate::error::*;
+use crate::journal;
+use crate::utils::atomic_file::*;
+
+// Well-known keys of exndbam packages
+pub const SUMMARY: &str = "SUMMARY";
+pub const DESCRIPTION: &str = "DESCRIPTION";
+pub const HOMEPAGE: &str = "HOMEPAGE";
+pub const LICENCES: &str = "LICENCES";
+pub const REPOSITORY: &str = "REPOSITORY";
+pub const SLOT: &str = "SLOT";
+pub const EAPI: &str = "EAPI";
+pub const OPTIONS: &str = "OPTIONS";
+pub const DEPENDENCIES: &str = "DEPENDENCIES";
+pub const BUILD_DEPENDENCIES: &str = "BUILD_DEPENDENCIES";
+pub const RUN_DEPENDENCIES: &str = "RUN_DEPENDENCIES";
+pub const POST_DEPENDENCIES: &str = "POST_DEPENDENCIES";
+pub const INSTALLED_TIME: &str = "INSTALLED_TIME";
+
+/// Files in package entry that are not metadata keys.
+const RESERVED: &[&str] = &["contents"];
+
+impl PackageView {
+    /// Lists names of all metadata keys of package (sorted).
+    pub fn keys(&self) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for entry in self.location.read_dir()? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str() {
+                if is_key_name(name) {
+                    keys.push(name.to_string());
+                }
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Value of key or `None` if package have no such key.
+    pub fn key(&self, key: &str) -> Result<Option<String>> {
+        match fs::read_to_string(self.key_path(key)?) {
+            Ok(value) => Ok(Some(value)),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Atomically creates or replaces value of key.
+    pub fn write_key(&self, key: &str, value: &str) -> Result<()> {
+        journal::exclusive(&self.lock)?;
+        let mut f = AtomicFile::create(self.key_path(key)?)?;
+        f.write_all(value.as_bytes())?;
+        Ok(f.commit()?)
+    }
+
+    /// Removes key. Returns `false` if there were no such key.
+    pub fn remove_key(&self, key: &str) -> Result<bool> {
+        journal::exclusive(&self.lock)?;
+        match fs::remove_file(self.key_path(key)?) {
+            Ok(()) => Ok(true),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn summary(&self) -> Result<Option<String>> {
+        self.single_line_key(SUMMARY)
+    }
+
+    pub fn repository(&self) -> Result<Option<String>> {
+        self.single_line_key(REPOSITORY)
+    }
+
+    pub fn dependencies(&self) -> Result<Option<String>> {
+        self.single_line_key(DEPENDENCIES)
+    }
+
+    pub fn installed_time(&self) -> Result<Option<SystemTime>> {
+        match self.single_line_key(INSTALLED_TIME)? {
+            Some(value) => match value.parse::<u64>() {
+                Ok(secs) => Ok(Some(UNIX_EPOCH + Duration::from_secs(secs))),
+                Err(err) => Err(Error::Parse {
+                    path: self.location.join(INSTALLED_TIME),
+                    line: None,
+                    reason: err.to_string(),
+                }),
+            },
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_installed_time(&self, time: SystemTime) -> Result<()> {
+        let secs = time.duration_since(UNIX_EPOCH).map(|epoch| epoch.as_secs()).unwrap_or_default();
+        self.write_key(INSTALLED_TIME, &format!("{}\n", secs))
+    }
+
+    /// Value of key with trailing whitespaces stripped. Empty values treated as absent.
+    fn single_line_key(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.key(key)?
+            .map(|value| value.trim_end().to_string())
+            .filter(|value| !value.is_empty()))
+    }
+
+    fn key_path(&self, key: &str) -> Result<PathBuf> {
+        if is_key_name(key) {
+            Ok(self.location.join(key))
+        } else {
+            Err(Error::layout(self.location.join(key), "invalid key name"))
+        }
+    }
+}
+
+fn is_key_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && !name.contains('/') && !RESERVED.contains(&name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NDBAM;
+    use totems::*;
+
+    #[test]
+    fn write_read_remove() {
+        let dir = tempfile::tempdir().unwrap();
+        let ndbam = NDBAM::create(dir.path(), "exndbam-1").unwrap();
+        let pkg = ndbam.new_package_version("cat/pkg", "1.0", "0").unwrap();
+
+        assert_ok!(pkg.keys(), value == Vec::<String>::new());
+        assert_ok!(pkg.key(SUMMARY), value == None::<String>);
+        assert_ok!(pkg.summary(), value == None::<String>);
+
+        assert_ok!(pkg.write_key(SUMMARY, "Some package\n"));
+        assert_ok!(pkg.write_key("X_CUSTOM", "anything"));
+        assert_ok!(pkg.key(SUMMARY), value == Some("Some package\n".to_string()));
+        assert_ok!(pkg.summary(), value == Some("Some package".to_string()));
+        assert_ok!(pkg.keys(), value == vec![SUMMARY.to_string(), "X_CUSTOM".to_string()]);
+
+        assert!(pkg.remove_key(SUMMARY).unwrap());
+        assert!(!pkg.remove_key(SUMMARY).unwrap());
+        assert_ok!(pkg.keys(), value == vec!["X_CUSTOM".to_string()]);
+    }
+
+    #[test]
+    fn contents_is_not_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let ndbam = NDBAM::create(dir.path(), "exndbam-1").unwrap();
+        let pkg = ndbam.new_package_version("cat/pkg", "1.0", "0").unwrap();
+        pkg.content_writer().unwrap().commit().unwrap();
+
+        assert_ok!(pkg.keys(), value == Vec::<String>::new());
+        assert_err!(pkg.write_key("contents", ""));
+        assert_err!(pkg.write_key("../escape", ""));
+        assert_err!(pkg.remove_key(".hidden"));
+    }
+
+    #[test]
+    fn installed_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let ndbam = NDBAM::create(dir.path(), "exndbam-1").unwrap();
+        let pkg = ndbam.new_package_version("cat/pkg", "1.0", "0").unwrap();
+
+        let moment = UNIX_EPOCH + Duration::from_secs(1558050745);
+        assert_ok!(pkg.set_installed_time(moment));
+        assert_ok!(pkg.key(INSTALLED_TIME), value == Some("1558050745\n".to_string()));
+        assert_ok!(pkg.installed_time(), value == Some(moment));
+
+        assert_ok!(pkg.write_key(INSTALLED_TIME, "garbage"));
+        assert_err!(pkg.installed_time());
+    }
+}
@@ -0,0 +1,185 @@
+//! Conversion of installed packages between NDBAM and VDB layouts.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use super::NDBAM;
+use crate::contents::Entry;
+use crate::error::*;
+use crate::metadata;
+use crate::repository::*;
+use crate::vdb::{self, VDB};
+
+/// Something from source repository that could not be carried over to target one.
+#[derive(Debug, PartialEq)]
+pub struct Loss {
+    /// Package affected (if it could be identified at all).
+    pub package: Option<String>,
+    pub reason: String,
+}
+
+impl fmt::Display for Loss {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.package {
+            Some(ref package) => write!(f, "{}: {}", package, self.reason),
+            None => f.write_str(&self.reason),
+        }
+    }
+}
+
+/// Pairs of NDBAM and VDB keys with the same meaning.
+const KEYS: &[(&str, &str)] = &[
+    (metadata::SUMMARY, vdb::DESCRIPTION),
+    (metadata::REPOSITORY, vdb::REPOSITORY),
+    (metadata::LICENCES, vdb::LICENSE),
+    (metadata::OPTIONS, vdb::USE),
+    (metadata::BUILD_DEPENDENCIES, vdb::DEPEND),
+    (metadata::RUN_DEPENDENCIES, vdb::RDEPEND),
+    (metadata::POST_DEPENDENCIES, vdb::PDEPEND),
+    (metadata::INSTALLED_TIME, vdb::BUILD_TIME),
+];
+
+/// Copies every package from `source` into (normally freshly created) `target`.
+pub fn vdb_to_ndbam(source: &VDB, target: &NDBAM) -> Result<Vec<Loss>> {
+    let key_name = |key: &str| match KEYS.iter().find(|(_, vdb_key)| *vdb_key == key) {
+        Some((ndbam_key, _)) => ndbam_key.to_string(),
+        None => key.to_string(),
+    };
+    migrate(source, target, key_name, |_| Ok(None))
+}
+
+/// Copies every package from `source` into (normally empty) `target`.
+pub fn ndbam_to_vdb(source: &NDBAM, target: &VDB) -> Result<Vec<Loss>> {
+    let key_name = |key: &str| match KEYS.iter().find(|(ndbam_key, _)| *ndbam_key == key) {
+        Some((_, vdb_key)) => vdb_key.to_string(),
+        None => key.to_string(),
+    };
+    let check_entry = |entry: &Entry| {
+        entry.to_vdb()?;
+        Ok(match entry {
+            Entry::File { extra, .. } | Entry::Sym { extra, .. } if !extra.is_empty() => {
+                let mut keys: Vec<_> = extra.keys().map(String::as_str).collect();
+                keys.sort();
+                Some(format!("dropped {} of {:?}", keys.join(", "), entry.path()))
+            }
+            _ => None,
+        })
+    };
+    migrate(source, target, key_name, check_entry)
+}
+
+/// Copies packages between arbitrary repositories.
+///
+/// `check_entry` tells whether target can keep contents entry completely (`Ok(None)`), with
+/// some loss (`Ok(Some(..))`) or not at all (`Err(..)`).
+fn migrate<S, T, K, C>(source: &S, target: &T, key_name: K, check_entry: C) -> Result<Vec<Loss>>
+where
+    S: Repository,
+    T: Repository,
+    K: Fn(&str) -> String,
+    C: Fn(&Entry) -> std::result::Result<Option<String>, String>,
+{
+    target.lock_exclusive()?;
+    let mut losses = Vec::new();
+    for pkg in source.all_packages()? {
+        let pkg = match pkg {
+            Ok(pkg) => pkg,
+            Err(err) => {
+                losses.push(Loss { package: None, reason: err.to_string() });
+                continue;
+            }
+        };
+        let name = pkg.id().to_string();
+        let mut lost = |reason: String| losses.push(Loss { package: Some(name.clone()), reason });
+
+        let entries = match pkg.contents().and_then(|contents| contents.collect::<Result<Vec<_>>>()) {
+            Ok(entries) => entries,
+            Err(err) => {
+                lost(err.to_string());
+                continue;
+            }
+        };
+        let mut kept = Vec::with_capacity(entries.len());
+        for entry in entries {
+            match check_entry(&entry) {
+                Ok(None) => kept.push(entry),
+                Ok(Some(reason)) => {
+                    lost(reason);
+                    kept.push(entry);
+                }
+                Err(reason) => lost(reason),
+            }
+        }
+
+        let copy = match target.new_package_version(&pkg.name(), pkg.version(), pkg.slot().unwrap_or("0")) {
+            Ok(copy) => copy,
+            Err(Error::Layout { reason, .. }) => {
+                lost(reason);
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        copy.write_contents(&kept)?;
+
+        // Translated keys take precedence over ones that just happen to have the same name
+        let mut keys = pkg.keys()?;
+        keys.sort_by_key(|key| key_name(key) == *key);
+        let mut written = HashMap::new();
+        for key in keys {
+            let target_key = key_name(&key);
+            if let Some(other) = written.get(&target_key) {
+                lost(format!("key {} conflicts with {}", key, other));
+                continue;
+            }
+            written.insert(target_key.clone(), key.clone());
+            match pkg.key(&key) {
+                Ok(Some(value)) => copy.write_key(&target_key, &value)?,
+                Ok(None) => {}
+                Err(err) => lost(format!("key {}: {}", key, err)),
+            }
+        }
+    }
+    Ok(losses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let origin = dir.path().join("origin");
+        let ndbam = NDBAM::create(&origin, "exndbam-1").unwrap();
+        let pkg = ndbam.new_package_version("sys-libs/zlib", "1.2.11", "0").unwrap();
+        pkg.write_key(metadata::SUMMARY, "Compression library\n").unwrap();
+        pkg.write_key(metadata::DESCRIPTION, "Long story\n").unwrap();
+        let mut extra = HashMap::new();
+        extra.insert("part".to_string(), "libraries".to_string());
+        pkg.write_contents(&[
+            Entry::Dir { path: PathBuf::from("/usr") },
+            Entry::Dir { path: PathBuf::from("/multiple\nlines") },
+            Entry::File { path: PathBuf::from("/usr/lib/libz.so.1"), md5: "d692bb800".to_string(), mtime: UNIX_EPOCH, extra },
+        ]).unwrap();
+        ndbam.new_package_version("no-category", "1", "0").unwrap().write_contents(&[]).unwrap();
+
+        let vdb = VDB::create(&dir.path().join("vdb")).unwrap();
+        let mut reasons: Vec<_> = ndbam_to_vdb(&ndbam, &vdb).unwrap().iter().map(ToString::to_string).collect();
+        reasons.sort();
+        assert_eq!(reasons.len(), 4, "{:?}", reasons);
+        assert!(reasons[0].starts_with("no-category-1:0: "));
+        assert!(reasons[1].contains("VDB cannot represent newline"));
+        assert_eq!(reasons[2], "sys-libs/zlib-1.2.11:0: dropped part of \"/usr/lib/libz.so.1\"");
+        assert_eq!(reasons[3], "sys-libs/zlib-1.2.11:0: key DESCRIPTION conflicts with SUMMARY");
+
+        let copy = dir.path().join("copy");
+        let ndbam = NDBAM::create(&copy, "exndbam-1").unwrap();
+        assert_eq!(vdb_to_ndbam(&vdb, &ndbam).unwrap(), vec![]);
+        let pkg = ndbam.latest_of("sys-libs/zlib").unwrap().unwrap();
+        assert_eq!(pkg.slot(), Some("0"));
+        assert_eq!(pkg.summary().unwrap(), Some("Compression library".to_string()));
+        assert_eq!(pkg.contents().unwrap().count(), 2);
+    }
+}
@@ -0,0 +1,93 @@
+use std::fmt;
+
+use crate::dep_spec::split_name_version;
+use crate::version::Version;
+
+/// Identity of installed package as encoded in repository layout.
+///
+/// NDBAM keeps packages under `data/<category>---<name>/<version>:<slot>:<cookie>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackageId {
+    pub category: Option<String>,
+    pub name: String,
+    pub version: Version,
+    pub slot: Option<String>,
+    pub cookie: Option<String>,
+}
+
+impl PackageId {
+    /// # Examples
+    ///
+    /// ```
+    /// # use ndbam::package_id::PackageId;
+    /// let id = PackageId::from_entry("app-misc---ca-certificates", "20190110:0:C.1.2.3.C").unwrap();
+    /// assert_eq!(id.category.as_ref().unwrap(), "app-misc");
+    /// assert_eq!(id.name, "ca-certificates");
+    /// assert_eq!(id.version.as_str(), "20190110");
+    /// assert_eq!(id.slot.as_ref().unwrap(), "0");
+    /// assert_eq!(id.cookie.as_ref().unwrap(), "C.1.2.3.C");
+    /// assert_eq!(id.to_string(), "app-misc/ca-certificates-20190110:0");
+    ///
+    /// assert!(PackageId::from_entry("hello", "0").is_ok());
+    /// assert!(PackageId::from_entry("hello", "bad:0").is_err());
+    /// ```
+    pub fn from_entry(name_entry: &str, version_entry: &str) -> Result<PackageId, String> {
+        let (category, name) = split_qualified_name(&name_entry.replace("---", "/"))?;
+
+        let mut tokens = version_entry.split(':');
+        let version = Version::parse(tokens.next().unwrap_or_default())?;
+        let slot = tokens.next().map(String::from);
+        let cookie = tokens.next().map(String::from);
+        if tokens.next().is_some() {
+            return Err(format!("Unexpected trailing fields in {:?}", version_entry));
+        }
+
+        Ok(PackageId { category, name, version, slot, cookie })
+    }
+
+    /// Identity of package kept in Gentoo VDB under `<category>/<name>-<version>`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ndbam::package_id::PackageId;
+    /// let id = PackageId::from_vdb_entry("dev-libs", "foo-bar-1.0-r1", Some("0".to_string())).unwrap();
+    /// assert_eq!(id.to_string(), "dev-libs/foo-bar-1.0-r1:0");
+    /// assert!(PackageId::from_vdb_entry("dev-libs", "foo", None).is_err());
+    /// ```
+    pub fn from_vdb_entry(category: &str, entry: &str, slot: Option<String>) -> Result<PackageId, String> {
+        let (name, version) = split_name_version(entry)
+            .ok_or_else(|| format!("Missing version in {:?}", entry))?;
+        let (category, name) = split_qualified_name(&format!("{}/{}", category, name))?;
+        let version = Version::parse(version)?;
+        Ok(PackageId { category, name, version, slot, cookie: None })
+    }
+
+    /// Name with category (if any) as user would type it. E.g. `app-misc/ca-certificates`.
+    pub fn qualified_name(&self) -> String {
+        match self.category {
+            Some(ref category) => format!("{}/{}", category, self.name),
+            None => self.name.clone(),
+        }
+    }
+}
+
+impl fmt::Display for PackageId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}:{}", self.qualified_name(), self.version, self.slot.as_deref().unwrap_or("0"))
+    }
+}
+
+/// Splits `category/name` into its parts.
+pub fn split_qualified_name(qualified: &str) -> Result<(Option<String>, String), String> {
+    let mut parts = qualified.splitn(2, '/');
+    let first = parts.next().unwrap_or_default();
+    let (category, name) = match parts.next() {
+        Some(name) => (Some(first.to_string()), name.to_string()),
+        None => (None, first.to_string()),
+    };
+    if name.is_empty() || name.contains('/') || category.as_ref().is_some_and(String::is_empty) {
+        return Err(format!("Invalid package name {:?}", qualified));
+    }
+    Ok((category, name))
+}
@@ -0,0 +1,434 @@
+//! Persistent index of every path recorded in package `contents`.
+//!
+//! ```text
+//! index
+//!   +- stamps   (<entry> TAB <mtime> where entry is `.` for data/ itself, <name entry> or
+//!   |            <name entry>/<version entry> of authoritative install)
+//!   +- paths
+//!      +- 00    (<name entry>/<version entry> TAB <escaped path>)
+//!      ...
+//!      +- ff
+//! ```
+//!
+//! Paths are spread over buckets by the first byte of sha1 of canonical path. Only
+//! authoritative installs are indexed (see [`NDBAM::versions_of`](crate::NDBAM::versions_of)).
+//!
+//! Index is up to date while every directory has its recorded modification time. Adding or
+//! removing package changes its name directory and rewriting `contents` (or any other file of
+//! package) changes package directory, whoever does it. Refresh re-reads `contents` only of
+//! packages whose directories changed.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::OsStr;
+use std::io::prelude::*;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::UNIX_EPOCH;
+use std::{fs, io};
+
+use super::{sorted_versions, Installs, PackageView};
+use crate::contents::*;
+use crate::error::*;
+use crate::journal;
+use crate::lock::RepositoryLock;
+use crate::utils::atomic_file::*;
+use crate::utils::line_escape::*;
+
+/// Directory relative to `data/` mapped to its modification time.
+type Stamps = BTreeMap<String, String>;
+
+/// Bucket name mapped to (owner, path) pairs.
+type Buckets = HashMap<String, Vec<(String, PathBuf)>>;
+
+pub(crate) struct PathIndex<'l> {
+    lock: &'l Rc<RepositoryLock>,
+    location: PathBuf,
+}
+
+impl<'l> PathIndex<'l> {
+    pub fn new(lock: &'l Rc<RepositoryLock>) -> Self {
+        PathIndex { lock, location: lock.location().join("index") }
+    }
+
+    /// Owners of `path` or `None` if index is out of date.
+    pub fn owners(&self, path: &Path) -> Result<Option<Vec<PackageView>>> {
+        self.lock.shared()?;
+        if !self.up_to_date()? {
+            return Ok(None);
+        }
+        self.lookup(path).map(Some)
+    }
+
+    /// Owners of every one of `paths` (by canonical path) found by reading `contents` of every
+    /// package (e.g. while index is out of date and only shared lock is held).
+    pub fn scan<'p>(&self, paths: impl IntoIterator<Item=&'p Path>) -> Result<HashMap<PathBuf, Vec<PackageView>>> {
+        let mut owners: HashMap<PathBuf, Vec<PackageView>> = paths.into_iter()
+            .map(|path| (canonical(path), Vec::new()))
+            .collect();
+        for pkg in super::all_packages_at(self.lock)? {
+            let pkg = pkg?;
+            if !pkg.location.join("contents").exists() {
+                continue;
+            }
+            let mut owned = BTreeSet::new();
+            for entry in pkg.contents()? {
+                let path = canonical(entry?.path());
+                if owners.contains_key(&path) {
+                    owned.insert(path);
+                }
+            }
+            for path in owned {
+                owners.get_mut(&path).unwrap().push(pkg.clone());
+            }
+        }
+        Ok(owners)
+    }
+
+    /// Whether no directory changed since the last update.
+    pub fn up_to_date(&self) -> Result<bool> {
+        let recorded = self.recorded_stamps()?;
+        if recorded.is_empty() {
+            return Ok(false); // never built
+        }
+        let data = self.lock.location().join("data");
+        for (dir, stamp) in &recorded {
+            match stamp_of(&data.join(dir)) {
+                Ok(current) if current == *stamp => {}
+                Ok(_) => return Ok(false),
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Owners of `path` according to index without checking whether it is up to date.
+    ///
+    /// Only meaningful right after [`refresh`](#method.refresh) while exclusive lock is held.
+    pub fn lookup(&self, path: &Path) -> Result<Vec<PackageView>> {
+        let path = canonical(path);
+        let data = self.lock.location().join("data");
+        let mut owners = Vec::new();
+        for (owner, owned) in self.read_bucket(&bucket_of(&path))? {
+            if owned == path {
+                owners.push(PackageView::new(data.join(owner), self.lock.clone())?);
+            }
+        }
+        Ok(owners)
+    }
+
+    /// Brings index in sync with `data/` by re-reading only `contents` that changed since the
+    /// last update.
+    pub fn refresh(&self) -> Result<()> {
+        self.update(None)
+    }
+
+    /// Same as [`refresh`](#method.refresh) but limited to single package (e.g. the one just
+    /// merged) which is re-read regardless of its stamp.
+    pub fn refresh_package(&self, pkg: &PackageView) -> Result<()> {
+        let data = self.lock.location().join("data");
+        match pkg.location.strip_prefix(&data).ok().and_then(Path::to_str) {
+            Some(owner) => self.update(Some(owner)),
+            None => Err(Error::layout(&pkg.location, "package outside of repository")),
+        }
+    }
+
+    fn update(&self, only: Option<&str>) -> Result<()> {
+        journal::exclusive(self.lock)?;
+        let recorded = self.recorded_stamps()?;
+        let current = self.current_stamps()?;
+        let all_changed: BTreeSet<&String> = recorded.keys().chain(current.keys())
+            .filter(|owner| is_package(owner))
+            .filter(|owner| recorded.get(*owner) != current.get(*owner))
+            .collect();
+        let changed: BTreeSet<&String> = match only {
+            // Rewritten in the same tick it was created in still looks the same
+            Some(only) => current.keys().filter(|owner| owner.as_str() == only)
+                .chain(all_changed.iter().cloned().filter(|owner| owner.as_str() == only))
+                .collect(),
+            None => all_changed.clone(),
+        };
+
+        let data = self.lock.location().join("data");
+        let mut stamps = recorded.clone();
+        if changed.len() == all_changed.len() {
+            // Every package is in sync now (including additions and removals)
+            stamps.retain(|entry, _| is_package(entry));
+            let dirs = current.iter().filter(|(entry, _)| !is_package(entry));
+            stamps.extend(dirs.map(|(entry, stamp)| (entry.clone(), stamp.clone())));
+        }
+        let mut fresh: Buckets = HashMap::new();
+        for owner in &changed {
+            let stamp = match current.get(*owner) {
+                Some(stamp) => stamp,
+                None => {
+                    stamps.remove(*owner);
+                    continue;
+                }
+            };
+            stamps.insert(owner.to_string(), stamp.clone());
+            let pkg = PackageView::new(data.join(owner), self.lock.clone())?;
+            if !pkg.location.join("contents").exists() {
+                continue;
+            }
+            for entry in pkg.contents()? {
+                let path = canonical(entry?.path());
+                fresh.entry(bucket_of(&path)).or_default().push((owner.to_string(), path));
+            }
+        }
+
+        // Packages we knew about might have left entries in any bucket while brand new ones
+        // only in buckets of their paths (e.g. after interrupted update)
+        let buckets: BTreeSet<String> = if changed.iter().any(|owner| recorded.contains_key(*owner)) {
+            self.existing_buckets()?.into_iter().chain(fresh.keys().cloned()).collect()
+        } else {
+            fresh.keys().cloned().collect()
+        };
+
+        fs::create_dir_all(self.location.join("paths"))?;
+        for bucket in buckets {
+            let mut entries = self.read_bucket(&bucket)?;
+            entries.retain(|(owner, _)| !changed.contains(owner));
+            entries.extend(fresh.remove(&bucket).unwrap_or_default());
+            self.write_bucket(&bucket, &entries)?;
+        }
+        self.write_stamps(&stamps)
+    }
+
+    /// Drops index completely and builds it again.
+    pub fn rebuild(&self) -> Result<()> {
+        journal::exclusive(self.lock)?;
+        if self.location.exists() {
+            fs::remove_dir_all(&self.location)?;
+        }
+        self.refresh()
+    }
+
+    fn recorded_stamps(&self) -> Result<Stamps> {
+        let path = self.location.join("stamps");
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Stamps::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut stamps = Stamps::new();
+        for (n, line) in text.lines().enumerate() {
+            match line.find('\t') {
+                Some(sep) => stamps.insert(line[..sep].to_string(), line[sep + 1..].to_string()),
+                None => return Err(Error::Parse { path, line: Some(n + 1), reason: "missing stamp".to_string() }),
+            };
+        }
+        Ok(stamps)
+    }
+
+    /// Stamps of `data/`, package name directories and authoritative installs in them.
+    fn current_stamps(&self) -> Result<Stamps> {
+        let data = self.lock.location().join("data");
+        let mut stamps = Stamps::new();
+        stamps.insert(".".to_string(), stamp_of(&data).map_err(|err| Error::layout(&data, err))?);
+        for name in data.read_dir().map_err(|err| Error::layout(&data, err))? {
+            let name = name?;
+            let name_entry = match name.file_name().to_str() {
+                Some(name_entry) if name.file_type()?.is_dir() => name_entry.to_string(),
+                _ => continue,  // not a package we could ever report
+            };
+            stamps.insert(name_entry, stamp_of(&name.path())?);
+            for pkg in sorted_versions(name.path().read_dir()?, self.lock, Installs::Authoritative) {
+                let pkg = match pkg {
+                    Ok(pkg) => pkg,
+                    Err(Error::Layout { .. }) => continue,  // fsck reports it
+                    Err(err) => return Err(err),
+                };
+                if let Some(owner) = pkg.location.strip_prefix(&data).ok().and_then(Path::to_str) {
+                    stamps.insert(owner.to_string(), stamp_of(&pkg.location)?);
+                }
+            }
+        }
+        Ok(stamps)
+    }
+
+    fn write_stamps(&self, stamps: &Stamps) -> Result<()> {
+        let mut f = AtomicFile::create(self.location.join("stamps"))?;
+        for (owner, stamp) in stamps {
+            writeln!(f, "{}\t{}", owner, stamp)?;
+        }
+        Ok(f.commit()?)
+    }
+
+    fn existing_buckets(&self) -> Result<Vec<String>> {
+        let mut buckets = Vec::new();
+        match self.location.join("paths").read_dir() {
+            Ok(entries) => for entry in entries {
+                if let Some(name) = entry?.file_name().to_str() {
+                    if name.len() == 2 {
+                        buckets.push(name.to_string());
+                    }
+                }
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        Ok(buckets)
+    }
+
+    fn read_bucket(&self, bucket: &str) -> Result<Vec<(String, PathBuf)>> {
+        let path = self.location.join("paths").join(bucket);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut entries = Vec::new();
+        for (n, line) in data.split(|ch| *ch == b'\n').enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            let parsed = line.iter().position(|ch| *ch == b'\t').and_then(|sep| {
+                let owner = std::str::from_utf8(&line[..sep]).ok()?;
+                Some((owner.to_string(), PathBuf::from(OsStr::from_bytes(&unescape(&line[sep + 1..])?))))
+            });
+            match parsed {
+                Some(entry) => entries.push(entry),
+                None => return Err(Error::Parse { path, line: Some(n + 1), reason: "malformed index entry".to_string() }),
+            }
+        }
+        Ok(entries)
+    }
+
+    fn write_bucket(&self, bucket: &str, entries: &[(String, PathBuf)]) -> Result<()> {
+        let path = self.location.join("paths").join(bucket);
+        if entries.is_empty() {
+            return match fs::remove_file(&path) {
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+                res => Ok(res?),
+            };
+        }
+        let mut f = AtomicFile::create(path)?;
+        for (owner, path) in entries {
+            f.write_all(owner.as_bytes())?;
+            f.write_all(b"\t")?;
+            f.write_all(&escape(path.as_os_str().as_bytes()))?;
+            f.write_all(b"\n")?;
+        }
+        Ok(f.commit()?)
+    }
+}
+
+/// Whether stamp `entry` describes package rather than directory above it.
+fn is_package(entry: &str) -> bool {
+    entry.contains('/')
+}
+
+fn stamp_of(dir: &Path) -> io::Result<String> {
+    let mtime = dir.metadata()?.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+    Ok(format!("{}.{:09}", mtime.as_secs(), mtime.subsec_nanos()))
+}
+
+pub(crate) fn canonical(path: &Path) -> PathBuf {
+    path.components().collect()
+}
+
+fn bucket_of(path: &Path) -> String {
+    bytes_hash(Algorithm::SHA1, path.as_os_str().as_bytes())[..2].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NDBAM;
+    use std::time::{Duration, SystemTime};
+
+    fn install(ndbam: &NDBAM, name: &str, paths: &[&str]) -> PackageView {
+        let pkg = ndbam.new_package_version(name, "1", "0").unwrap();
+        write_contents(&pkg, paths);
+        pkg
+    }
+
+    fn write_contents(pkg: &PackageView, paths: &[&str]) {
+        let mut writer = pkg.content_writer().unwrap();
+        for path in paths {
+            writer.write_entry(&Entry::Dir { path: PathBuf::from(path) }).unwrap();
+        }
+        writer.commit().unwrap();
+    }
+
+    fn owner_names(index: &PathIndex, path: &str) -> Option<Vec<String>> {
+        index.owners(Path::new(path)).unwrap().map(|owners| {
+            let mut names: Vec<_> = owners.iter().map(PackageView::name).collect();
+            names.sort();
+            names
+        })
+    }
+
+    #[test]
+    fn incremental_updates() {
+        let dir = tempfile::tempdir().unwrap();
+        let ndbam = NDBAM::create(dir.path(), "exndbam-1").unwrap();
+        let index = PathIndex::new(&ndbam.lock);
+        let foo = install(&ndbam, "app-misc/foo", &["/usr/bin/foo", "/usr/share/doc"]);
+        install(&ndbam, "app-misc/bar", &["/usr/bin/bar", "/usr/share/doc"]);
+
+        assert_eq!(owner_names(&index, "/usr/bin/foo"), None);
+        index.refresh().unwrap();
+        assert_eq!(owner_names(&index, "/usr/bin/foo"), Some(vec!["app-misc/foo".to_string()]));
+        assert_eq!(owner_names(&index, "/usr/share/doc"),
+                   Some(vec!["app-misc/bar".to_string(), "app-misc/foo".to_string()]));
+        assert_eq!(owner_names(&index, "/usr/bin/baz"), Some(vec![]));
+
+        write_contents(&foo, &["/usr/bin/foo2"]);
+        index.refresh_package(&foo).unwrap();
+        assert_eq!(owner_names(&index, "/usr/bin/foo"), Some(vec![]));
+        assert_eq!(owner_names(&index, "/usr/bin/foo2"), Some(vec!["app-misc/foo".to_string()]));
+
+        // Rewritten behind our back (mtime should differ even on coarse filesystems)
+        write_contents(&foo, &["/usr/bin/foo3"]);
+        let location = fs::File::open(&foo.location).unwrap();
+        location.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        assert_eq!(owner_names(&index, "/usr/bin/foo3"), None);
+        let owners: Vec<_> = ndbam.owners_of(Path::new("/usr/bin/foo3")).unwrap().iter().map(PackageView::name).collect();
+        assert_eq!(owners, vec!["app-misc/foo"]);
+        assert!(ndbam.owners_of(Path::new("/usr/bin/foo2")).unwrap().is_empty());
+        index.refresh().unwrap();
+        assert_eq!(owner_names(&index, "/usr/bin/foo3"), Some(vec!["app-misc/foo".to_string()]));
+
+        fs::remove_dir_all(&foo.location).unwrap();
+        assert_eq!(owner_names(&index, "/usr/bin/foo3"), None);
+        index.refresh().unwrap();
+        assert_eq!(owner_names(&index, "/usr/bin/foo3"), Some(vec![]));
+        assert_eq!(owner_names(&index, "/usr/share/doc"), Some(vec!["app-misc/bar".to_string()]));
+    }
+
+    #[test]
+    fn shadowed_installs() {
+        let dir = tempfile::tempdir().unwrap();
+        let ndbam = NDBAM::create(dir.path(), "exndbam-1").unwrap();
+        let index = PathIndex::new(&ndbam.lock);
+        let old = install(&ndbam, "app-misc/foo", &["/usr/bin/foo-old", "/usr/bin/foo"]);
+        index.refresh().unwrap();
+        assert_eq!(owner_names(&index, "/usr/bin/foo-old"), Some(vec!["app-misc/foo".to_string()]));
+
+        // Newer install of the same slot takes over without old one being removed
+        let new = install(&ndbam, "app-misc/foo", &["/usr/bin/foo"]);
+        assert_eq!(owner_names(&index, "/usr/bin/foo"), None);
+        index.refresh().unwrap();
+        assert_eq!(owner_names(&index, "/usr/bin/foo-old"), Some(vec![]));
+        let owners = index.owners(Path::new("/usr/bin/foo")).unwrap().unwrap();
+        assert_eq!(owners.iter().map(|pkg| &pkg.location).collect::<Vec<_>>(), vec![&new.location]);
+        assert!(old.location.exists());
+    }
+
+    #[test]
+    fn rebuild() {
+        let dir = tempfile::tempdir().unwrap();
+        let ndbam = NDBAM::create(dir.path(), "exndbam-1").unwrap();
+        let index = PathIndex::new(&ndbam.lock);
+        install(&ndbam, "app-misc/foo", &["/usr/bin/foo"]);
+        index.refresh().unwrap();
+        fs::write(dir.path().join("index/paths/garbage"), "whatever").unwrap();
+
+        index.rebuild().unwrap();
+        assert!(!dir.path().join("index/paths/garbage").exists());
+        assert_eq!(owner_names(&index, "/usr/bin/foo"), Some(vec!["app-misc/foo".to_string()]));
+    }
+}
@@ -0,0 +1,306 @@
+//! Layout independent view of installed packages.
+//!
+//! Tools that should work with any kind of database (NDBAM, VDB, test fixtures, etc.) are
+//! expected to be written against [`Repository`] and [`Package`] rather than concrete types.
+
+use std::path::Path;
+use std::time::SystemTime;
+
+use super::{PackageView, NDBAM};
+use crate::config::ConfigProtect;
+use crate::contents::{AtomicSession, Entry};
+use crate::dep_spec::PackageDepSpec;
+use crate::error::*;
+use crate::merger::{CollisionPolicy, Merged};
+use crate::package_id::PackageId;
+use crate::path_index::PathIndex;
+use crate::unmerger::{Removal, UnmergePolicy};
+use crate::utils::virtual_root::RootPath;
+use crate::vdb::{VdbPackage, VDB};
+
+pub type Packages<'r, P> = Box<dyn Iterator<Item=Result<P>> + 'r>;
+
+/// Database of installed packages.
+pub trait Repository {
+    type Package: Package;
+
+    fn location(&self) -> &Path;
+
+    /// Lists every installed package.
+    fn all_packages(&self) -> Result<Packages<'_, Self::Package>>;
+
+    /// Lists every installed package that matches `spec`.
+    fn matching(&self, spec: &PackageDepSpec) -> Result<Packages<'_, Self::Package>> {
+        let spec = spec.clone();
+        Ok(Box::new(self.all_packages()?.filter(move |pkg| match pkg {
+            Ok(pkg) => spec.matches(pkg.id()),
+            Err(_) => true,  // let caller know about problems
+        })))
+    }
+
+    /// Ensure no one else accesses repository while we are alive.
+    fn lock_exclusive(&self) -> Result<()> {
+        Err(unsupported(self.location(), "modification"))
+    }
+
+    /// Allocates entry for package that is about to be installed.
+    fn new_package_version(&self, _name: &str, _version: &str, _slot: &str) -> Result<Self::Package> {
+        Err(unsupported(self.location(), "modification"))
+    }
+}
+
+/// Single installed package.
+pub trait Package {
+    fn location(&self) -> &Path;
+
+    fn id(&self) -> &PackageId;
+
+    fn name(&self) -> String {
+        self.id().qualified_name()
+    }
+
+    fn version(&self) -> &str {
+        self.id().version.as_str()
+    }
+
+    fn slot(&self) -> Option<&str> {
+        self.id().slot.as_deref()
+    }
+
+    fn full_name(&self) -> String {
+        format!("{}-{}", self.name(), self.version())
+    }
+
+    fn contents(&self) -> Result<Box<dyn Iterator<Item=Result<Entry>>>>;
+
+    /// Lists names of all metadata keys of package (sorted).
+    fn keys(&self) -> Result<Vec<String>>;
+
+    /// Value of key or `None` if package have no such key.
+    fn key(&self, key: &str) -> Result<Option<String>>;
+
+    fn summary(&self) -> Result<Option<String>>;
+
+    fn installed_time(&self) -> Result<Option<SystemTime>>;
+
+    /// Replaces whole contents of package.
+    fn write_contents(&self, _entries: &[Entry]) -> Result<()> {
+        Err(unsupported(self.location(), "writing contents"))
+    }
+
+    fn write_key(&self, _key: &str, _value: &str) -> Result<()> {
+        Err(unsupported(self.location(), "writing metadata"))
+    }
+
+    fn set_installed_time(&self, _time: SystemTime) -> Result<()> {
+        Err(unsupported(self.location(), "writing metadata"))
+    }
+
+    /// Moves everything from `image` into `root` recording it as contents of this package.
+    fn merge(&self, _image: &dyn RootPath, _root: &dyn RootPath) -> Result<()> {
+        Err(unsupported(self.location(), "merging"))
+    }
+
+    /// Same as [`merge`](#method.merge) but resolves collisions according to `policy` (except for
+    /// modified files under `protect`ed paths) and also retires `replaced` packages.
+    fn merge_replacing(&self, _image: &dyn RootPath, _root: &dyn RootPath, _replaced: &[Self], _policy: CollisionPolicy, _protect: &ConfigProtect) -> Result<Merged>
+    where
+        Self: Sized,
+    {
+        Err(unsupported(self.location(), "merging"))
+    }
+
+    /// Removes whatever package installed into `root` and then package itself.
+    fn unmerge(&self, _root: &dyn RootPath, _policy: UnmergePolicy) -> Result<Vec<Removal>> {
+        Err(unsupported(self.location(), "unmerging"))
+    }
+}
+
+fn unsupported(path: &Path, operation: &'static str) -> Error {
+    Error::Unsupported { path: path.to_owned(), operation }
+}
+
+impl<'p> Repository for NDBAM<'p> {
+    type Package = PackageView;
+
+    fn location(&self) -> &Path {
+        self.location
+    }
+
+    fn all_packages(&self) -> Result<Packages<'_, PackageView>> {
+        Ok(Box::new(NDBAM::all_packages(self)?))
+    }
+
+    fn matching(&self, spec: &PackageDepSpec) -> Result<Packages<'_, PackageView>> {
+        NDBAM::matching(self, spec)
+    }
+
+    fn lock_exclusive(&self) -> Result<()> {
+        NDBAM::lock_exclusive(self)
+    }
+
+    fn new_package_version(&self, name: &str, version: &str, slot: &str) -> Result<PackageView> {
+        NDBAM::new_package_version(self, name, version, slot)
+    }
+}
+
+impl Package for PackageView {
+    fn location(&self) -> &Path {
+        &self.location
+    }
+
+    fn id(&self) -> &PackageId {
+        PackageView::id(self)
+    }
+
+    fn contents(&self) -> Result<Box<dyn Iterator<Item=Result<Entry>>>> {
+        Ok(Box::new(PackageView::contents(self)?))
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        PackageView::keys(self)
+    }
+
+    fn key(&self, key: &str) -> Result<Option<String>> {
+        PackageView::key(self, key)
+    }
+
+    fn summary(&self) -> Result<Option<String>> {
+        PackageView::summary(self)
+    }
+
+    fn installed_time(&self) -> Result<Option<SystemTime>> {
+        PackageView::installed_time(self)
+    }
+
+    fn write_contents(&self, entries: &[Entry]) -> Result<()> {
+        let mut writer = self.content_writer()?;
+        for entry in entries {
+            writer.write_entry(entry)?;
+        }
+        writer.commit()?;
+        PathIndex::new(&self.lock).refresh_package(self)
+    }
+
+    fn write_key(&self, key: &str, value: &str) -> Result<()> {
+        PackageView::write_key(self, key, value)
+    }
+
+    fn set_installed_time(&self, time: SystemTime) -> Result<()> {
+        PackageView::set_installed_time(self, time)
+    }
+
+    fn merge(&self, image: &dyn RootPath, root: &dyn RootPath) -> Result<()> {
+        PackageView::merge(self, image, root)
+    }
+
+    fn merge_replacing(&self, image: &dyn RootPath, root: &dyn RootPath, replaced: &[PackageView], policy: CollisionPolicy, protect: &ConfigProtect) -> Result<Merged> {
+        PackageView::merge_replacing(self, image, root, replaced, policy, protect)
+    }
+
+    fn unmerge(&self, root: &dyn RootPath, policy: UnmergePolicy) -> Result<Vec<Removal>> {
+        PackageView::unmerge(self, root, policy)
+    }
+}
+
+impl Repository for VDB {
+    type Package = VdbPackage;
+
+    fn location(&self) -> &Path {
+        VDB::location(self)
+    }
+
+    fn all_packages(&self) -> Result<Packages<'_, VdbPackage>> {
+        Ok(Box::new(VDB::all_packages(self)?))
+    }
+
+    fn lock_exclusive(&self) -> Result<()> {
+        Ok(())  // there is no locking protocol for VDB
+    }
+
+    fn new_package_version(&self, name: &str, version: &str, slot: &str) -> Result<VdbPackage> {
+        VDB::new_package_version(self, name, version, slot)
+    }
+}
+
+impl Package for VdbPackage {
+    fn location(&self) -> &Path {
+        VdbPackage::location(self)
+    }
+
+    fn id(&self) -> &PackageId {
+        VdbPackage::id(self)
+    }
+
+    fn contents(&self) -> Result<Box<dyn Iterator<Item=Result<Entry>>>> {
+        Ok(Box::new(VdbPackage::contents(self)?))
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        VdbPackage::keys(self)
+    }
+
+    fn key(&self, key: &str) -> Result<Option<String>> {
+        VdbPackage::key(self, key)
+    }
+
+    fn summary(&self) -> Result<Option<String>> {
+        VdbPackage::summary(self)
+    }
+
+    fn installed_time(&self) -> Result<Option<SystemTime>> {
+        VdbPackage::installed_time(self)
+    }
+
+    fn write_contents(&self, entries: &[Entry]) -> Result<()> {
+        VdbPackage::write_contents(self, entries)
+    }
+
+    fn write_key(&self, key: &str, value: &str) -> Result<()> {
+        VdbPackage::write_key(self, key, value)
+    }
+
+    fn set_installed_time(&self, time: SystemTime) -> Result<()> {
+        VdbPackage::set_installed_time(self, time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn names<R: Repository>(repo: &R, spec: &str) -> Vec<String> {
+        let spec = PackageDepSpec::parse(spec).unwrap();
+        repo.matching(&spec).unwrap().map(|pkg| pkg.unwrap().full_name()).collect()
+    }
+
+    #[test]
+    fn same_queries_on_both_layouts() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("ndbam");
+        let ndbam = NDBAM::create(&location, "exndbam-1").unwrap();
+        Repository::new_package_version(&ndbam, "dev-libs/foo", "1.0", "0").unwrap();
+        Repository::new_package_version(&ndbam, "dev-libs/foo", "2.0", "0").unwrap();
+
+        fs::create_dir_all(dir.path().join("vdb/dev-libs/foo-1.0")).unwrap();
+        fs::create_dir_all(dir.path().join("vdb/dev-libs/foo-2.0")).unwrap();
+        let vdb = VDB::new(&dir.path().join("vdb")).unwrap();
+
+        assert_eq!(names(&ndbam, ">=dev-libs/foo-2"), vec!["dev-libs/foo-2.0"]);
+        assert_eq!(names(&vdb, ">=dev-libs/foo-2"), vec!["dev-libs/foo-2.0"]);
+    }
+
+    #[test]
+    fn vdb_cannot_merge() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("image")).unwrap();
+        let vdb = VDB::create(&dir.path().join("vdb")).unwrap();
+        let pkg = Repository::new_package_version(&vdb, "dev-libs/bar", "1", "0").unwrap();
+        let image = crate::root_at_buf(dir.path().join("image"));
+        match Package::merge(&pkg, &image, &crate::root_at_buf(dir.path().to_owned())) {
+            Err(Error::Unsupported { operation, .. }) => assert_eq!(operation, "merging"),
+            _ => panic!("VDB package should not be merged"),
+        }
+    }
+}
@@ -0,0 +1,292 @@
+//! Index of paths owned by multiple packages.
+//!
+//! Each shared path gets its own directory named after sha1 of the path with symlinks back to
+//! every owning package:
+//!
+//! ```text
+//! shared_paths
+//!   +- 8040f3f3... (/usr/share/info/dir)
+//!      +- .path
+//!      +- app-doc---foo:1.0:0:C.1.2.3.C -> ../../data/app-doc---foo/1.0:0:C.1.2.3.C
+//!      +- app-doc---bar:2.1:0:C.4.5.6.C -> ../../data/app-doc---bar/2.1:0:C.4.5.6.C
+//! ```
+//!
+//! Only files and symlinks are tracked since almost every directory is shared by several
+//! packages anyway.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::prelude::*;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::{fs, io};
+
+use super::{all_packages_at, PackageView, NDBAM};
+use crate::contents::*;
+use crate::error::*;
+use crate::journal;
+use crate::lock::RepositoryLock;
+use crate::path_index::*;
+#[cfg(test)]
+use crate::utils::virtual_root::*;
+use crate::utils::atomic_file::*;
+
+pub(crate) struct SharedPaths<'l> {
+    lock: &'l Rc<RepositoryLock>,
+    location: PathBuf,
+}
+
+impl<'l> SharedPaths<'l> {
+    pub fn new(lock: &'l Rc<RepositoryLock>) -> Self {
+        SharedPaths { lock, location: lock.location().join("shared_paths") }
+    }
+
+    /// Registers every package in `owners` as owner of `path`.
+    pub fn add(&self, path: &Path, owners: &[&PackageView]) -> Result<()> {
+        journal::exclusive(self.lock)?;
+        let entry = self.entry_dir(path);
+        fs::create_dir_all(&entry)?;
+        if !entry.join(".path").exists() {
+            let mut f = AtomicFile::create(entry.join(".path"))?;
+            f.write_all(canonical(path).as_os_str().as_bytes())?;
+            f.commit()?;
+        }
+        for owner in owners {
+            let (name, target) = owner_link(owner)?;
+            match symlink(&target, entry.join(name)) {
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+                res => res?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Withdraws `owner` from owners of `path` forgetting about path once it is not shared anymore.
+    pub fn remove(&self, path: &Path, owner: &PackageView) -> Result<()> {
+        journal::exclusive(self.lock)?;
+        let entry = self.entry_dir(path);
+        if !entry.is_dir() {
+            return Ok(());
+        }
+        let (name, _) = owner_link(owner)?;
+        match fs::remove_file(entry.join(name)) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            res => res?,
+        }
+        if self.links(&entry)?.len() < 2 {
+            fs::remove_dir_all(&entry)?;
+        }
+        Ok(())
+    }
+
+    /// Owners of `path` or `None` if path is not shared.
+    pub fn owners(&self, path: &Path) -> Result<Option<Vec<PackageView>>> {
+        self.lock.shared()?;
+        let entry = self.entry_dir(path);
+        if !entry.is_dir() {
+            return Ok(None);
+        }
+        let mut owners = Vec::new();
+        for link in self.links(&entry)? {
+            let location = entry.join(fs::read_link(&link)?);
+            if location.is_dir() {
+                owners.push(PackageView::new(location, self.lock.clone())?);
+            }
+        }
+        Ok(Some(owners))
+    }
+
+    /// Replaces whole index with one built from `contents` of every installed package.
+    pub fn rebuild(&self) -> Result<()> {
+        journal::exclusive(self.lock)?;
+        let mut owners: HashMap<PathBuf, Vec<PackageView>> = HashMap::new();
+        for pkg in all_packages_at(self.lock)? {
+            let pkg = pkg?;
+            for entry in pkg.contents()? {
+                match entry? {
+                    Entry::Dir { .. } => {}
+                    entry => owners.entry(canonical(entry.path())).or_default().push(pkg.clone()),
+                }
+            }
+        }
+
+        if self.location.exists() {
+            fs::remove_dir_all(&self.location)?;
+        }
+        fs::create_dir_all(&self.location)?;
+        for (path, owners) in owners {
+            if owners.len() > 1 {
+                self.add(&path, &owners.iter().collect::<Vec<_>>())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn links(&self, entry: &Path) -> Result<Vec<PathBuf>> {
+        let mut links = Vec::new();
+        for link in entry.read_dir()? {
+            let link = link?;
+            if link.file_type()?.is_symlink() {
+                links.push(link.path());
+            }
+        }
+        Ok(links)
+    }
+
+    fn entry_dir(&self, path: &Path) -> PathBuf {
+        let path = canonical(path);
+        self.location.join(bytes_hash(Algorithm::SHA1, path.as_os_str().as_bytes()))
+    }
+}
+
+/// Name of symlink and its target for package entry.
+fn owner_link(owner: &PackageView) -> Result<(PathBuf, PathBuf)> {
+    let version_entry = owner.location.file_name();
+    let name_entry = owner.location.parent().and_then(Path::file_name);
+    match (name_entry, version_entry) {
+        (Some(name_entry), Some(version_entry)) => {
+            let mut link = name_entry.to_os_string();
+            link.push(OsStr::new(":"));
+            link.push(version_entry);
+            let target = Path::new("../../data").join(name_entry).join(version_entry);
+            Ok((PathBuf::from(link), target))
+        }
+        _ => Err(Error::layout(&owner.location, "invalid package entry")),
+    }
+}
+
+impl PackageView {
+    /// Registers shared ownership for those of `paths` that are also owned by other packages.
+    pub(crate) fn share_paths(&self, paths: &[PathBuf]) -> Result<()> {
+        let index = PathIndex::new(&self.lock);
+        let shared = SharedPaths::new(&self.lock);
+        for path in paths {
+            let owners = match index.owners(path)? {
+                Some(owners) => owners,
+                None => {
+                    index.refresh()?;
+                    index.owners(path)?.unwrap_or_default()
+                }
+            };
+            if owners.len() > 1 {
+                shared.add(path, &owners.iter().collect::<Vec<_>>())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'p> NDBAM<'p> {
+    /// Every package that owns `path` according to its contents.
+    ///
+    /// Out of date path index is left for writers to refresh while every `contents` is read
+    /// instead.
+    pub fn owners_of(&self, path: &Path) -> Result<Vec<PackageView>> {
+        if let Some(owners) = SharedPaths::new(&self.lock).owners(path)? {
+            return Ok(owners);
+        }
+
+        let index = PathIndex::new(&self.lock);
+        match index.owners(path)? {
+            Some(owners) => Ok(owners),
+            None => Ok(index.scan(Some(path))?.remove(&canonical(path)).unwrap_or_default()),
+        }
+    }
+
+    /// Re-creates index of shared paths from scratch.
+    pub fn rebuild_shared_paths(&self) -> Result<()> {
+        SharedPaths::new(&self.lock).rebuild()
+    }
+
+    /// Brings index of all recorded paths up to date.
+    pub fn refresh_path_index(&self) -> Result<()> {
+        PathIndex::new(&self.lock).refresh()
+    }
+
+    /// Re-creates index of all recorded paths from scratch.
+    pub fn rebuild_path_index(&self) -> Result<()> {
+        PathIndex::new(&self.lock).rebuild()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn install(ndbam: &NDBAM, name: &str, paths: &[&str]) -> PackageView {
+        let pkg = ndbam.new_package_version(name, "1", "0").unwrap();
+        let mut writer = pkg.content_writer().unwrap();
+        for path in paths {
+            writer.write_entry(&Entry::File {
+                path: PathBuf::from(path),
+                md5: "d41d8cd98f00b204e9800998ecf8427e".to_string(),
+                mtime: UNIX_EPOCH,
+                extra: Default::default(),
+            }).unwrap();
+        }
+        writer.commit().unwrap();
+        pkg
+    }
+
+    fn owner_names(ndbam: &NDBAM, path: &str) -> Vec<String> {
+        let mut names: Vec<_> = ndbam.owners_of(Path::new(path)).unwrap().iter().map(PackageView::name).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn rebuild_and_query() {
+        let dir = tempfile::tempdir().unwrap();
+        let ndbam = NDBAM::create(dir.path(), "exndbam-1").unwrap();
+        install(&ndbam, "app-doc/foo", &["/usr/share/info/dir", "/usr/share/info/foo.info"]);
+        install(&ndbam, "app-doc/bar", &["/usr/share/info/dir", "/usr/share/info/bar.info"]);
+
+        ndbam.rebuild_shared_paths().unwrap();
+        assert_eq!(dir.path().join("shared_paths").read_dir().unwrap().count(), 1);
+
+        let shared = SharedPaths::new(&ndbam.lock);
+        assert_eq!(shared.owners(Path::new("/usr/share/info/dir")).unwrap().map(|owners| owners.len()), Some(2));
+        assert!(shared.owners(Path::new("/usr/share/info/foo.info")).unwrap().is_none());
+
+        assert_eq!(owner_names(&ndbam, "/usr/share/info/dir"), vec!["app-doc/bar", "app-doc/foo"]);
+        assert_eq!(owner_names(&ndbam, "/usr/share/info/foo.info"), vec!["app-doc/foo"]);
+        assert_eq!(owner_names(&ndbam, "/usr/share/info/absent"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn query_stale_index_under_shared_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let ndbam = NDBAM::create(dir.path(), "exndbam-1").unwrap();
+        install(&ndbam, "app-doc/foo", &["/usr/share/info/foo.info"]);
+        fs::remove_file(dir.path().join("index/stamps")).unwrap();
+        drop(ndbam);
+
+        let ndbam = NDBAM::new(dir.path()).unwrap();
+        assert_eq!(owner_names(&ndbam, "/usr/share/info/foo.info"), vec!["app-doc/foo"]);
+        assert!(!ndbam.lock.is_exclusive());
+        assert!(!dir.path().join("index/stamps").exists());
+    }
+
+    #[test]
+    fn register_on_merge() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("repo");
+        let ndbam = NDBAM::create(&location, "exndbam-1").unwrap();
+        // Owner of path that does not exist in root anymore
+        install(&ndbam, "app-doc/foo", &["/usr/share/info/dir"]);
+
+        let image = dir.path().join("image");
+        fs::create_dir_all(image.join("usr/share/info")).unwrap();
+        fs::write(image.join("usr/share/info/dir"), "").unwrap();
+        fs::create_dir(dir.path().join("root")).unwrap();
+
+        let pkg = ndbam.new_package_version("app-doc/bar", "1", "0").unwrap();
+        pkg.merge(&root_at_buf(image), &root_at_buf(dir.path().join("root"))).unwrap();
+
+        let shared = SharedPaths::new(&ndbam.lock);
+        assert_eq!(shared.owners(Path::new("/usr/share/info/dir")).unwrap().map(|owners| owners.len()), Some(2));
+    }
+}
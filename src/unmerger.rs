@@ -0,0 +1,365 @@
+//! Removal of installed package from managed file-system.
+//!
+//! Follows the same safety rules as Paludis: files and symlinks are removed only while they are
+//! exactly what package installed (same md5 or symlink target and modification time) and
+//! directories only once they are empty and not used by any other package.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::PackageView;
+use crate::contents::*;
+use crate::error::*;
+use crate::journal;
+use crate::package_id::PackageId;
+use crate::path_index::{canonical, PathIndex};
+use crate::shared_paths::SharedPaths;
+use crate::utils::virtual_root::*;
+
+/// How to treat files and symlinks that were changed since install.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnmergePolicy {
+    /// Keep anything that does not match contents.
+    Safe,
+    /// Remove modified files and symlinks as well (but never ones owned by other packages).
+    Force,
+}
+
+/// What unmerge does with single contents entry.
+#[derive(Debug, PartialEq)]
+pub enum Removal {
+    Remove(Entry),
+    Keep(Entry, KeepReason),
+}
+
+impl Removal {
+    pub fn entry(&self) -> &Entry {
+        match self {
+            Removal::Remove(entry) | Removal::Keep(entry, _) => entry,
+        }
+    }
+}
+
+/// Why path recorded in contents stays in file-system after unmerge.
+#[derive(Debug, PartialEq)]
+pub enum KeepReason {
+    /// Nothing to remove.
+    Missing,
+    /// Differs from what was recorded during install.
+    Modified(&'static str),
+    /// Other packages own the same path.
+    Owned(Vec<PackageId>),
+    /// Directory still contains something that is not removed.
+    NotEmpty,
+}
+
+impl fmt::Display for KeepReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeepReason::Missing => f.write_str("does not exist"),
+            KeepReason::Modified(how) => write!(f, "modified since install ({})", how),
+            KeepReason::Owned(owners) => {
+                let owners: Vec<_> = owners.iter().map(ToString::to_string).collect();
+                write!(f, "owned by another package ({})", owners.join(", "))
+            }
+            KeepReason::NotEmpty => f.write_str("not empty"),
+        }
+    }
+}
+
+impl PackageView {
+    /// Decides what [`unmerge`](#method.unmerge) would do with every contents entry without
+    /// changing anything.
+    ///
+    /// Entries are ordered the way they should be removed (i.e. children before parents).
+    ///
+    /// Only shared lock is taken. Out of date path index is left for writers to refresh while
+    /// every `contents` is read instead.
+    pub fn unmerge_plan(&self, root: &dyn RootPath, policy: UnmergePolicy) -> Result<Vec<Removal>> {
+        self.lock.shared()?;
+        let entries = self.removal_order()?;
+        let index = PathIndex::new(&self.lock);
+        if index.up_to_date()? {
+            return self.plan(entries, root, policy, |path| index.lookup(path));
+        }
+        let mut owners = index.scan(entries.iter().map(Entry::path))?;
+        self.plan(entries, root, policy, |path| Ok(owners.remove(&canonical(path)).unwrap_or_default()))
+    }
+
+    /// Contents entries ordered the way they should be removed.
+    fn removal_order(&self) -> Result<Vec<Entry>> {
+        let mut entries = self.contents()?.collect::<Result<Vec<_>>>()?;
+        entries.reverse();
+        // Contents written by merger lists parents first, but nothing guarantees that
+        entries.sort_by(|a, b| b.path().cmp(a.path()));
+        entries.dedup_by(|a, b| a.path() == b.path());
+        Ok(entries)
+    }
+
+    /// Decides what to do with every one of `entries` given `owners` of their paths.
+    fn plan(
+        &self,
+        entries: Vec<Entry>,
+        root: &dyn RootPath,
+        policy: UnmergePolicy,
+        mut owners: impl FnMut(&Path) -> Result<Vec<PackageView>>,
+    ) -> Result<Vec<Removal>> {
+        let mut plan = Vec::with_capacity(entries.len());
+        let mut removed = HashSet::new();
+        for entry in entries {
+            let real_path = root.real_path(entry.path())?;
+            let others: Vec<_> = owners(entry.path())?.into_iter()
+                .filter(|owner| owner.location != self.location)
+                .map(|owner| owner.id)
+                .collect();
+            let reason = match real_path.symlink_metadata() {
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => Some(KeepReason::Missing),
+                Err(err) => return Err(err.into()),
+                Ok(_) if !others.is_empty() => Some(KeepReason::Owned(others)),
+                Ok(metadata) => keep_reason(&entry, &real_path, &metadata, policy, &removed)?,
+            };
+            plan.push(match reason {
+                Some(reason) => Removal::Keep(entry, reason),
+                None => {
+                    removed.insert(canonical(&real_path));
+                    Removal::Remove(entry)
+                }
+            });
+        }
+        Ok(plan)
+    }
+
+    /// Removes everything package installed into `root` (as far as `policy` permits) and then
+    /// package itself.
+    ///
+    /// Package entry is retired by single rename so it either is still installed or gone
+    /// completely.
+    pub fn unmerge(&self, root: &dyn RootPath, policy: UnmergePolicy) -> Result<Vec<Removal>> {
+        journal::exclusive(&self.lock)?;
+        let index = PathIndex::new(&self.lock);
+        index.refresh()?;
+        let plan = self.plan(self.removal_order()?, root, policy, |path| index.lookup(path))?;
+        for removal in &plan {
+            if let Removal::Remove(entry) = removal {
+                let real_path = root.real_path(entry.path())?;
+                let removed = match entry {
+                    Entry::Dir { .. } => fs::remove_dir(&real_path),
+                    _ => fs::remove_file(&real_path),
+                };
+                match removed {
+                    Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+                    res => res?,
+                }
+            }
+        }
+
+        let grave = tempfile::Builder::new().prefix(".tmp").tempdir_in(self.lock.location())?;
+        fs::rename(&self.location, grave.path().join("package"))?;
+        if let Some(name) = self.location.parent() {
+            match fs::remove_dir(name) {
+                Err(ref err) if err.kind() == io::ErrorKind::DirectoryNotEmpty => {}
+                res => res?,
+            }
+        }
+        grave.close()?;
+
+        PathIndex::new(&self.lock).refresh()?;
+        let shared = SharedPaths::new(&self.lock);
+        for removal in &plan {
+            if let Entry::File { path, .. } | Entry::Sym { path, .. } = removal.entry() {
+                shared.remove(path, self)?;
+            }
+        }
+        Ok(plan)
+    }
+}
+
+/// Why existing path that belongs to nobody else should stay.
+fn keep_reason(
+    entry: &Entry,
+    real_path: &Path,
+    metadata: &Metadata,
+    policy: UnmergePolicy,
+    removed: &HashSet<PathBuf>,
+) -> Result<Option<KeepReason>> {
+    let (same_type, wrong_type) = match entry {
+        Entry::Dir { .. } => (metadata.is_dir(), "not a directory"),
+        Entry::File { .. } => (metadata.is_file(), "not a regular file"),
+        Entry::Sym { .. } => (metadata.file_type().is_symlink(), "not a symbolic link"),
+    };
+    if !same_type {
+        // Not forced since it is something else entirely (e.g. directory in place of file)
+        return Ok(Some(KeepReason::Modified(wrong_type)));
+    }
+    if policy == UnmergePolicy::Safe {
+        if let Some(mtime) = entry.mtime() {
+            if epoch_secs(mtime) != epoch_secs(&metadata.modified()?) {
+                return Ok(Some(KeepReason::Modified("modification time changed")));
+            }
+        }
+        match entry {
+            Entry::File { md5, .. } if file_hash(Algorithm::MD5, real_path)? != *md5 => {
+                return Ok(Some(KeepReason::Modified("content changed")));
+            }
+            Entry::Sym { target, .. } if real_path.read_link()? != *target => {
+                return Ok(Some(KeepReason::Modified("symlink changed")));
+            }
+            _ => {}
+        }
+    }
+    if metadata.is_dir() && !will_be_empty(real_path, removed)? {
+        return Ok(Some(KeepReason::NotEmpty));
+    }
+    Ok(None)
+}
+
+/// Whether directory has nothing except what is already planned for removal.
+fn will_be_empty(real_path: &Path, removed: &HashSet<PathBuf>) -> Result<bool> {
+    for child in real_path.read_dir()? {
+        if !removed.contains(&canonical(&child?.path())) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn epoch_secs(moment: &SystemTime) -> u64 {
+    moment.duration_since(UNIX_EPOCH).map(|epoch| epoch.as_secs()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NDBAM;
+    use std::os::unix::fs::symlink;
+
+    fn kept(plan: &[Removal]) -> Vec<(String, String)> {
+        plan.iter().filter_map(|removal| match removal {
+            Removal::Keep(entry, reason) => Some((entry.path().to_string_lossy().into_owned(), reason.to_string())),
+            Removal::Remove(_) => None,
+        }).collect()
+    }
+
+    fn removed(plan: &[Removal]) -> Vec<String> {
+        plan.iter().filter_map(|removal| match removal {
+            Removal::Remove(entry) => Some(entry.path().to_string_lossy().into_owned()),
+            Removal::Keep(..) => None,
+        }).collect()
+    }
+
+    /// Merges image with `/usr/bin/foo`, `/usr/bin/foo-link`, `/usr/share/foo/data` and
+    /// `/etc/foo.conf`.
+    fn merged(dir: &Path, ndbam: &NDBAM) -> PackageView {
+        let image = dir.join("image");
+        fs::create_dir_all(image.join("usr/bin")).unwrap();
+        fs::create_dir_all(image.join("usr/share/foo")).unwrap();
+        fs::create_dir_all(image.join("etc")).unwrap();
+        fs::write(image.join("usr/bin/foo"), "#!/bin/sh\n").unwrap();
+        symlink("foo", image.join("usr/bin/foo-link")).unwrap();
+        fs::write(image.join("usr/share/foo/data"), "data\n").unwrap();
+        fs::write(image.join("etc/foo.conf"), "answer=42\n").unwrap();
+        fs::create_dir_all(dir.join("root/usr")).unwrap();
+
+        let pkg = ndbam.new_package_version("app-misc/foo", "1", "0").unwrap();
+        pkg.merge(&root_at_buf(image), &root_at_buf(dir.join("root"))).unwrap();
+        pkg
+    }
+
+    #[test]
+    fn safety_rules() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("db");
+        let ndbam = NDBAM::create(&location, "exndbam-1").unwrap();
+        let foo = merged(dir.path(), &ndbam);
+        let root = root_at_buf(dir.path().join("root"));
+
+        // Another package claims one of files
+        let bar = ndbam.new_package_version("app-misc/bar", "1", "0").unwrap();
+        let mut content = bar.content_writer().unwrap();
+        content.write_entry(&Entry::from_path(&dir.path().join("root/usr/bin/foo-link"), &root).unwrap()).unwrap();
+        AtomicSession::commit(content).unwrap();
+        ndbam.rebuild_shared_paths().unwrap();
+        let conf = dir.path().join("root/etc/foo.conf");
+        let mtime = conf.metadata().unwrap().modified().unwrap();
+        fs::write(&conf, "answer=43\n").unwrap();
+        fs::File::options().write(true).open(&conf).unwrap().set_modified(mtime).unwrap();
+        fs::write(dir.path().join("root/usr/share/foo/local"), "").unwrap();
+
+        let plan = foo.unmerge(&root, UnmergePolicy::Safe).unwrap();
+        assert_eq!(removed(&plan), vec!["/usr/share/foo/data", "/usr/bin/foo"]);
+        assert_eq!(kept(&plan), vec![
+            ("/usr/share/foo".to_string(), "not empty".to_string()),
+            ("/usr/share".to_string(), "not empty".to_string()),
+            ("/usr/bin/foo-link".to_string(), "owned by another package (app-misc/bar-1:0)".to_string()),
+            ("/usr/bin".to_string(), "not empty".to_string()),
+            ("/usr".to_string(), "not empty".to_string()),
+            ("/etc/foo.conf".to_string(), "modified since install (content changed)".to_string()),
+            ("/etc".to_string(), "not empty".to_string()),
+        ]);
+        assert!(!dir.path().join("root/usr/bin/foo").exists());
+        assert!(dir.path().join("root/usr/bin/foo-link").symlink_metadata().is_ok());
+        assert!(!location.join("data/app-misc---foo").exists());
+        assert_eq!(ndbam.owners_of(Path::new("/usr/bin/foo")).unwrap().len(), 0);
+        assert_eq!(ndbam.owners_of(Path::new("/usr/bin/foo-link")).unwrap().len(), 1);
+        assert!(!location.join("shared_paths").read_dir().unwrap().any(|_| true));
+        assert_eq!(ndbam.fsck().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn plan_and_force() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("db");
+        let ndbam = NDBAM::create(&location, "exndbam-1").unwrap();
+        let foo = merged(dir.path(), &ndbam);
+        let root = root_at_buf(dir.path().join("root"));
+        fs::write(dir.path().join("root/etc/foo.conf"), "answer=43\n").unwrap();
+        fs::remove_file(dir.path().join("root/usr/bin/foo-link")).unwrap();
+        fs::remove_file(dir.path().join("root/usr/share/foo/data")).unwrap();
+        fs::create_dir(dir.path().join("root/usr/share/foo/data")).unwrap();
+
+        let plan = foo.unmerge_plan(&root, UnmergePolicy::Force).unwrap();
+        assert_eq!(removed(&plan), vec!["/usr/bin/foo", "/usr/bin", "/etc/foo.conf", "/etc"]);
+        assert_eq!(kept(&plan), vec![
+            ("/usr/share/foo/data".to_string(), "modified since install (not a regular file)".to_string()),
+            ("/usr/share/foo".to_string(), "not empty".to_string()),
+            ("/usr/share".to_string(), "not empty".to_string()),
+            ("/usr/bin/foo-link".to_string(), "does not exist".to_string()),
+            ("/usr".to_string(), "not empty".to_string()),
+        ]);
+        assert!(dir.path().join("root/etc/foo.conf").exists());
+        assert!(foo.location.exists());
+
+        foo.unmerge(&root, UnmergePolicy::Force).unwrap();
+        assert!(!dir.path().join("root/etc").exists());
+        assert!(dir.path().join("root/usr/share/foo/data").is_dir());
+    }
+
+    #[test]
+    fn plan_on_stale_index_under_shared_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("db");
+        let ndbam = NDBAM::create(&location, "exndbam-1").unwrap();
+        merged(dir.path(), &ndbam);
+        let root = root_at_buf(dir.path().join("root"));
+        // Another package claims one of files while nobody refreshes index
+        let bar = ndbam.new_package_version("app-misc/bar", "1", "0").unwrap();
+        let mut content = bar.content_writer().unwrap();
+        content.write_entry(&Entry::from_path(&dir.path().join("root/usr/bin/foo-link"), &root).unwrap()).unwrap();
+        AtomicSession::commit(content).unwrap();
+        fs::remove_file(location.join("index/stamps")).unwrap();
+        drop((bar, ndbam));
+
+        let ndbam = NDBAM::new(&location).unwrap();
+        let foo = ndbam.matching(&crate::dep_spec::PackageDepSpec::parse("app-misc/foo").unwrap()).unwrap().next().unwrap().unwrap();
+        let plan = foo.unmerge_plan(&root, UnmergePolicy::Safe).unwrap();
+        assert_eq!(removed(&plan), vec!["/usr/share/foo/data", "/usr/share/foo", "/usr/share", "/usr/bin/foo", "/etc/foo.conf", "/etc"]);
+        assert!(kept(&plan).contains(&("/usr/bin/foo-link".to_string(), "owned by another package (app-misc/bar-1:0)".to_string())));
+        assert!(!ndbam.lock.is_exclusive());
+        assert!(!location.join("index/stamps").exists());
+        assert!(dir.path().join("root/usr/bin/foo").exists());
+    }
+}
@@ -21,7 +21,10 @@ impl AtomicFile {
     /// Later call to 'commit' will either create file if it doesn't exist or replace its content.
     #[inline]
     pub fn create(path: PathBuf) -> io::Result<Self> {
-        let temp = NamedTempFile::new_in(path.parent().expect("invalid path to file"))?;
+        let dir = path.parent().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("invalid path to file {:?}", path))
+        })?;
+        let temp = NamedTempFile::new_in(dir)?;
         Ok(AtomicFile { path, temp })
     }
 
@@ -0,0 +1,305 @@
+//! Moving files and symlinks between file-systems preserving their attributes.
+
+use std::ffi::{CString, OsString};
+use std::fs::{self, File, FileTimes};
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Renames `from` into `to` falling back to [`copy_node`] and removal of `from` when they are on
+/// different file-systems.
+pub fn move_node(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(ref err) if is_cross_device(err) => {
+            copy_node(from, to)?;
+            fs::remove_file(from)
+        }
+        res => res,
+    }
+}
+
+/// Whether operation failed only because it cannot be done across file-systems (`EXDEV`).
+pub fn is_cross_device(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EXDEV)
+}
+
+/// Where [`copy_node`] prepares copy of `to` before atomically renaming it into place.
+pub fn copy_path(to: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(to.file_name().unwrap_or_default());
+    name.push(".ndbam-copy");
+    to.with_file_name(name)
+}
+
+/// Atomically replaces `to` with copy of file or symlink at `from`.
+///
+/// Mode, ownership, modification time and extended attributes (including file capabilities) are
+/// preserved as well as holes of sparse files. Attributes that `to` refuses (e.g. `trusted.*` for
+/// unprivileged user or none supported by its file-system) are skipped like cp(1) does.
+pub fn copy_node(from: &Path, to: &Path) -> io::Result<()> {
+    let metadata = from.symlink_metadata()?;
+    let temp = copy_path(to);
+    match fs::remove_file(&temp) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+        res => res?, // stale one
+    }
+
+    if metadata.file_type().is_symlink() {
+        symlink(fs::read_link(from)?, &temp)?;
+        chown(&temp, &metadata)?;
+        copy_xattrs(from, &temp)?;
+        set_symlink_times(&temp, &metadata)?;
+    } else if metadata.is_file() {
+        let mut source = File::open(from)?;
+        let mut copy = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&temp)?;
+        copy_sparse(&mut source, &mut copy, metadata.len())?;
+        // Changing owner drops setuid bits and capabilities so it goes first
+        chown(&temp, &metadata)?;
+        copy.set_permissions(metadata.permissions())?;
+        copy_xattrs(from, &temp)?;
+        copy.set_times(FileTimes::new().set_accessed(metadata.accessed()?).set_modified(metadata.modified()?))?;
+        copy.sync_all()?;
+    } else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("cannot copy {:?}", from)));
+    }
+
+    fs::rename(&temp, to).inspect_err(|_| {
+        let _ = fs::remove_file(&temp);
+    })
+}
+
+/// Atomically replaces `to` with hardlink to `existing` file.
+pub fn link_node(existing: &Path, to: &Path) -> io::Result<()> {
+    let temp = copy_path(to);
+    match fs::remove_file(&temp) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+        res => res?, // stale one
+    }
+    fs::hard_link(existing, &temp)?;
+    fs::rename(&temp, to).inspect_err(|_| {
+        let _ = fs::remove_file(&temp);
+    })
+}
+
+/// Gives directory `to` the same ownership, mode and extended attributes as `from` has.
+pub fn copy_dir_attributes(from: &Path, to: &Path) -> io::Result<()> {
+    let metadata = from.metadata()?;
+    chown(to, &metadata)?;
+    fs::set_permissions(to, metadata.permissions())?;
+    copy_xattrs(from, to)
+}
+
+/// Copies data regions of `source` leaving holes unallocated.
+fn copy_sparse(source: &mut File, copy: &mut File, len: u64) -> io::Result<()> {
+    let mut pos = 0;
+    while pos < len {
+        let data = match seek(source, pos, libc::SEEK_DATA) {
+            Ok(data) => data,
+            Err(ref err) if err.raw_os_error() == Some(libc::ENXIO) => break, // only hole left
+            Err(ref err) if err.raw_os_error() == Some(libc::EINVAL) => pos, // holes not supported
+            Err(err) => return Err(err),
+        };
+        let hole = seek(source, data, libc::SEEK_HOLE).unwrap_or(len);
+        source.seek(SeekFrom::Start(data))?;
+        copy.seek(SeekFrom::Start(data))?;
+        io::copy(&mut io::Read::take(&mut *source, hole - data), copy)?;
+        pos = hole;
+    }
+    copy.set_len(len)
+}
+
+fn seek(file: &File, offset: u64, whence: libc::c_int) -> io::Result<u64> {
+    let res = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res as u64)
+    }
+}
+
+fn chown(path: &Path, metadata: &fs::Metadata) -> io::Result<()> {
+    let path = c_path(path)?;
+    if unsafe { libc::lchown(path.as_ptr(), metadata.uid(), metadata.gid()) } == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    // Unprivileged user keeps whatever is possible like cp(1) does
+    if err.raw_os_error() == Some(libc::EPERM) && unsafe { libc::geteuid() } != 0 {
+        Ok(())
+    } else {
+        Err(err)
+    }
+}
+
+fn set_symlink_times(path: &Path, metadata: &fs::Metadata) -> io::Result<()> {
+    let timespec = |time: std::time::SystemTime| {
+        let since = time.duration_since(UNIX_EPOCH).unwrap_or_else(|_| Duration::from_secs(0));
+        libc::timespec { tv_sec: since.as_secs() as libc::time_t, tv_nsec: since.subsec_nanos() as _ }
+    };
+    let times = [timespec(metadata.accessed()?), timespec(metadata.modified()?)];
+    let path = c_path(path)?;
+    if unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Names of extended attributes of `path` (not following symlink).
+pub fn xattr_names(path: &Path) -> io::Result<Vec<CString>> {
+    let path = c_path(path)?;
+    let list = xattr_buffer(|buf, size| unsafe { libc::llistxattr(path.as_ptr(), buf, size) })?;
+    Ok(list.split(|ch| *ch == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| CString::new(name).expect("no NUL inside"))
+        .collect())
+}
+
+/// Value of extended attribute `name` of `path` (not following symlink).
+pub fn xattr_value(path: &Path, name: &CString) -> io::Result<Vec<u8>> {
+    let path = c_path(path)?;
+    xattr_buffer(|buf, size| unsafe {
+        libc::lgetxattr(path.as_ptr(), name.as_ptr(), buf as *mut libc::c_void, size)
+    })
+}
+
+/// Sets extended attribute `name` of `path` (not following symlink).
+pub fn set_xattr(path: &Path, name: &CString, value: &[u8]) -> io::Result<()> {
+    let path = c_path(path)?;
+    let res = unsafe {
+        libc::lsetxattr(path.as_ptr(), name.as_ptr(), value.as_ptr() as *const libc::c_void, value.len(), 0)
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn copy_xattrs(from: &Path, to: &Path) -> io::Result<()> {
+    let names = match xattr_names(from) {
+        Err(ref err) if err.raw_os_error() == Some(libc::ENOTSUP) => return Ok(()),
+        names => names?,
+    };
+    for name in names {
+        match set_xattr(to, &name, &xattr_value(from, &name)?) {
+            // Copy keeps whatever is possible like cp(1) does
+            Err(ref err) if matches!(err.raw_os_error(), Some(libc::EPERM) | Some(libc::ENOTSUP)) => {}
+            res => res?,
+        }
+    }
+    Ok(())
+}
+
+/// Calls `query` first to learn size of result and then to actually get it.
+fn xattr_buffer<F>(query: F) -> io::Result<Vec<u8>>
+where
+    F: Fn(*mut libc::c_char, libc::size_t) -> libc::ssize_t,
+{
+    loop {
+        let size = query(ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; size as usize];
+        let size = query(buf.as_mut_ptr() as *mut libc::c_char, buf.len());
+        if size >= 0 {
+            buf.truncate(size as usize);
+            return Ok(buf);
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+        // Grown in between, try again
+    }
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::SystemTime;
+
+    #[test]
+    fn copy_preserves_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("from");
+        let mut f = File::create(&from).unwrap();
+        f.write_all(b"head").unwrap();
+        f.seek(SeekFrom::Start(4 << 20)).unwrap();
+        f.write_all(b"tail").unwrap();
+        drop(f);
+        fs::set_permissions(&from, fs::Permissions::from_mode(0o750)).unwrap();
+        let mtime = UNIX_EPOCH + Duration::from_secs(1558050745);
+        File::open(&from).unwrap().set_modified(mtime).unwrap();
+        let xattr = CString::new("user.ndbam").unwrap();
+        let has_xattrs = set_xattr(&from, &xattr, b"value").is_ok();
+
+        let to = dir.path().join("to");
+        fs::write(&to, "old").unwrap();
+        copy_node(&from, &to).unwrap();
+        let metadata = to.metadata().unwrap();
+        assert_eq!(fs::read(&to).unwrap(), fs::read(&from).unwrap());
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o750);
+        assert_eq!(metadata.modified().unwrap(), mtime);
+        assert_eq!(metadata.uid(), from.metadata().unwrap().uid());
+        assert!(metadata.blocks() * 512 < metadata.len(), "holes are kept");
+        if has_xattrs {
+            assert_eq!(xattr_value(&to, &xattr).unwrap(), b"value");
+        }
+        assert!(!copy_path(&to).exists());
+
+        let link = dir.path().join("link");
+        symlink("from", &link).unwrap();
+        copy_node(&link, &dir.path().join("link-copy")).unwrap();
+        assert_eq!(fs::read_link(dir.path().join("link-copy")).unwrap(), PathBuf::from("from"));
+        assert!(copy_node(dir.path(), &dir.path().join("dir-copy")).is_err());
+    }
+
+    #[test]
+    fn skip_refused_xattrs() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("from");
+        fs::write(&from, "").unwrap();
+        if set_xattr(&from, &CString::new("user.ndbam").unwrap(), b"value").is_err() {
+            return; // no xattrs here
+        }
+        // Symlinks never take user.* attributes
+        let link = dir.path().join("link");
+        symlink("from", &link).unwrap();
+        copy_xattrs(&from, &link).unwrap();
+        assert!(xattr_names(&link).unwrap().is_empty());
+    }
+
+    #[test]
+    fn move_across_filesystems() {
+        let other = match tempfile::tempdir_in("/dev/shm") {
+            Ok(other) => other,
+            Err(_) => return, // no tmpfs around
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let from = other.path().join("file");
+        fs::write(&from, "data").unwrap();
+        if fs::hard_link(&from, dir.path().join("probe")).map_err(|err| is_cross_device(&err)) != Err(true) {
+            return; // the same file-system after all
+        }
+
+        let mtime = SystemTime::now() - Duration::from_secs(3600);
+        File::open(&from).unwrap().set_modified(mtime).unwrap();
+        move_node(&from, &dir.path().join("file")).unwrap();
+        assert!(!from.exists());
+        assert_eq!(fs::read_to_string(dir.path().join("file")).unwrap(), "data");
+        assert_eq!(dir.path().join("file").metadata().unwrap().modified().unwrap(), mtime);
+    }
+}
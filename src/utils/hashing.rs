@@ -18,3 +18,7 @@ pub fn file_hash<P: AsRef<Path>>(algorithm: Algorithm, path: P) -> io::Result<St
         reader.consume(n);
     }
 }
+
+pub fn bytes_hash(algorithm: Algorithm, bytes: &[u8]) -> String {
+    crypto_hash::hex_digest(algorithm, bytes)
+}
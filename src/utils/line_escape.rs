@@ -0,0 +1,50 @@
+//! Escaping of arbitrary bytes (e.g. paths) for line oriented files where fields are separated by
+//! TAB.
+
+/// Makes bytes safe to be stored as a single field of a line.
+pub fn escape(bytes: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(bytes.len());
+    for ch in bytes {
+        match ch {
+            b'\\' => escaped.extend_from_slice(b"\\\\"),
+            b'\n' => escaped.extend_from_slice(b"\\n"),
+            b'\t' => escaped.extend_from_slice(b"\\t"),
+            ch => escaped.push(*ch),
+        }
+    }
+    escaped
+}
+
+/// Reverts [`escape`] or returns `None` for malformed escape sequence.
+pub fn unescape(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut chars = bytes.iter();
+    while let Some(ch) = chars.next() {
+        match ch {
+            b'\\' => match chars.next()? {
+                b'\\' => unescaped.push(b'\\'),
+                b'n' => unescaped.push(b'\n'),
+                b't' => unescaped.push(b'\t'),
+                _ => return None,
+            },
+            ch => unescaped.push(*ch),
+        }
+    }
+    Some(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for path in &[&b"/plain"[..], b"/back\\slash", b"/multiple\nlines", b"/tab\there", b"/bad\x9cbyte"] {
+            assert_eq!(unescape(&escape(path)).as_deref(), Some(*path));
+            assert!(!escape(path).contains(&b'\n'));
+            assert!(!escape(path).contains(&b'\t'));
+        }
+        assert_eq!(unescape(b"/dangling\\"), None);
+        assert_eq!(unescape(b"/raw\ttab").as_deref(), Some(&b"/raw\ttab"[..]));
+    }
+}
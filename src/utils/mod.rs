@@ -1,5 +1,7 @@
 pub mod atomic_file;
+pub mod copy;
 pub mod hashing;
+pub mod line_escape;
 pub mod nom_extra;
 #[cfg(test)]
 pub mod pretty_bytes;
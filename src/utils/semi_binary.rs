@@ -1,5 +1,5 @@
 use std::str::*;
-use Utf8Chunk::*;
+use self::Utf8Chunk::*;
 
 #[derive(PartialEq, Debug)]
 pub enum Utf8Chunk<'s> {
@@ -0,0 +1,394 @@
+//! Access to Gentoo-style VDB (`/var/db/pkg`).
+//!
+//! ```text
+//! /var/db/pkg
+//!   +- dev-libs
+//!      +- openssl-1.1.1b
+//!         +- CONTENTS
+//!         +- SLOT
+//!         +- DESCRIPTION
+//!         ...
+//! ```
+
+use std::cmp::Ordering;
+use std::ffi::OsStr;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fs, io};
+
+use crate::contents::Entry;
+use crate::dep_spec::PackageDepSpec;
+use crate::error::*;
+use crate::package_id::{split_qualified_name, PackageId};
+use crate::utils::atomic_file::*;
+
+// Well-known keys of VDB packages
+pub const DESCRIPTION: &str = "DESCRIPTION";
+pub const HOMEPAGE: &str = "HOMEPAGE";
+pub const LICENSE: &str = "LICENSE";
+pub const REPOSITORY: &str = "repository";
+pub const SLOT: &str = "SLOT";
+pub const EAPI: &str = "EAPI";
+pub const USE: &str = "USE";
+pub const DEPEND: &str = "DEPEND";
+pub const RDEPEND: &str = "RDEPEND";
+pub const PDEPEND: &str = "PDEPEND";
+pub const BUILD_TIME: &str = "BUILD_TIME";
+
+/// Files in package entry that are not metadata keys.
+const RESERVED: &[&str] = &["CONTENTS"];
+
+pub struct VDB {
+    location: PathBuf,
+}
+
+impl VDB {
+    /// Opens existing VDB at `location` (e.g. `/var/db/pkg`).
+    pub fn new(location: &Path) -> Result<VDB> {
+        if !location.is_dir() {
+            return Err(Error::layout(location, "VDB should be a directory"));
+        }
+        Ok(VDB { location: location.to_owned() })
+    }
+
+    /// Initializes VDB at `location` that should not exist or be empty.
+    pub fn create(location: &Path) -> Result<VDB> {
+        if location.read_dir().map(|mut entries| entries.next().is_some()).unwrap_or(false) {
+            return Err(Error::layout(location, "directory is not empty"));
+        }
+        fs::create_dir_all(location)?;
+        Ok(VDB { location: location.to_owned() })
+    }
+
+    pub fn location(&self) -> &Path {
+        &self.location
+    }
+
+    /// Creates entry for package `name` (with category) unless there is one already.
+    pub fn new_package_version(&self, name: &str, version: &str, slot: &str) -> Result<VdbPackage> {
+        let (category, name) = match split_qualified_name(name) {
+            Ok((Some(category), name)) => (category, name),
+            Ok((None, _)) => return Err(Error::layout(&self.location, format!("{:?} has no category", name))),
+            Err(reason) => return Err(Error::layout(&self.location, reason)),
+        };
+        let category = self.location.join(category);
+        fs::create_dir_all(&category)?;
+        let location = category.join(format!("{}-{}", name, version));
+        fs::create_dir(&location).map_err(|err| match err.kind() {
+            io::ErrorKind::AlreadyExists => Error::layout(&location, "package already exists"),
+            _ => err.into(),
+        })?;
+        let mut f = AtomicFile::create(location.join(SLOT))?;
+        writeln!(f, "{}", slot)?;
+        f.commit()?;
+        VdbPackage::new(location)
+    }
+
+    /// Lists every installed package ordered by category, name and version.
+    pub fn all_packages(&self) -> Result<impl Iterator<Item=Result<VdbPackage>>> {
+        let mut categories = Vec::new();
+        for category in self.location.read_dir()? {
+            let category = category?;
+            let name = category.file_name();
+            if category.file_type()?.is_dir() && !name.to_string_lossy().starts_with('.') {
+                categories.push(category.path());
+            }
+        }
+        categories.sort();
+
+        let mut packages = Vec::new();
+        for category in categories {
+            let mut versions: Vec<_> = category.read_dir()?
+                .filter_map(|entry| match entry {
+                    // Portage keeps e.g. -MERGING-foo-1.0 while installing
+                    Ok(ref entry) if entry.file_name().to_string_lossy().starts_with('-') => None,
+                    Ok(entry) => Some(VdbPackage::new(entry.path())),
+                    Err(err) => Some(Err(err.into())),
+                })
+                .collect();
+            versions.sort_by(|a, b| match (a, b) {
+                (Ok(a), Ok(b)) => a.id.name.cmp(&b.id.name).then_with(|| a.id.version.cmp(&b.id.version)),
+                (Ok(_), Err(_)) => Ordering::Greater,
+                (Err(_), Ok(_)) => Ordering::Less,
+                (Err(_), Err(_)) => Ordering::Equal,
+            });
+            packages.extend(versions);
+        }
+        Ok(packages.into_iter())
+    }
+
+    /// Lists every installed package that matches `spec`.
+    pub fn matching(&self, spec: &PackageDepSpec) -> Result<impl Iterator<Item=Result<VdbPackage>>> {
+        let spec = spec.clone();
+        Ok(self.all_packages()?.filter(move |pkg| match pkg {
+            Ok(pkg) => spec.matches(pkg.id()),
+            Err(_) => true,  // let caller know about problems
+        }))
+    }
+}
+
+/// Package entry in VDB.
+#[derive(Clone)]
+pub struct VdbPackage {
+    location: PathBuf,
+    id: PackageId,
+}
+
+impl VdbPackage {
+    fn new(location: PathBuf) -> Result<VdbPackage> {
+        if !location.is_dir() {
+            return Err(Error::layout(location, "package entry should be a directory"));
+        }
+        let slot = match fs::read_to_string(location.join(SLOT)) {
+            Ok(slot) => Some(slot.trim_end().to_string()).filter(|slot| !slot.is_empty()),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+        let category = location.parent().and_then(Path::file_name).and_then(OsStr::to_str);
+        let entry = location.file_name().and_then(OsStr::to_str);
+        let id = match (category, entry) {
+            (Some(category), Some(entry)) => PackageId::from_vdb_entry(category, entry, slot),
+            _ => Err("non UTF-8 entry".to_string()),
+        };
+        match id {
+            Ok(id) => Ok(VdbPackage { location, id }),
+            Err(reason) => Err(Error::layout(location, reason)),
+        }
+    }
+
+    pub fn location(&self) -> &Path {
+        &self.location
+    }
+
+    pub fn id(&self) -> &PackageId {
+        &self.id
+    }
+
+    pub fn name(&self) -> String {
+        self.id.qualified_name()
+    }
+
+    pub fn version(&self) -> &str {
+        self.id.version.as_str()
+    }
+
+    pub fn slot(&self) -> Option<&str> {
+        self.id.slot.as_deref()
+    }
+
+    pub fn full_name(&self) -> String {
+        format!("{}-{}", self.name(), self.version())
+    }
+
+    /// Entries of `CONTENTS` without FIFOs and device nodes (see [`Entry::parse_vdb`]).
+    pub fn contents(&self) -> Result<impl Iterator<Item=Result<Entry>>> {
+        let path = self.location.join("CONTENTS");
+        let f = fs::File::open(&path).map_err(|err| Error::layout(&self.location, err))?;
+        Ok(io::BufReader::new(f)
+            .split(b'\n')
+            .enumerate()
+            .filter_map(move |(n, row)| {
+                let row = match row {
+                    Ok(row) => row,
+                    Err(err) => return Some(Err(err.into())),
+                };
+                Entry::parse_vdb(&row).map_err(|reason| Error::Parse {
+                    path: path.clone(),
+                    line: Some(n + 1),
+                    reason,
+                }).transpose()
+            }))
+    }
+
+    /// Lists names of all metadata keys of package (sorted).
+    pub fn keys(&self) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for entry in self.location.read_dir()? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str() {
+                if is_key_name(name) {
+                    keys.push(name.to_string());
+                }
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Value of key or `None` if package have no such key.
+    pub fn key(&self, key: &str) -> Result<Option<String>> {
+        if !is_key_name(key) {
+            return Err(Error::layout(self.location.join(key), "invalid key name"));
+        }
+        match fs::read_to_string(self.location.join(key)) {
+            Ok(value) => Ok(Some(value)),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Atomically creates or replaces value of key.
+    pub fn write_key(&self, key: &str, value: &str) -> Result<()> {
+        if !is_key_name(key) {
+            return Err(Error::layout(self.location.join(key), "invalid key name"));
+        }
+        let mut f = AtomicFile::create(self.location.join(key))?;
+        f.write_all(value.as_bytes())?;
+        Ok(f.commit()?)
+    }
+
+    /// Atomically replaces `CONTENTS` of package.
+    ///
+    /// Fails if any of entries cannot be represented in VDB.
+    pub fn write_contents<'e>(&self, entries: impl IntoIterator<Item=&'e Entry>) -> Result<()> {
+        let path = self.location.join("CONTENTS");
+        let mut f = AtomicFile::create(path.clone())?;
+        for entry in entries {
+            let line = entry.to_vdb().map_err(|reason| Error::layout(&path, reason))?;
+            f.write_all(&line)?;
+            f.write_all(b"\n")?;
+        }
+        Ok(f.commit()?)
+    }
+
+    pub fn set_installed_time(&self, time: SystemTime) -> Result<()> {
+        let secs = time.duration_since(UNIX_EPOCH).map(|epoch| epoch.as_secs()).unwrap_or_default();
+        self.write_key(BUILD_TIME, &format!("{}\n", secs))
+    }
+
+    pub fn summary(&self) -> Result<Option<String>> {
+        self.single_line_key(DESCRIPTION)
+    }
+
+    pub fn repository(&self) -> Result<Option<String>> {
+        self.single_line_key(REPOSITORY)
+    }
+
+    pub fn installed_time(&self) -> Result<Option<SystemTime>> {
+        match self.single_line_key(BUILD_TIME)? {
+            Some(value) => match value.parse::<u64>() {
+                Ok(secs) => Ok(Some(UNIX_EPOCH + Duration::from_secs(secs))),
+                Err(err) => Err(Error::Parse {
+                    path: self.location.join(BUILD_TIME),
+                    line: None,
+                    reason: err.to_string(),
+                }),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Value of key with trailing whitespaces stripped. Empty values treated as absent.
+    fn single_line_key(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.key(key)?
+            .map(|value| value.trim_end().to_string())
+            .filter(|value| !value.is_empty()))
+    }
+}
+
+fn is_key_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && !name.contains('/') && !RESERVED.contains(&name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+    use totems::*;
+
+    fn package(vdb: &Path, entry: &str, files: &[(&str, &str)]) {
+        let location = vdb.join(entry);
+        fs::create_dir_all(&location).unwrap();
+        for (name, value) in files {
+            fs::write(location.join(name), value).unwrap();
+        }
+    }
+
+    #[test]
+    fn packages_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        package(dir.path(), "dev-libs/foo-1.10", &[(SLOT, "0\n")]);
+        package(dir.path(), "dev-libs/foo-1.9", &[(SLOT, "0\n")]);
+        package(dir.path(), "app-misc/bar-2.0-r1", &[(SLOT, "2\n")]);
+        package(dir.path(), "app-misc/-MERGING-bar-2.0-r2", &[]);
+        fs::create_dir(dir.path().join(".cache")).unwrap();
+
+        let vdb = VDB::new(dir.path()).unwrap();
+        let ids: Vec<_> = vdb.all_packages().unwrap().map(|pkg| pkg.unwrap().id().to_string()).collect();
+        assert_eq!(ids, vec!["app-misc/bar-2.0-r1:2", "dev-libs/foo-1.9:0", "dev-libs/foo-1.10:0"]);
+
+        let spec = PackageDepSpec::parse(">=dev-libs/foo-1.10").unwrap();
+        let ids: Vec<_> = vdb.matching(&spec).unwrap().map(|pkg| pkg.unwrap().full_name()).collect();
+        assert_eq!(ids, vec!["dev-libs/foo-1.10"]);
+    }
+
+    #[test]
+    fn broken_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        package(dir.path(), "dev-libs/no-version", &[]);
+        package(dir.path(), "dev-libs/foo-1.0", &[]);
+
+        let vdb = VDB::new(dir.path()).unwrap();
+        let packages: Vec<_> = vdb.all_packages().unwrap().collect();
+        assert_eq!(packages.len(), 2);
+        assert!(packages[0].is_err());
+        assert_eq!(packages[1].as_ref().ok().and_then(VdbPackage::slot), None);
+    }
+
+    #[test]
+    fn write_package() {
+        let dir = tempfile::tempdir().unwrap();
+        let vdb = VDB::create(&dir.path().join("pkg")).unwrap();
+        let pkg = vdb.new_package_version("sys-libs/zlib", "1.2.11", "0/1").unwrap();
+        assert!(vdb.new_package_version("sys-libs/zlib", "1.2.11", "0").is_err());
+        assert!(vdb.new_package_version("zlib", "1.2.11", "0").is_err());
+        assert!(VDB::create(vdb.location()).is_err());
+
+        pkg.write_key(DESCRIPTION, "Compression library\n").unwrap();
+        pkg.write_contents(&[
+            Entry::Dir { path: PathBuf::from("/usr") },
+            Entry::File {
+                path: PathBuf::from("/usr/lib/libz.so.1"),
+                md5: "d692bb800".to_string(),
+                mtime: UNIX_EPOCH,
+                extra: Default::default(),
+            },
+        ]).unwrap();
+        assert!(pkg.write_contents(&[Entry::Dir { path: PathBuf::from("/multiple\nlines") }]).is_err());
+
+        let pkg = vdb.all_packages().unwrap().next().unwrap().unwrap();
+        assert_eq!(pkg.id().to_string(), "sys-libs/zlib-1.2.11:0/1");
+        assert_ok!(pkg.summary(), value == Some("Compression library".to_string()));
+        assert_eq!(fs::read_to_string(pkg.location().join("CONTENTS")).unwrap(),
+                   "dir /usr\nobj /usr/lib/libz.so.1 d692bb800 0\n");
+    }
+
+    #[test]
+    fn metadata_and_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        package(dir.path(), "sys-libs/zlib-1.2.11", &[
+            (SLOT, "0/1\n"),
+            (DESCRIPTION, "Standard (de)compression library\n"),
+            (REPOSITORY, "gentoo\n"),
+            (BUILD_TIME, "1549752022\n"),
+            ("CONTENTS", "dir /usr\nobj /usr/lib/libz.so.1 d692bb800 1549752022\nsym /usr/lib/libz.so -> libz.so.1 0\nfif /run/zlib\n"),
+        ]);
+
+        let vdb = VDB::new(dir.path()).unwrap();
+        let pkg = vdb.all_packages().unwrap().next().unwrap().unwrap();
+        assert_eq!(pkg.slot(), Some("0/1"));
+        assert_ok!(pkg.summary(), value == Some("Standard (de)compression library".to_string()));
+        assert_ok!(pkg.repository(), value == Some("gentoo".to_string()));
+        assert_ok!(pkg.installed_time(), value == Some(UNIX_EPOCH + Duration::from_secs(1549752022)));
+        assert_ok!(pkg.keys(), value == vec![BUILD_TIME.to_string(), DESCRIPTION.to_string(),
+                                             SLOT.to_string(), REPOSITORY.to_string()]);
+        assert_err!(pkg.key("CONTENTS"));
+
+        let paths: Vec<_> = pkg.contents().unwrap().map(|entry| entry.unwrap().path().to_owned()).collect();
+        assert_eq!(paths, vec![Path::new("/usr"), Path::new("/usr/lib/libz.so.1"), Path::new("/usr/lib/libz.so")]);
+    }
+}
@@ -0,0 +1,330 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+/// Version suffix in order of precedence (`_alpha` < `_beta` < ... < `_scm`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Suffix {
+    Alpha,
+    Beta,
+    Pre,
+    Rc,
+    P,
+    Scm,
+}
+
+impl Suffix {
+    fn parse(i: &str) -> Option<(Suffix, &str)> {
+        for (name, suffix) in &[
+            ("alpha", Suffix::Alpha),
+            ("beta", Suffix::Beta),
+            ("pre", Suffix::Pre),
+            ("rc", Suffix::Rc),
+            ("p", Suffix::P),
+            ("scm", Suffix::Scm),
+        ] {
+            if let Some(tail) = i.strip_prefix(name) {
+                return Some((*suffix, tail));
+            }
+        }
+        None
+    }
+
+    /// Whether version with such suffix is newer than the one without it.
+    fn is_upper(self) -> bool {
+        self == Suffix::P || self == Suffix::Scm
+    }
+}
+
+/// Version of package following Exherbo/Gentoo rules.
+///
+/// Note that equal versions might have different textual representation (e.g. `1.0` and
+/// `1.0-r0`).
+///
+/// # Examples
+///
+/// ```
+/// # use ndbam::version::Version;
+/// let v = |s| Version::parse(s).unwrap();
+/// assert!(v("1.0_rc1") < v("1.0"));
+/// assert!(v("1.0") < v("1.0-r1"));
+/// assert!(v("1.0_p1") < v("1.0a"));
+/// assert!(v("1.0-r1") < v("1.0-scm"));
+/// assert!(v("99999") < v("scm"));
+/// assert!(Version::parse("1.0-beta").is_err());
+/// ```
+#[derive(Debug, Clone)]
+pub struct Version {
+    text: String,
+    components: Vec<String>,
+    letter: Option<char>,
+    suffixes: Vec<(Suffix, String)>,
+    revision: String,
+}
+
+impl Version {
+    pub fn parse(text: &str) -> Result<Version, String> {
+        let invalid = || format!("Invalid version {:?}", text);
+
+        if text == "scm" {
+            return Ok(Version {
+                text: text.to_string(),
+                components: vec![],
+                letter: None,
+                suffixes: vec![(Suffix::Scm, String::new())],
+                revision: String::new(),
+            });
+        }
+
+        let mut components = Vec::new();
+        let mut rest = text;
+        loop {
+            let (number, tail) = split_digits(rest);
+            if number.is_empty() {
+                return Err(invalid());
+            }
+            components.push(number.to_string());
+            match tail.strip_prefix('.') {
+                Some(tail) => rest = tail,
+                None => {
+                    rest = tail;
+                    break;
+                }
+            }
+        }
+
+        let mut letter = None;
+        if let Some(ch) = rest.chars().next() {
+            if ch.is_ascii_lowercase() {
+                letter = Some(ch);
+                rest = &rest[1..];
+            }
+        }
+
+        let mut suffixes = Vec::new();
+        while let Some(tail) = rest.strip_prefix('_') {
+            let (suffix, tail) = Suffix::parse(tail).ok_or_else(invalid)?;
+            let (number, tail) = split_digits(tail);
+            suffixes.push((suffix, number.to_string()));
+            rest = tail;
+        }
+
+        if rest == "-scm" {
+            // Exherbo-style "1.2-scm"
+            suffixes.push((Suffix::Scm, String::new()));
+            rest = "";
+        }
+
+        let mut revision = String::new();
+        if let Some(tail) = rest.strip_prefix("-r") {
+            let (number, tail) = split_digits(tail);
+            if number.is_empty() {
+                return Err(invalid());
+            }
+            revision = number.to_string();
+            rest = tail;
+        }
+
+        if !rest.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(Version { text: text.to_string(), components, letter, suffixes, revision })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    /// Revision part (`-rN`) as text or empty string if none.
+    pub fn revision(&self) -> &str {
+        &self.revision
+    }
+
+    /// Whether version is built from version control (i.e. `scm`, `1.0-scm`, `1.0_scm`).
+    pub fn is_scm(&self) -> bool {
+        self.suffixes.iter().any(|(suffix, _)| *suffix == Suffix::Scm)
+    }
+
+    /// Compares ignoring revision.
+    pub fn cmp_without_revision(&self, other: &Version) -> Ordering {
+        self.cmp_components(other)
+            .then_with(|| self.letter.cmp(&other.letter))
+            .then_with(|| self.cmp_suffixes(other))
+    }
+
+    /// Whether `self` starts with components of `prefix` (i.e. `=foo-1*` semantic). Note that
+    /// `1*` covers `1.2` but not `10`.
+    pub fn starts_with(&self, prefix: &Version) -> bool {
+        match self.text.strip_prefix(&prefix.text) {
+            Some(tail) => !tail.starts_with(|ch: char| ch.is_ascii_digit()),
+            None => false,
+        }
+    }
+
+    fn cmp_components(&self, other: &Version) -> Ordering {
+        // "scm" alone is newer than anything else
+        match (self.components.is_empty(), other.components.is_empty()) {
+            (true, true) => return Ordering::Equal,
+            (true, false) => return Ordering::Greater,
+            (false, true) => return Ordering::Less,
+            (false, false) => {}
+        }
+
+        let mut ours = self.components.iter();
+        let mut theirs = other.components.iter();
+        let first = cmp_numbers(ours.next().unwrap(), theirs.next().unwrap());
+        if first != Ordering::Equal {
+            return first;
+        }
+        loop {
+            match (ours.next(), theirs.next()) {
+                (Some(a), Some(b)) => {
+                    let order = if a.starts_with('0') || b.starts_with('0') {
+                        a.trim_end_matches('0').cmp(b.trim_end_matches('0'))
+                    } else {
+                        cmp_numbers(a, b)
+                    };
+                    if order != Ordering::Equal {
+                        return order;
+                    }
+                }
+                (Some(_), None) => return Ordering::Greater,
+                (None, Some(_)) => return Ordering::Less,
+                (None, None) => return Ordering::Equal,
+            }
+        }
+    }
+
+    fn cmp_suffixes(&self, other: &Version) -> Ordering {
+        let mut ours = self.suffixes.iter();
+        let mut theirs = other.suffixes.iter();
+        loop {
+            match (ours.next(), theirs.next()) {
+                (Some((a, an)), Some((b, bn))) => {
+                    let order = a.cmp(b).then_with(|| cmp_numbers(an, bn));
+                    if order != Ordering::Equal {
+                        return order;
+                    }
+                }
+                (Some((a, _)), None) => {
+                    return if a.is_upper() { Ordering::Greater } else { Ordering::Less };
+                }
+                (None, Some((b, _))) => {
+                    return if b.is_upper() { Ordering::Less } else { Ordering::Greater };
+                }
+                (None, None) => return Ordering::Equal,
+            }
+        }
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Version) -> Ordering {
+        self.cmp_without_revision(other)
+            .then_with(|| cmp_numbers(&self.revision, &other.revision))
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Version) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Version) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+fn split_digits(i: &str) -> (&str, &str) {
+    let n = i.find(|ch: char| !ch.is_ascii_digit()).unwrap_or(i.len());
+    i.split_at(n)
+}
+
+/// Compares arbitrary long non-negative integers (empty string is zero).
+fn cmp_numbers(a: &str, b: &str) -> Ordering {
+    let a = a.trim_start_matches('0');
+    let b = b.trim_start_matches('0');
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use totems::*;
+
+    fn v(text: &str) -> Version {
+        Version::parse(text).unwrap()
+    }
+
+    fn assert_ascending(versions: &[&str]) {
+        for pair in versions.windows(2) {
+            assert!(v(pair[0]) < v(pair[1]), "{} < {}", pair[0], pair[1]);
+            assert!(v(pair[1]) > v(pair[0]), "{} > {}", pair[1], pair[0]);
+        }
+    }
+
+    #[test]
+    fn parse_good() {
+        for text in &["0", "1.0", "1.2.3a", "1_alpha", "1.0_beta2_p3", "20190110", "1.0-r1", "1.0_rc1-r2", "1-scm", "1_scm", "scm"] {
+            assert_ok!(Version::parse(text));
+        }
+    }
+
+    #[test]
+    fn parse_bad() {
+        for text in &["", "a", ".1", "1.", "1..2", "1.0ab", "1.0_gamma", "1.0-r", "1.0-beta", "1.0 ", "1-scm-r1x", "scm-r1"] {
+            assert_err!(Version::parse(text));
+        }
+    }
+
+    #[test]
+    fn numeric_components() {
+        assert_ascending(&["0", "1", "1.0", "1.0.0", "1.1", "1.2", "1.10", "2", "10"]);
+        assert_ascending(&["1.01", "1.1"]);
+        assert_ascending(&["1.001", "1.01", "1.1"]);
+        assert_ascending(&["18446744073709551615", "18446744073709551616"]);
+    }
+
+    #[test]
+    fn trailing_zeros() {
+        assert_eq!(v("1.010"), v("1.01"));
+        assert_ascending(&["1.01", "1.010.1"]);
+    }
+
+    #[test]
+    fn letters() {
+        assert_ascending(&["1.0", "1.0a", "1.0b", "1.0.1"]);
+    }
+
+    #[test]
+    fn suffixes() {
+        assert_ascending(&["1.0_alpha", "1.0_alpha1", "1.0_alpha2", "1.0_beta", "1.0_pre", "1.0_rc", "1.0_rc1", "1.0", "1.0_p", "1.0_p1", "1.0_scm"]);
+        assert_ascending(&["1.0_alpha_p1", "1.0_alpha1"]);
+        assert_ascending(&["1.0_rc1_alpha", "1.0_rc1", "1.0_rc1_p1"]);
+    }
+
+    #[test]
+    fn revisions() {
+        assert_ascending(&["1.0", "1.0-r1", "1.0-r2", "1.0-r10", "1.0a"]);
+        assert_eq!(v("1.0-r0"), v("1.0"));
+        assert_eq!(v("1.0").cmp_without_revision(&v("1.0-r3")), Ordering::Equal);
+    }
+
+    #[test]
+    fn scm() {
+        assert_ascending(&["1.0", "1.0-r1", "1.0-scm", "1.1", "9999", "scm"]);
+        assert_eq!(v("1.0-scm"), v("1.0_scm"));
+        assert!(v("scm").is_scm());
+        assert!(!v("1.0").is_scm());
+    }
+}
@@ -0,0 +1,212 @@
+//! Files merged under side name next to their intended path (see
+//! [`CollisionPolicy::Yield`](crate::merger::CollisionPolicy::Yield)).
+//!
+//! Side file (e.g. `/etc/._cfg0000_hosts`) is recorded in `contents` of its package as usual with
+//! additional `yields=/etc/hosts` token. It stays pending until it is either accepted (moved over
+//! intended path which then belongs to the package) or rejected (removed together with its
+//! entry). Accepting one of several files yielded to the same path rejects the rest.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::{fs, io};
+
+use super::{all_packages_at, PackageView, NDBAM};
+use crate::contents::*;
+use crate::error::*;
+use crate::journal;
+use crate::lock::RepositoryLock;
+use crate::path_index::{canonical, PathIndex};
+use crate::utils::virtual_root::*;
+
+/// Extra token of `contents` entry that holds intended path of side file.
+pub const YIELDS_KEY: &str = "yields";
+
+/// Side file waiting for decision.
+pub struct Yield {
+    pub package: PackageView,
+    /// Path of side file.
+    pub path: PathBuf,
+    /// Path side file was intended to be merged at.
+    pub target: PathBuf,
+}
+
+impl Yield {
+    /// Whether `path` refers either to side file or to its intended path.
+    pub fn matches(&self, path: &Path) -> bool {
+        let path = canonical(path);
+        path == canonical(&self.path) || path == canonical(&self.target)
+    }
+
+    /// Moves side file over intended path and records the latter as owned by package instead.
+    ///
+    /// Other files yielded to the same path are rejected since they were yielded to what is
+    /// replaced now.
+    pub fn accept(&self, root: &dyn RootPath) -> Result<()> {
+        journal::exclusive(&self.package.lock)?;
+        let side = root.real_path(&self.path)?;
+        let target = root.real_path(&self.target)?;
+        if side.symlink_metadata().is_err() {
+            return Err(Error::collision(&self.path, "yielded file does not exist"));
+        }
+        if target.symlink_metadata().map(|metadata| metadata.is_dir()).unwrap_or(false) {
+            return Err(Error::collision(&self.target, "already exists as directory"));
+        }
+        fs::rename(&side, &target)?;
+        let accepted = Entry::from_path(&target, root)?;
+        self.rewrite_contents(Some(accepted))?;
+        self.package.share_paths(std::slice::from_ref(&self.target))?;
+
+        let (side, target) = (canonical(&self.path), canonical(&self.target));
+        for other in pending_at(&self.package.lock)? {
+            if canonical(&other.target) == target && canonical(&other.path) != side {
+                other.reject(root)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes side file and forgets about it.
+    pub fn reject(&self, root: &dyn RootPath) -> Result<()> {
+        journal::exclusive(&self.package.lock)?;
+        match fs::remove_file(root.real_path(&self.path)?) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            res => res?,
+        }
+        self.rewrite_contents(None)
+    }
+
+    /// Puts `replacement` in place of side file entry (or just drops it).
+    fn rewrite_contents(&self, replacement: Option<Entry>) -> Result<()> {
+        let side = canonical(&self.path);
+        let mut entries = Vec::new();
+        for entry in self.package.contents()? {
+            let entry = entry?;
+            if canonical(entry.path()) != side {
+                entries.push(entry);
+            }
+        }
+        entries.extend(replacement);
+
+        let mut writer = self.package.content_writer()?;
+        for entry in &entries {
+            writer.write_entry(entry)?;
+        }
+        writer.commit()?;
+        PathIndex::new(&self.package.lock).refresh_package(&self.package)
+    }
+}
+
+/// Marks `entry` of side file as yielded to `target`.
+pub(crate) fn yielding(entry: Entry, target: &Path) -> Result<Entry> {
+    let target = match target.to_str() {
+        Some(target) => target.to_string(),
+        None => return Err(Error::collision(target, "cannot yield non UTF-8 path")),
+    };
+    let mark = |mut extra: HashMap<String, String>| {
+        extra.insert(YIELDS_KEY.to_string(), target.clone());
+        extra
+    };
+    Ok(match entry {
+        Entry::File { path, md5, mtime, extra } => Entry::File { path, md5, mtime, extra: mark(extra) },
+        Entry::Sym { path, target, mtime, extra } => Entry::Sym { path, target, mtime, extra: mark(extra) },
+        Entry::Dir { path } => Entry::Dir { path },
+    })
+}
+
+impl<'p> NDBAM<'p> {
+    /// Every side file across repository that is waiting to be either accepted or rejected.
+    pub fn pending_yields(&self) -> Result<Vec<Yield>> {
+        pending_at(&self.lock)
+    }
+}
+
+fn pending_at(lock: &Rc<RepositoryLock>) -> Result<Vec<Yield>> {
+    let mut yields = Vec::new();
+    for pkg in all_packages_at(lock)? {
+        let pkg = pkg?;
+        for entry in pkg.contents()? {
+            if let Entry::File { path, extra, .. } | Entry::Sym { path, extra, .. } = entry? {
+                if let Some(target) = extra.get(YIELDS_KEY) {
+                    yields.push(Yield { package: pkg.clone(), path, target: PathBuf::from(target) });
+                }
+            }
+        }
+    }
+    Ok(yields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merger::CollisionPolicy;
+
+    fn merge(ndbam: &NDBAM, name: &str, image: &Path, root: &dyn RootPath, data: &str) -> PackageView {
+        fs::create_dir_all(image.join("etc")).unwrap();
+        fs::write(image.join("etc/hosts"), data).unwrap();
+        let pkg = ndbam.new_package_version(name, "1", "0").unwrap();
+        pkg.merge_replacing(&root_at_buf(image.to_owned()), root, &[], CollisionPolicy::Yield, &Default::default()).unwrap();
+        pkg
+    }
+
+    fn owners(ndbam: &NDBAM, path: &str) -> Vec<String> {
+        let mut names: Vec<_> = ndbam.owners_of(Path::new(path)).unwrap().iter().map(PackageView::name).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn accept_and_reject() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("db");
+        let ndbam = NDBAM::create(&location, "exndbam-1").unwrap();
+        fs::create_dir(dir.path().join("root")).unwrap();
+        let root = root_at_buf(dir.path().join("root"));
+        merge(&ndbam, "sys-apps/baselayout", &dir.path().join("image1"), &root, "old");
+        merge(&ndbam, "net-misc/a", &dir.path().join("image2"), &root, "a");
+        merge(&ndbam, "net-misc/b", &dir.path().join("image3"), &root, "b");
+
+        let mut yields = ndbam.pending_yields().unwrap();
+        yields.sort_by(|a, b| a.path.cmp(&b.path));
+        let found: Vec<_> = yields.iter().map(|y| (y.package.name(), y.path.clone(), y.target.clone())).collect();
+        assert_eq!(found, vec![
+            ("net-misc/a".to_string(), PathBuf::from("/etc/._cfg0000_hosts"), PathBuf::from("/etc/hosts")),
+            ("net-misc/b".to_string(), PathBuf::from("/etc/._cfg0001_hosts"), PathBuf::from("/etc/hosts")),
+        ]);
+        assert!(yields[0].matches(Path::new("/etc/hosts")) && yields[0].matches(Path::new("/etc//._cfg0000_hosts")));
+        assert!(!yields[0].matches(Path::new("/etc/._cfg0001_hosts")));
+
+        yields[1].reject(&root).unwrap();
+        assert!(!dir.path().join("root/etc/._cfg0001_hosts").exists());
+        assert_eq!(owners(&ndbam, "/etc/._cfg0001_hosts"), Vec::<String>::new());
+
+        yields[0].accept(&root).unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join("root/etc/hosts")).unwrap(), "a");
+        assert!(!dir.path().join("root/etc/._cfg0000_hosts").exists());
+        assert_eq!(owners(&ndbam, "/etc/hosts"), vec!["net-misc/a", "sys-apps/baselayout"]);
+        assert_eq!(owners(&ndbam, "/etc/._cfg0000_hosts"), Vec::<String>::new());
+        assert!(ndbam.pending_yields().unwrap().is_empty());
+    }
+
+    #[test]
+    fn accept_one_of_many() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("db");
+        let ndbam = NDBAM::create(&location, "exndbam-1").unwrap();
+        fs::create_dir(dir.path().join("root")).unwrap();
+        let root = root_at_buf(dir.path().join("root"));
+        merge(&ndbam, "sys-apps/baselayout", &dir.path().join("image1"), &root, "old");
+        merge(&ndbam, "net-misc/a", &dir.path().join("image2"), &root, "a");
+        merge(&ndbam, "net-misc/b", &dir.path().join("image3"), &root, "b");
+
+        let yields = ndbam.pending_yields().unwrap();
+        let b = yields.iter().find(|y| y.package.name() == "net-misc/b").unwrap();
+        b.accept(&root).unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join("root/etc/hosts")).unwrap(), "b");
+        assert!(!dir.path().join("root/etc/._cfg0000_hosts").exists());
+        assert!(!dir.path().join("root/etc/._cfg0001_hosts").exists());
+        assert_eq!(owners(&ndbam, "/etc/hosts"), vec!["net-misc/b", "sys-apps/baselayout"]);
+        assert_eq!(owners(&ndbam, "/etc/._cfg0000_hosts"), Vec::<String>::new());
+        assert!(ndbam.pending_yields().unwrap().is_empty());
+    }
+}
@@ -11,6 +11,7 @@ use predicates::prelude::*;
 pub struct Env {
     root: TempDir,
     cmd_output: Option<std::process::Output>,
+    lock_holder: Option<std::process::Child>,
 }
 
 impl Env {
@@ -26,6 +27,19 @@ impl Env {
         components.next();
         self.root.child(components.as_path())
     }
+
+    fn location(&self) -> PathBuf {
+        self.root.path().join("var").join("db").join("ndbam")
+    }
+}
+
+impl Drop for Env {
+    fn drop(&mut self) {
+        if let Some(ref mut holder) = self.lock_holder {
+            let _ = holder.kill();
+            let _ = holder.wait();
+        }
+    }
 }
 
 impl cucumber_rust::World for Env {}
@@ -35,6 +49,7 @@ impl Default for Env {
         Env {
             root: assert_fs::TempDir::new().unwrap(),
             cmd_output: None,
+            lock_holder: None,
         }
     }
 }
@@ -49,8 +64,21 @@ mod basic_steps {
             world.root.copy_from(&source, &["*"]).expect(&format!("Fail to copy from {:?}", &source));
         };
 
+        given regex r"^repository (shared|exclusive) locked by another process$" (String) |world, mode, _step| {
+            let acquired = world.root.path().join(".locked");
+            world.lock_holder = Some(Command::new("flock")
+                .arg(if mode == "shared" { "--shared" } else { "--exclusive" })
+                .arg(world.location())
+                .arg("-c").arg(format!("touch {:?} && sleep 60", acquired))
+                .spawn()
+                .expect("flock utility"));
+            while !acquired.exists() {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+        };
+
         when regex r"^run (\S+)(.*)$" (String, String) |world, program, trail, _step| {
-            let location = world.root.path().join("var").join("db").join("ndbam");
+            let location = world.location();
             let args: Vec<String> = {
                 shellwords::split(&trail).unwrap().iter()
                     .map(|arg| {
@@ -93,6 +121,10 @@ mod basic_steps {
             world.cmd_assert().stdout(predicate::str::contains(needle));
         };
 
+        then regex r"errors contains?:\s*(.*)" (String) |world, needle, _step| {
+            world.cmd_assert().stderr(predicate::str::contains(needle));
+        };
+
         then regex r"errors do(?:es)? not contains?:\s*(.*)" (String) |world, needle, _step| {
             world.cmd_assert().stderr(predicate::str::contains(needle).not());
         };